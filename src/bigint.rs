@@ -0,0 +1,574 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::{Add, Mul, Neg, Sub};
+
+/// An arbitrary-precision signed integer, stored as sign and magnitude with
+/// little-endian base 2^32 digits. The magnitude never has trailing zero
+/// digits and zero is never negative, so derived equality is structural.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    digits: Vec<u32>,
+}
+
+const DECIMAL_CHUNK: u32 = 1_000_000_000;
+const DECIMAL_CHUNK_WIDTH: usize = 9;
+
+impl BigInt {
+    fn new(negative: bool, mut digits: Vec<u32>) -> Self {
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        BigInt {
+            negative: negative && !digits.is_empty(),
+            digits,
+        }
+    }
+
+    pub fn zero() -> Self {
+        BigInt::new(false, vec![])
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    /// The number of bits in the magnitude, zero for zero.
+    pub fn bits(&self) -> u64 {
+        match self.digits.last() {
+            Some(top) => self.digits.len() as u64 * 32 - u64::from(top.leading_zeros()),
+            None => 0,
+        }
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.digits.len() > 2 {
+            return None;
+        }
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0u64, |acc, digit| (acc << 32) | u64::from(*digit));
+        if self.negative {
+            0i64.checked_sub_unsigned(magnitude)
+        } else {
+            i64::try_from(magnitude).ok()
+        }
+    }
+
+    /// The value divided by `2^bits`, rounded toward zero.
+    pub fn shift_down(&self, bits: u64) -> Self {
+        let skip = (bits / 32) as usize;
+        if skip >= self.digits.len() {
+            return BigInt::zero();
+        }
+        let shift = bits % 32;
+        let digits = &self.digits[skip..];
+        let shifted = (0..digits.len())
+            .map(|i| {
+                let high = digits.get(i + 1).map_or(0, |digit| u64::from(*digit));
+                (((high << 32) | u64::from(digits[i])) >> shift) as u32
+            })
+            .collect();
+        BigInt::new(self.negative, shifted)
+    }
+
+    /// `2^exponent`.
+    pub fn power_of_two(exponent: u32) -> Self {
+        let mut digits = vec![0; exponent as usize / 32];
+        digits.push(1 << (exponent % 32));
+        BigInt::new(false, digits)
+    }
+
+    pub fn from_u64(value: u64) -> Self {
+        BigInt::new(false, vec![value as u32, (value >> 32) as u32])
+    }
+
+    pub fn to_u64(&self) -> Option<u64> {
+        if self.negative || self.digits.len() > 2 {
+            return None;
+        }
+        Some(
+            self.digits
+                .iter()
+                .rev()
+                .fold(0u64, |acc, digit| (acc << 32) | u64::from(*digit)),
+        )
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0.0, |acc, digit| acc * 4294967296.0 + f64::from(*digit));
+        if self.negative {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+
+    /// Parses an optionally signed string of decimal digits.
+    pub fn parse(text: &str) -> Option<BigInt> {
+        let (negative, digits) = match text.as_bytes().first()? {
+            b'-' => (true, &text[1..]),
+            b'+' => (false, &text[1..]),
+            _ => (false, text),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        let mut magnitude = vec![];
+        let first_chunk = digits.len() % DECIMAL_CHUNK_WIDTH;
+        let mut start = 0;
+        let mut end = if first_chunk == 0 {
+            DECIMAL_CHUNK_WIDTH
+        } else {
+            first_chunk
+        };
+        while start < digits.len() {
+            let chunk: u32 = digits[start..end].parse().ok()?;
+            let scale = 10u32.pow((end - start) as u32);
+            magnitude = mul_small_add(&magnitude, scale, chunk);
+            start = end;
+            end += DECIMAL_CHUNK_WIDTH;
+        }
+        Some(BigInt::new(negative, magnitude))
+    }
+
+    /// Truncating division, returning the quotient and a remainder with the
+    /// sign of the dividend. Returns `None` when dividing by zero.
+    pub fn div_rem(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (quotient, remainder) = div_rem_magnitudes(&self.digits, &other.digits);
+        Some((
+            BigInt::new(self.negative != other.negative, quotient),
+            BigInt::new(self.negative, remainder),
+        ))
+    }
+}
+
+/// The greatest common divisor of the magnitudes, which is never negative.
+pub fn gcd(a: &BigInt, b: &BigInt) -> BigInt {
+    let mut a = BigInt::new(false, a.digits.clone());
+    let mut b = BigInt::new(false, b.digits.clone());
+    while let Some((_, remainder)) = a.div_rem(&b) {
+        a = b;
+        b = remainder;
+    }
+    a
+}
+
+impl From<i64> for BigInt {
+    fn from(value: i64) -> Self {
+        let magnitude = value.unsigned_abs();
+        BigInt::new(value < 0, vec![magnitude as u32, (magnitude >> 32) as u32])
+    }
+}
+
+fn compare_magnitudes(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u64;
+    for i in 0..a.len().max(b.len()) {
+        let sum = u64::from(*a.get(i).unwrap_or(&0)) + u64::from(*b.get(i).unwrap_or(&0)) + carry;
+        out.push(sum as u32);
+        carry = sum >> 32;
+    }
+    if carry > 0 {
+        out.push(carry as u32);
+    }
+    out
+}
+
+/// Computes `a - b` where `a >= b`.
+fn sub_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, digit) in a.iter().enumerate() {
+        let mut diff = i64::from(*digit) - i64::from(*b.get(i).unwrap_or(&0)) - borrow;
+        borrow = 0;
+        if diff < 0 {
+            diff += 1 << 32;
+            borrow = 1;
+        }
+        out.push(diff as u32);
+    }
+    out
+}
+
+fn mul_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = vec![0u32; a.len() + b.len()];
+    for (i, x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, y) in b.iter().enumerate() {
+            let product = u64::from(*x) * u64::from(*y) + u64::from(out[i + j]) + carry;
+            out[i + j] = product as u32;
+            carry = product >> 32;
+        }
+        out[i + b.len()] = carry as u32;
+    }
+    out
+}
+
+fn mul_small_add(a: &[u32], factor: u32, addend: u32) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len() + 1);
+    let mut carry = u64::from(addend);
+    for digit in a {
+        let product = u64::from(*digit) * u64::from(factor) + carry;
+        out.push(product as u32);
+        carry = product >> 32;
+    }
+    if carry > 0 {
+        out.push(carry as u32);
+    }
+    out
+}
+
+fn div_rem_small(a: &[u32], divisor: u32) -> (Vec<u32>, u32) {
+    let mut quotient = vec![0u32; a.len()];
+    let mut remainder = 0u64;
+    for (i, digit) in a.iter().enumerate().rev() {
+        let current = (remainder << 32) | u64::from(*digit);
+        quotient[i] = (current / u64::from(divisor)) as u32;
+        remainder = current % u64::from(divisor);
+    }
+    (quotient, remainder as u32)
+}
+
+/// Long division of magnitudes a whole digit at a time (Knuth's algorithm D):
+/// each quotient digit is estimated from the top digits, corrected at most
+/// twice, and its multiple of `b` subtracted in one pass.
+fn div_rem_magnitudes(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if compare_magnitudes(a, b) == Ordering::Less {
+        return (vec![], a.to_vec());
+    }
+    if b.len() == 1 {
+        let (quotient, remainder) = div_rem_small(a, b[0]);
+        return (quotient, vec![remainder]);
+    }
+
+    // Scaling both so the divisor's top bit is set keeps the estimates
+    // close.
+    let shift = b[b.len() - 1].leading_zeros();
+    let mut b = shift_left(b, shift);
+    b.pop();
+    let mut a = shift_left(a, shift);
+    let n = b.len();
+    let top = u64::from(b[n - 1]);
+    let second = u64::from(b[n - 2]);
+    let mut quotient = vec![0u32; a.len() - n];
+    for j in (0..quotient.len()).rev() {
+        let high = (u64::from(a[j + n]) << 32) | u64::from(a[j + n - 1]);
+        let mut estimate = high / top;
+        let mut rest = high % top;
+        while estimate >> 32 != 0 || estimate * second > ((rest << 32) | u64::from(a[j + n - 2])) {
+            estimate -= 1;
+            rest += top;
+            if rest >> 32 != 0 {
+                break;
+            }
+        }
+
+        let mut borrow = 0i64;
+        let mut carry = 0u64;
+        for i in 0..n {
+            let product = estimate * u64::from(b[i]) + carry;
+            carry = product >> 32;
+            let diff = i64::from(a[i + j]) - (product & 0xffff_ffff) as i64 + borrow;
+            a[i + j] = diff as u32;
+            borrow = diff >> 32;
+        }
+        let diff = i64::from(a[j + n]) - carry as i64 + borrow;
+        a[j + n] = diff as u32;
+
+        // The estimate was one too large: add `b` back.
+        if diff < 0 {
+            estimate -= 1;
+            let mut carry = 0u64;
+            for i in 0..n {
+                let sum = u64::from(a[i + j]) + u64::from(b[i]) + carry;
+                a[i + j] = sum as u32;
+                carry = sum >> 32;
+            }
+            a[j + n] = a[j + n].wrapping_add(carry as u32);
+        }
+        quotient[j] = estimate as u32;
+    }
+
+    let remainder = (0..n)
+        .map(|i| {
+            let high = if i + 1 < n { u64::from(a[i + 1]) } else { 0 };
+            (((high << 32) | u64::from(a[i])) >> shift) as u32
+        })
+        .collect();
+    (quotient, remainder)
+}
+
+/// The digits shifted up by `shift` bits, less than a whole digit, with one
+/// more digit for what is shifted out of the top.
+fn shift_left(digits: &[u32], shift: u32) -> Vec<u32> {
+    let mut out = Vec::with_capacity(digits.len() + 1);
+    let mut carry = 0;
+    for digit in digits {
+        let shifted = u64::from(*digit) << shift;
+        out.push(shifted as u32 | carry);
+        carry = (shifted >> 32) as u32;
+    }
+    out.push(carry);
+    out
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add_magnitudes(&self.digits, &other.digits));
+        }
+        match compare_magnitudes(&self.digits, &other.digits) {
+            Ordering::Less => {
+                BigInt::new(other.negative, sub_magnitudes(&other.digits, &self.digits))
+            }
+            _ => BigInt::new(self.negative, sub_magnitudes(&self.digits, &other.digits)),
+        }
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: &BigInt) -> BigInt {
+        self + &-other
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: &BigInt) -> BigInt {
+        BigInt::new(
+            self.negative != other.negative,
+            mul_magnitudes(&self.digits, &other.digits),
+        )
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::new(!self.negative, self.digits.clone())
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare_magnitudes(&self.digits, &other.digits),
+            (true, true) => compare_magnitudes(&other.digits, &self.digits),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        let mut chunks = vec![];
+        let mut magnitude = self.digits.clone();
+        while !magnitude.is_empty() {
+            let (quotient, chunk) = div_rem_small(&magnitude, DECIMAL_CHUNK);
+            chunks.push(chunk);
+            magnitude = quotient;
+            while magnitude.last() == Some(&0) {
+                magnitude.pop();
+            }
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.pop().unwrap())?;
+        for chunk in chunks.iter().rev() {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(text: &str) -> BigInt {
+        BigInt::parse(text).unwrap()
+    }
+
+    #[test]
+    fn test_parse_display() {
+        for text in &[
+            "0",
+            "7",
+            "-7",
+            "4294967296",
+            "18446744073709551616",
+            "-123456789012345678901234567890",
+            "1000000000000000000000000000000",
+        ] {
+            assert_eq!(big(text).to_string(), *text);
+        }
+        assert_eq!(big("-0").to_string(), "0");
+        assert_eq!(big("+0042").to_string(), "42");
+        assert_eq!(BigInt::parse(""), None);
+        assert_eq!(BigInt::parse("-"), None);
+        assert_eq!(BigInt::parse("12a"), None);
+    }
+
+    #[test]
+    fn test_i64_conversion() {
+        for value in &[0, 1, -1, i64::MAX, i64::MIN, 1 << 40] {
+            assert_eq!(BigInt::from(*value).to_i64(), Some(*value));
+            assert_eq!(BigInt::from(*value).to_string(), value.to_string());
+        }
+        assert_eq!(big("9223372036854775808").to_i64(), None);
+        assert_eq!(big("-9223372036854775809").to_i64(), None);
+    }
+
+    #[test]
+    fn test_f64_conversion() {
+        assert_eq!(big("0").to_f64(), 0.0);
+        assert_eq!(
+            big("-18446744073709551616").to_f64(),
+            -18446744073709551616.0
+        );
+        assert_eq!(big("1000000000000000000000000").to_f64(), 1e24);
+    }
+
+    #[test]
+    fn test_shift_down() {
+        assert_eq!(
+            big("-18446744073709551617").shift_down(1).to_string(),
+            "-9223372036854775808"
+        );
+        assert_eq!(
+            big("123456789012345678901234567890").shift_down(40),
+            big("112283295504626656")
+        );
+        assert_eq!(big("5").shift_down(64), BigInt::zero());
+        assert_eq!(big("5").shift_down(0), big("5"));
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let a = big("123456789012345678901234567890");
+        let b = big("-987654321098765432109876543210");
+        assert_eq!((&a + &b).to_string(), "-864197532086419753208641975320");
+        assert_eq!((&a - &b).to_string(), "1111111110111111111011111111100");
+        assert_eq!(
+            (&a * &b).to_string(),
+            "-121932631137021795226185032733622923332237463801111263526900"
+        );
+        assert_eq!((&a - &a), BigInt::zero());
+    }
+
+    #[test]
+    fn test_div_rem() {
+        let a = big("121932631137021795226185032733622923332237463801111263526901");
+        let b = big("-987654321098765432109876543210");
+        let (quotient, remainder) = a.div_rem(&b).unwrap();
+        assert_eq!(quotient.to_string(), "-123456789012345678901234567890");
+        assert_eq!(remainder.to_string(), "1");
+        let (quotient, remainder) = big("-7").div_rem(&big("2")).unwrap();
+        assert_eq!(
+            (quotient.to_string(), remainder.to_string()),
+            ("-3".into(), "-1".into())
+        );
+        assert_eq!(a.div_rem(&BigInt::zero()), None);
+    }
+
+    #[test]
+    fn test_div_rem_multi_digit() {
+        // Digits near 0 and 2^32 - 1 make the quotient digit estimates miss.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut digit = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            match state % 4 {
+                0 => 0,
+                1 => u32::MAX,
+                2 => 1 << 31,
+                _ => state as u32,
+            }
+        };
+        for round in 0..2000 {
+            let a = BigInt::new(false, (0..2 + round % 9).map(|_| digit()).collect());
+            let b = BigInt::new(
+                round % 3 == 0,
+                (0..2 + round % 4).map(|_| digit()).collect(),
+            );
+            if b.digits.len() < 2 {
+                continue;
+            }
+            let (quotient, remainder) = a.div_rem(&b).unwrap();
+            assert_eq!(&(&quotient * &b) + &remainder, a);
+            assert_eq!(
+                compare_magnitudes(&remainder.digits, &b.digits),
+                Ordering::Less
+            );
+        }
+    }
+
+    #[test]
+    fn test_gcd_of_large_operands() {
+        let three = BigInt::from(3);
+        let mut a = BigInt::from(1);
+        for _ in 0..2000 {
+            a = &a * &three;
+        }
+        let b = BigInt::power_of_two(3000);
+        assert_eq!(gcd(&a, &b), BigInt::from(1));
+        assert_eq!(gcd(&(&a * &b), &(&b * &b)), b);
+    }
+
+    #[test]
+    fn test_gcd() {
+        assert_eq!(gcd(&big("12"), &big("-18")), big("6"));
+        assert_eq!(gcd(&big("0"), &big("-5")), big("5"));
+        assert_eq!(
+            gcd(&big("36893488147419103232"), &big("27670116110564327424")),
+            big("9223372036854775808")
+        );
+    }
+
+    #[test]
+    fn test_ordering() {
+        assert!(big("-100000000000000000000") < big("-1"));
+        assert!(big("-1") < big("0"));
+        assert!(big("99999999999999999999") < big("100000000000000000000"));
+    }
+}
@@ -0,0 +1,655 @@
+use crate::context::{Env, Layout};
+use crate::error::Arity;
+use crate::expression::{ExprKind, Expression};
+use crate::function::Function;
+use crate::intern::{symbol, Symbol};
+use crate::ops::Operation;
+use crate::registry::{Native, NativeFn, Registry};
+use crate::span::Span;
+use crate::stack;
+use crate::value::Value;
+use std::rc::Rc;
+
+/// Builtins the VM runs directly. Each takes values already evaluated and
+/// checked by the instructions before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Lt,
+    Gt,
+    And,
+    Or,
+    Not,
+    Print,
+}
+
+/// How each argument of a builtin is checked as soon as it is evaluated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Check {
+    Any,
+    Number,
+    Bool,
+}
+
+fn builtin(name: &str) -> Option<(Builtin, Arity, Check)> {
+    let builtin = match name {
+        "+" => (Builtin::Add, Arity::AtLeast(1), Check::Number),
+        "-" => (Builtin::Sub, Arity::Exact(2), Check::Number),
+        "*" => (Builtin::Mul, Arity::AtLeast(1), Check::Number),
+        "/" => (Builtin::Div, Arity::Exact(2), Check::Number),
+        "%" => (Builtin::Rem, Arity::Exact(2), Check::Number),
+        "=" => (Builtin::Eq, Arity::Exact(2), Check::Any),
+        "<" => (Builtin::Lt, Arity::Exact(2), Check::Number),
+        ">" => (Builtin::Gt, Arity::Exact(2), Check::Number),
+        "and" => (Builtin::And, Arity::Exact(2), Check::Bool),
+        "or" => (Builtin::Or, Arity::Exact(2), Check::Bool),
+        "not" => (Builtin::Not, Arity::Exact(1), Check::Bool),
+        "print" => (Builtin::Print, Arity::Exact(1), Check::Any),
+        _ => return None,
+    };
+    Some(builtin)
+}
+
+/// Where a variable is found, resolved when the code using it is compiled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Address {
+    /// A slot of the frame some number of scopes out from the innermost.
+    Local(usize, usize),
+    /// A global, by its index into `Chunk::names`.
+    Global(usize),
+}
+
+/// A single VM instruction. Operands index into the tables of the `Chunk`
+/// or, for jumps, into its code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Const(usize),
+    Nil,
+    Pop,
+    /// Pushes the value of a variable, failing if it is unbound.
+    Load(Address),
+    Jump(usize),
+    /// Pops a bool, jumping if it is false.
+    JumpUnless(usize),
+    /// Jumps if the callee below that many arguments is a user function.
+    JumpIfFunction(usize, usize),
+    /// Checks that the argument on top of the stack is a number, unless the
+    /// callee that many values below it is a user function.
+    CheckNumber(usize),
+    CheckBool(usize),
+    /// Pushes the user function a builtin's name is bound to, or nil if it
+    /// refers to the builtin, as the callee of the arguments that follow.
+    Callee(Address),
+    /// Replaces the callee and the arguments above it with the builtin's
+    /// result.
+    Builtin(Builtin, usize),
+    /// If a special form's name is bound to a user function, jumps to code
+    /// that evaluates the whole call with the tree walker instead.
+    Shadowed(Address, usize),
+    /// Pushes the function a name in operator position refers to.
+    Function(Address),
+    CheckFunction,
+    Call(usize),
+    TailCall(usize),
+    /// Pops one value per binding and evaluates what follows in a new scope
+    /// laid out by `Chunk::layouts`, until the matching `EndLet`.
+    Let(usize),
+    EndLet,
+    Set(Address),
+    Global(usize),
+    /// Applies a builtin to its unevaluated arguments, for the forms the
+    /// compiler doesn't handle itself.
+    Operation(usize),
+    /// Calls a native function with the arguments on top of the stack, in
+    /// place of them and their callee.
+    Native(usize, usize),
+    /// Pops that many values into a vector.
+    Vector(usize),
+    /// Pops that many keys and values, each key below its value, into a map.
+    Map(usize),
+    /// Evaluates an expression with the tree walker.
+    Eval(usize),
+    Return,
+}
+
+/// Compiled code with the spans errors raised by each instruction are
+/// reported at.
+#[derive(Default)]
+pub struct Chunk {
+    pub code: Vec<Instruction>,
+    pub spans: Vec<Span>,
+    pub constants: Vec<Value>,
+    pub names: Vec<Symbol>,
+    pub layouts: Vec<Layout>,
+    pub operations: Vec<(Rc<dyn Operation>, Vec<Expression>)>,
+    pub natives: Vec<Rc<NativeFn>>,
+    pub exprs: Vec<Expression>,
+    /// The generation of the registry the chunk was compiled against.
+    pub generation: usize,
+}
+
+/// Compiles an expression to run in `scope`.
+pub fn compile(expr: &Expression, scope: Option<&Env>, builtins: &Registry) -> Chunk {
+    let mut compiler = Compiler::new(scope, builtins);
+    compiler.expr(expr, false);
+    compiler.emit(Instruction::Return, expr.span);
+    compiler.chunk
+}
+
+/// Compiles a function's body, whose last expression is in tail position.
+pub fn compile_function(function: &Function, builtins: &Registry) -> Chunk {
+    let span = match (function.body.first(), function.body.last()) {
+        (Some(first), Some(last)) => first.span.to(last.span),
+        _ => Span::default(),
+    };
+    let mut compiler = Compiler::new(function.env.as_ref(), builtins);
+    compiler.scopes.push(function.layout.names.clone());
+    compiler.body(&function.body, span, true);
+    compiler.emit(Instruction::Return, span);
+    compiler.chunk
+}
+
+struct Compiler<'a> {
+    chunk: Chunk,
+    /// The names bound by each scope the code runs in, innermost last.
+    scopes: Vec<Vec<Rc<str>>>,
+    builtins: &'a Registry,
+}
+
+impl<'a> Compiler<'a> {
+    /// A compiler for code run in `scope`. A frame's bindings never change
+    /// once it is made, so variables can be resolved against it now.
+    fn new(scope: Option<&Env>, builtins: &'a Registry) -> Self {
+        let mut scopes = Vec::new();
+        let mut scope = scope.cloned();
+        while let Some(frame) = scope {
+            let frame = frame.borrow();
+            scopes.push(frame.vars.iter().map(|(name, _)| name.clone()).collect());
+            scope = frame.parent.clone();
+        }
+        scopes.reverse();
+        Compiler {
+            chunk: Chunk {
+                generation: builtins.generation(),
+                ..Chunk::default()
+            },
+            scopes,
+            builtins,
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction, span: Span) -> usize {
+        self.chunk.code.push(instruction);
+        self.chunk.spans.push(span);
+        self.chunk.code.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.chunk.code.len();
+        match &mut self.chunk.code[at] {
+            Instruction::Jump(to)
+            | Instruction::JumpUnless(to)
+            | Instruction::JumpIfFunction(_, to)
+            | Instruction::Shadowed(_, to) => *to = target,
+            instruction => unreachable!("{:?} is not a jump", instruction),
+        }
+    }
+
+    fn name(&mut self, name: &str) -> usize {
+        let symbol = symbol(name);
+        match self.chunk.names.iter().position(|known| *known == symbol) {
+            Some(index) => index,
+            None => {
+                self.chunk.names.push(symbol);
+                self.chunk.names.len() - 1
+            }
+        }
+    }
+
+    fn resolve(&mut self, name: &str) -> Address {
+        for (depth, names) in self.scopes.iter().rev().enumerate() {
+            if let Some(slot) = names.iter().position(|bound| **bound == *name) {
+                return Address::Local(depth, slot);
+            }
+        }
+        Address::Global(self.name(name))
+    }
+
+    fn constant(&mut self, value: Value, span: Span) {
+        self.chunk.constants.push(value);
+        self.emit(Instruction::Const(self.chunk.constants.len() - 1), span);
+    }
+
+    fn operation(&mut self, op: Rc<dyn Operation>, args: &[Expression], span: Span) {
+        self.chunk.operations.push((op, args.to_vec()));
+        self.emit(
+            Instruction::Operation(self.chunk.operations.len() - 1),
+            span,
+        );
+    }
+
+    fn fallback(&mut self, expr: &Expression) {
+        self.chunk.exprs.push(expr.clone());
+        self.emit(Instruction::Eval(self.chunk.exprs.len() - 1), expr.span);
+    }
+
+    fn expr(&mut self, expr: &Expression, tail: bool) {
+        if stack::exhausted() {
+            // Left to the tree walker, which fails with `StackOverflow` if
+            // the expression is still too deep to evaluate.
+            return self.fallback(expr);
+        }
+        let items = match &expr.kind {
+            ExprKind::Value(value) => return self.constant(value.clone(), expr.span),
+            ExprKind::Symbol(name) => {
+                let address = self.resolve(name);
+                self.emit(Instruction::Load(address), expr.span);
+                return;
+            }
+            ExprKind::Vector(items) => {
+                for item in items {
+                    self.expr(item, false);
+                }
+                self.emit(Instruction::Vector(items.len()), expr.span);
+                return;
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expr(key, false);
+                    self.expr(value, false);
+                }
+                self.emit(Instruction::Map(entries.len()), expr.span);
+                return;
+            }
+            ExprKind::List(items) if items.is_empty() => return self.fallback(expr),
+            ExprKind::List(items) => items,
+        };
+
+        let (head, args) = items.split_first().unwrap();
+        let name = match &head.kind {
+            ExprKind::Symbol(name) => name,
+            _ => {
+                self.expr(head, false);
+                self.emit(Instruction::CheckFunction, head.span);
+                self.call(args, expr.span, tail);
+                return;
+            }
+        };
+        let address = self.resolve(name);
+        match (self.builtins.get(name).cloned(), head.op) {
+            (None, None) => {
+                self.emit(Instruction::Function(address), head.span);
+                self.call(args, expr.span, tail);
+            }
+            (Some(Native::Function(function)), _) => {
+                self.chunk.natives.push(function);
+                let native = Instruction::Native(self.chunk.natives.len() - 1, args.len());
+                self.eager(address, expr, Check::Any, (native, expr.span), tail);
+            }
+            (Some(Native::Form(op)), _) => {
+                self.form(address, expr, |compiler| {
+                    compiler.operation(op, args, expr.span)
+                });
+            }
+            (None, Some(op)) => match builtin(name) {
+                Some((builtin, arity, check)) if arity.accepts(args.len()) => {
+                    // Division reports its errors at the divisor.
+                    let span = match builtin {
+                        Builtin::Div | Builtin::Rem => args[1].span,
+                        _ => expr.span,
+                    };
+                    let instruction = Instruction::Builtin(builtin, args.len());
+                    self.eager(address, expr, check, (instruction, span), tail);
+                }
+                _ => self.form(address, expr, |compiler| {
+                    if !compiler.special(name, args, expr, tail) {
+                        compiler.operation(Rc::new(op), args, expr.span);
+                    }
+                }),
+            },
+        }
+    }
+
+    /// Compiles a call to a builtin taking evaluated arguments. Whether its
+    /// name is bound to a user function is only known once they have been
+    /// evaluated, so they are compiled once, and `apply` is followed by
+    /// code making the call instead.
+    fn eager(
+        &mut self,
+        address: Address,
+        expr: &Expression,
+        check: Check,
+        (apply, span): (Instruction, Span),
+        tail: bool,
+    ) {
+        let (head, args) = match &expr.kind {
+            ExprKind::List(items) => items.split_first().unwrap(),
+            _ => unreachable!("only calls are compiled eagerly"),
+        };
+        self.emit(Instruction::Callee(address), head.span);
+        for (index, arg) in args.iter().enumerate() {
+            self.expr(arg, false);
+            match check {
+                Check::Any => {}
+                Check::Number => {
+                    self.emit(Instruction::CheckNumber(index + 1), arg.span);
+                }
+                Check::Bool => {
+                    self.emit(Instruction::CheckBool(index + 1), arg.span);
+                }
+            }
+        }
+        let shadowed = self.emit(Instruction::JumpIfFunction(args.len(), 0), head.span);
+        self.emit(apply, span);
+        let end = self.emit(Instruction::Jump(0), expr.span);
+        self.patch(shadowed);
+        let call = if tail {
+            Instruction::TailCall(args.len())
+        } else {
+            Instruction::Call(args.len())
+        };
+        self.emit(call, expr.span);
+        self.patch(end);
+    }
+
+    /// Compiles a form whose arguments aren't simply evaluated first with
+    /// `compile`. If its name is bound to a user function, the call is left
+    /// to the tree walker instead.
+    fn form(&mut self, address: Address, expr: &Expression, compile: impl FnOnce(&mut Self)) {
+        let shadowed = self.emit(Instruction::Shadowed(address, 0), expr.span);
+        compile(self);
+        let end = self.emit(Instruction::Jump(0), expr.span);
+        self.patch(shadowed);
+        self.fallback(expr);
+        self.patch(end);
+    }
+
+    /// Evaluates the arguments and calls the function below them.
+    fn call(&mut self, args: &[Expression], span: Span, tail: bool) {
+        for arg in args {
+            self.expr(arg, false);
+        }
+        let call = if tail {
+            Instruction::TailCall(args.len())
+        } else {
+            Instruction::Call(args.len())
+        };
+        self.emit(call, span);
+    }
+
+    fn body(&mut self, body: &[Expression], span: Span, tail: bool) {
+        match body.split_last() {
+            Some((last, init)) => {
+                for expr in init {
+                    self.expr(expr, false);
+                    self.emit(Instruction::Pop, expr.span);
+                }
+                self.expr(last, tail);
+            }
+            None => {
+                self.emit(Instruction::Nil, span);
+            }
+        }
+    }
+
+    /// Compiles a special form the VM runs directly, returning `false` if it
+    /// is left to its `Operation`. Malformed forms are always left to it, so
+    /// that they fail in exactly the same way.
+    fn special(&mut self, name: &str, args: &[Expression], expr: &Expression, tail: bool) -> bool {
+        match (name, args) {
+            ("quote", [quoted]) => self.constant(quoted.to_value(), expr.span),
+            ("if", [condition, then, rest @ ..]) if rest.len() <= 1 => {
+                self.expr(condition, false);
+                let otherwise = self.emit(Instruction::JumpUnless(0), condition.span);
+                self.expr(then, tail);
+                let end = self.emit(Instruction::Jump(0), expr.span);
+                self.patch(otherwise);
+                match rest.first() {
+                    Some(otherwise) => self.expr(otherwise, tail),
+                    None => {
+                        self.emit(Instruction::Nil, expr.span);
+                    }
+                }
+                self.patch(end);
+            }
+            ("while", [condition, body]) => {
+                let start = self.chunk.code.len();
+                self.expr(condition, false);
+                let end = self.emit(Instruction::JumpUnless(0), condition.span);
+                self.expr(body, false);
+                self.emit(Instruction::Pop, body.span);
+                self.emit(Instruction::Jump(start), expr.span);
+                self.patch(end);
+                self.emit(Instruction::Nil, expr.span);
+            }
+            ("block", body) => self.body(body, expr.span, tail),
+            ("let", [bindings, body @ ..]) => {
+                let names = match let_bindings(bindings) {
+                    Some(names) => names,
+                    None => return false,
+                };
+                for (_, value) in &names {
+                    self.expr(value, false);
+                }
+                let layout = Layout::new(names.into_iter().map(|(name, _)| name));
+                self.scopes.push(layout.names.clone());
+                self.chunk.layouts.push(layout);
+                self.emit(Instruction::Let(self.chunk.layouts.len() - 1), expr.span);
+                self.body(body, expr.span, tail);
+                self.scopes.pop();
+                self.emit(Instruction::EndLet, expr.span);
+            }
+            ("set!", [target, value]) => match &target.kind {
+                ExprKind::Symbol(target_name) => {
+                    let address = self.resolve(target_name);
+                    self.expr(value, false);
+                    self.emit(Instruction::Set(address), target.span);
+                }
+                _ => return false,
+            },
+            ("global", [target, value]) => match &target.kind {
+                ExprKind::Symbol(target_name) => {
+                    let index = self.name(target_name);
+                    self.expr(value, false);
+                    self.emit(Instruction::Global(index), expr.span);
+                }
+                _ => return false,
+            },
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// The names and value expressions of well-formed `let` bindings.
+fn let_bindings(bindings: &Expression) -> Option<Vec<(Rc<str>, &Expression)>> {
+    let bindings = match &bindings.kind {
+        ExprKind::List(bindings) => bindings,
+        _ => return None,
+    };
+    let mut names = Vec::with_capacity(bindings.len());
+    for binding in bindings {
+        match &binding.kind {
+            ExprKind::List(pair) => match pair.as_slice() {
+                [Expression {
+                    kind: ExprKind::Symbol(name),
+                    ..
+                }, value] => names.push((name.clone(), value)),
+                _ => return None,
+            },
+            _ => return None,
+        }
+    }
+    Some(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use crate::macros::expand;
+
+    fn code(source: &str) -> Vec<Instruction> {
+        compile(&Expression::from(source), None, &Registry::default()).code
+    }
+
+    fn function_code(source: &str) -> Vec<Instruction> {
+        let mut context = Context::new();
+        match Expression::from(source).eval(&mut context) {
+            Ok(Value::Function(ref function)) => compile_function(function, &context.builtins).code,
+            result => panic!("expected a function, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_constants_and_symbols() {
+        assert_eq!(code("5"), vec![Instruction::Const(0), Instruction::Return]);
+        assert_eq!(
+            code("x"),
+            vec![Instruction::Load(Address::Global(0)), Instruction::Return]
+        );
+        assert_eq!(
+            code("[1 x]"),
+            vec![
+                Instruction::Const(0),
+                Instruction::Load(Address::Global(0)),
+                Instruction::Vector(2),
+                Instruction::Return
+            ]
+        );
+    }
+
+    #[test]
+    fn test_builtins_check_for_shadowing() {
+        assert_eq!(
+            code("(< x 1)"),
+            vec![
+                Instruction::Callee(Address::Global(0)),
+                Instruction::Load(Address::Global(1)),
+                Instruction::CheckNumber(1),
+                Instruction::Const(0),
+                Instruction::CheckNumber(2),
+                Instruction::JumpIfFunction(2, 8),
+                Instruction::Builtin(Builtin::Lt, 2),
+                Instruction::Jump(9),
+                Instruction::Call(2),
+                Instruction::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_code_grows_linearly() {
+        let mut context = Context::with_prelude();
+        let mut length = |source: &str| {
+            let expr = expand(&Expression::from(source), &mut context).unwrap();
+            compile(&expr, None, &Registry::default()).code.len()
+        };
+        let nested = |depth: usize, open: &str, leaf: &str| {
+            format!("{}{}{}", open.repeat(depth), leaf, ")".repeat(depth))
+        };
+        let clauses = |count: usize| {
+            let clauses: String = (0..count)
+                .map(|i| format!("((= x {}) {}) ", i, i))
+                .collect();
+            format!("(cond {}(true x))", clauses)
+        };
+        let sources: [&dyn Fn(usize) -> String; 4] = [
+            &|depth| nested(depth, "(+ 1 ", "1"),
+            &|depth| nested(depth, "(if x 1 ", "2"),
+            &|depth| nested(depth, "(list ", "1"),
+            &clauses,
+        ];
+        for source in sources.iter() {
+            let step = length(&source(2)) - length(&source(1));
+            for depth in 2..40 {
+                assert_eq!(
+                    length(&source(depth + 1)) - length(&source(depth)),
+                    step,
+                    "{}",
+                    source(depth)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_user_calls_and_tail_position() {
+        assert_eq!(
+            function_code("(lambda () (f (g 1)))"),
+            vec![
+                Instruction::Function(Address::Global(0)),
+                Instruction::Function(Address::Global(1)),
+                Instruction::Const(0),
+                Instruction::Call(1),
+                Instruction::TailCall(1),
+                Instruction::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lexical_addresses() {
+        assert_eq!(
+            code("(let ((x 1) (y 2) (x 3)) (let ((z x)) (set! y z)))"),
+            vec![
+                Instruction::Shadowed(Address::Global(0), 18),
+                Instruction::Const(0),
+                Instruction::Const(1),
+                Instruction::Const(2),
+                Instruction::Let(0),
+                Instruction::Shadowed(Address::Global(0), 15),
+                Instruction::Load(Address::Local(0, 0)),
+                Instruction::Let(1),
+                Instruction::Shadowed(Address::Global(1), 12),
+                Instruction::Load(Address::Local(0, 0)),
+                Instruction::Set(Address::Local(1, 1)),
+                Instruction::Jump(13),
+                Instruction::Eval(0),
+                Instruction::EndLet,
+                Instruction::Jump(16),
+                Instruction::Eval(1),
+                Instruction::EndLet,
+                Instruction::Jump(19),
+                Instruction::Eval(2),
+                Instruction::Return,
+            ]
+        );
+        assert!(
+            function_code("(let ((a 1)) (lambda (b & c) (f a b c)))").ends_with(&[
+                Instruction::Load(Address::Local(1, 0)),
+                Instruction::Load(Address::Local(0, 0)),
+                Instruction::Load(Address::Local(0, 1)),
+                Instruction::TailCall(3),
+                Instruction::Return,
+            ])
+        );
+        assert_eq!(
+            function_code("(lambda (+) (+ 1 2))")[0],
+            Instruction::Callee(Address::Local(0, 0))
+        );
+    }
+
+    #[test]
+    fn test_malformed_forms_fall_back() {
+        assert_eq!(
+            code("(if true)"),
+            vec![
+                Instruction::Shadowed(Address::Global(0), 3),
+                Instruction::Operation(0),
+                Instruction::Jump(4),
+                Instruction::Eval(0),
+                Instruction::Return,
+            ]
+        );
+        assert_eq!(code("(len x)")[1], Instruction::Operation(0));
+        assert_eq!(code("(let ((1 2)) 3)")[1], Instruction::Operation(0));
+        assert_eq!(code("()"), vec![Instruction::Eval(0), Instruction::Return]);
+    }
+}
@@ -1,14 +1,341 @@
+use crate::error::{EvalError, EvalErrorKind};
+use crate::expression::Expression;
+use crate::function::Function;
+use crate::gc::Heap;
+use crate::intern::{self, Symbol};
+use crate::program::Program;
+use crate::registry::Registry;
 use crate::value::Value;
+use crate::vm;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
 
+pub(crate) type Env = Rc<RefCell<Frame>>;
+
+/// How programs are evaluated. Both give the same results; the VM is faster.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Engine {
+    Tree,
+    Vm,
+}
+
+/// How deeply calls may nest before evaluation fails with `StackOverflow`.
+/// Evaluation also fails that way once the thread's stack is nearly used
+/// up, which on a small stack can come first.
+pub const DEFAULT_MAX_DEPTH: usize = 10_000;
+
+const PRELUDE: &str = include_str!("prelude.lisplike");
+
+/// A single scope of local bindings, linked to the scope it was created in.
+/// Bindings keep the order they were first made in, so compiled code can
+/// reach them by position.
+pub(crate) struct Frame {
+    pub vars: Vec<(Rc<str>, Value)>,
+    pub parent: Option<Env>,
+}
+
+impl Frame {
+    pub fn new(parent: Option<Env>) -> Self {
+        Frame {
+            vars: Vec::new(),
+            parent,
+        }
+    }
+
+    /// Binds `name`, replacing any earlier binding of it in this frame.
+    pub fn bind(&mut self, name: Rc<str>, value: Value) {
+        match self.get_mut(&name) {
+            Some(slot) => *slot = value,
+            None => self.vars.push((name, value)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.vars
+            .iter()
+            .find(|(bound, _)| **bound == *name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Value> {
+        self.vars
+            .iter_mut()
+            .find(|(bound, _)| **bound == *name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// The shape of a frame made all at once, by a call or a `let`: the names
+/// it binds, and the slot each value given in turn is stored in. A name given
+/// twice keeps its first slot and takes its last value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Layout {
+    pub names: Vec<Rc<str>>,
+    pub slots: Vec<usize>,
+}
+
+impl Layout {
+    pub fn new(names: impl IntoIterator<Item = Rc<str>>) -> Self {
+        let mut layout = Layout::default();
+        for name in names {
+            let slot = match layout.names.iter().position(|known| *known == name) {
+                Some(slot) => slot,
+                None => {
+                    layout.names.push(name);
+                    layout.names.len() - 1
+                }
+            };
+            layout.slots.push(slot);
+        }
+        layout
+    }
+
+    /// A frame binding one value per slot, in order.
+    pub fn frame(&self, values: impl IntoIterator<Item = Value>, parent: Option<Env>) -> Frame {
+        let mut vars: Vec<_> = self
+            .names
+            .iter()
+            .map(|name| (name.clone(), Value::Nil))
+            .collect();
+        for (&slot, value) in self.slots.iter().zip(values) {
+            vars[slot].1 = value;
+        }
+        Frame { vars, parent }
+    }
+}
+
+/// The global bindings, indexed by symbol. Each keeps its symbol, so the
+/// number stays its own while it is bound.
+#[derive(Default)]
+pub struct Globals {
+    values: Vec<Option<(Symbol, Value)>>,
+    len: usize,
+}
+
+impl Globals {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.get_symbol(&intern::lookup(name)?)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Value> {
+        self.get_symbol_mut(&intern::lookup(name)?)
+    }
+
+    pub fn insert(&mut self, name: &str, value: Value) -> Option<Value> {
+        self.insert_symbol(&intern::symbol(name), value)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Value> {
+        let index = intern::lookup(name)?.index();
+        let (_, removed) = self.values.get_mut(index)?.take()?;
+        self.len -= 1;
+        Some(removed)
+    }
+
+    pub(crate) fn get_symbol(&self, symbol: &Symbol) -> Option<&Value> {
+        let (_, value) = self.values.get(symbol.index())?.as_ref()?;
+        Some(value)
+    }
+
+    pub(crate) fn get_symbol_mut(&mut self, symbol: &Symbol) -> Option<&mut Value> {
+        let (_, value) = self.values.get_mut(symbol.index())?.as_mut()?;
+        Some(value)
+    }
+
+    pub(crate) fn insert_symbol(&mut self, symbol: &Symbol, value: Value) -> Option<Value> {
+        if self.values.len() <= symbol.index() {
+            self.values.resize(symbol.index() + 1, None);
+        }
+        let previous = self.values[symbol.index()].replace((symbol.clone(), value));
+        match previous {
+            Some((_, previous)) => Some(previous),
+            None => {
+                self.len += 1;
+                None
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Everything a program runs against: its globals and macros, the builtins
+/// the host registered, and settings such as the engine and the limits on
+/// call depth and heap size. Programs evaluated in the same context see each
+/// other's definitions.
 pub struct Context {
-    pub globals: HashMap<String, Value>,
+    pub globals: Globals,
+    pub macros: HashMap<String, Rc<Function>>,
+    pub builtins: Registry,
+    pub(crate) args: Vec<String>,
+    pub(crate) scope: Option<Env>,
+    pub(crate) gensym_counter: usize,
+    pub(crate) heap: Heap,
+    /// The number of function calls in progress, excluding tail calls.
+    pub(crate) depth: usize,
+    /// The number of nested `expand` calls in progress, which `max_depth`
+    /// also bounds.
+    pub(crate) expansion_depth: usize,
+    pub(crate) max_depth: usize,
+    pub(crate) engine: Engine,
+    /// Where `print` writes.
+    pub(crate) output: Box<dyn Write>,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Context::new()
+    }
 }
 
 impl Context {
     pub fn new() -> Self {
         Context {
-            globals: HashMap::new(),
+            globals: Globals::default(),
+            args: Vec::new(),
+            scope: None,
+            macros: HashMap::new(),
+            builtins: Registry::default(),
+            gensym_counter: 0,
+            heap: Heap::new(),
+            depth: 0,
+            expansion_depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            engine: Engine::Vm,
+            output: Box::new(io::stdout()),
+        }
+    }
+
+    /// A context with the standard library macros (`when`, `unless`, `cond`
+    /// and `for`) already defined.
+    pub fn with_prelude() -> Self {
+        let mut context = Context::new();
+        Program::parse(PRELUDE)
+            .expect("prelude should parse")
+            .eval(&mut context)
+            .expect("prelude should evaluate");
+        context
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    /// Chooses how programs are evaluated from now on.
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Bounds how deeply calls and macro expansions may nest before failing
+    /// with `StackOverflow`. The default is `DEFAULT_MAX_DEPTH`.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// The script arguments `arg` and `arg-count` see.
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    /// The most cells the heap may hold, as `set_heap_limit` set it.
+    pub fn heap_limit(&self) -> Option<usize> {
+        self.heap.limit
+    }
+
+    /// Bounds the heap: once frames, atoms and the items and bytes of live
+    /// collections and strings number more than `limit`, evaluation fails
+    /// with `HeapLimitExceeded`. `None`, the default, removes the bound.
+    pub fn set_heap_limit(&mut self, limit: Option<usize>) {
+        self.heap.limit = limit;
+    }
+
+    /// Sends what `print` writes to `output` instead of standard output.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    /// Writes `text` and a newline to the output.
+    pub(crate) fn print(&mut self, text: &str) -> Result<(), EvalError> {
+        writeln!(self.output, "{}", text)
+            .map_err(|err| EvalErrorKind::OutputFailed(err.to_string()).into())
+    }
+
+    /// Evaluates an already macro-expanded expression with `engine`.
+    pub fn eval(&mut self, expr: &Expression) -> Result<Value, EvalError> {
+        match self.engine {
+            Engine::Tree => expr.eval(self),
+            Engine::Vm => vm::eval(expr, self),
+        }
+    }
+
+    /// Resolves `name` in the innermost scope that binds it, falling back to
+    /// the globals.
+    pub fn lookup(&self, name: &str) -> Option<Value> {
+        let mut scope = self.scope.clone();
+        while let Some(frame) = scope {
+            let frame = frame.borrow();
+            if let Some(value) = frame.get(name) {
+                return Some(value.clone());
+            }
+            scope = frame.parent.clone();
+        }
+        self.globals.get(name).cloned()
+    }
+
+    /// The frame `depth` scopes out from the innermost one.
+    pub(crate) fn frame(&self, depth: usize) -> Env {
+        let mut frame = self.scope.clone().expect("a scope that deep");
+        for _ in 0..depth {
+            let parent = frame.borrow().parent.clone().expect("a scope that deep");
+            frame = parent;
         }
+        frame
+    }
+
+    /// Rebinds `name` in the innermost scope that binds it, falling back to
+    /// the globals. Returns `false` if `name` is not bound anywhere.
+    pub fn assign(&mut self, name: &str, value: Value) -> bool {
+        let mut scope = self.scope.clone();
+        while let Some(frame) = scope {
+            let mut frame = frame.borrow_mut();
+            if let Some(slot) = frame.get_mut(name) {
+                *slot = value;
+                return true;
+            }
+            scope = frame.parent.clone();
+        }
+        match self.globals.get_mut(name) {
+            Some(slot) => {
+                *slot = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Runs `f` with `frame` as the innermost scope, restoring the current
+    /// scope afterwards.
+    pub(crate) fn with_frame<T>(
+        &mut self,
+        frame: Frame,
+        f: impl FnOnce(&mut Context) -> Result<T, EvalError>,
+    ) -> Result<T, EvalError> {
+        let env = self.heap.alloc(frame)?;
+        let outer = self.scope.replace(env);
+        let result = f(self);
+        self.scope = outer;
+        result
     }
 }
@@ -0,0 +1,378 @@
+use crate::bigint::BigInt;
+use crate::error::{Arity, EvalError, EvalErrorKind};
+use crate::registry::NativeFn;
+use crate::value::Value;
+use crate::vector::Vector;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+/// A Rust type a lisplike value can be converted to, as the arguments of a
+/// host function are.
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Result<Self, EvalError>;
+}
+
+/// A Rust type that converts to a lisplike value, as the result of a host
+/// function does.
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+/// What a host function may return: a value, or a `Result` of one for a
+/// function that can fail.
+pub trait IntoResult {
+    fn into_result(self) -> Result<Value, EvalError>;
+}
+
+impl<T: IntoValue> IntoResult for T {
+    fn into_result(self) -> Result<Value, EvalError> {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: IntoValue> IntoResult for Result<T, EvalError> {
+    fn into_result(self) -> Result<Value, EvalError> {
+        self.map(IntoValue::into_value)
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Result<Self, EvalError> {
+        Ok(value.clone())
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::Nil
+    }
+}
+
+macro_rules! integer_conversions {
+    ($($int:ty),*) => {
+        $(
+            impl FromValue for $int {
+                fn from_value(value: &Value) -> Result<Self, EvalError> {
+                    <$int>::try_from(value.as_integer()?)
+                        .map_err(|_| EvalErrorKind::IntegerOverflow.into())
+                }
+            }
+
+            impl IntoValue for $int {
+                fn into_value(self) -> Value {
+                    Value::Integer(i64::from(self))
+                }
+            }
+        )*
+    };
+}
+
+integer_conversions!(i8, i16, i32, i64, u8, u16, u32);
+
+macro_rules! wide_integer_conversions {
+    ($($int:ty),*) => {
+        $(
+            impl FromValue for $int {
+                fn from_value(value: &Value) -> Result<Self, EvalError> {
+                    let int = match value {
+                        Value::BigInt(int) => int.to_u64().and_then(|int| <$int>::try_from(int).ok()),
+                        _ => <$int>::try_from(value.as_integer()?).ok(),
+                    };
+                    int.ok_or_else(|| EvalErrorKind::IntegerOverflow.into())
+                }
+            }
+
+            impl IntoValue for $int {
+                fn into_value(self) -> Value {
+                    match i64::try_from(self) {
+                        Ok(int) => Value::Integer(int),
+                        Err(_) => Value::BigInt(BigInt::from_u64(self as u64)),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+wide_integer_conversions!(isize, u64, usize);
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Result<Self, EvalError> {
+        value.as_bool()
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Result<Self, EvalError> {
+        value.as_string().map(str::to_string)
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::String(self.into())
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::String(self.into())
+    }
+}
+
+/// `nil` is `None`; anything else must convert to `T`.
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Result<Self, EvalError> {
+        match value {
+            Value::Nil => Ok(None),
+            _ => T::from_value(value).map(Some),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        self.map_or(Value::Nil, IntoValue::into_value)
+    }
+}
+
+/// Accepts a list or a vector.
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Value) -> Result<Self, EvalError> {
+        match value {
+            Value::List(items) => items.iter().map(T::from_value).collect(),
+            Value::Vector(items) => items.iter().map(T::from_value).collect(),
+            _ => Err(value.type_mismatch("list")),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        Value::List(Rc::new(
+            self.into_iter().map(IntoValue::into_value).collect(),
+        ))
+    }
+}
+
+impl IntoValue for Vector {
+    fn into_value(self) -> Value {
+        Value::Vector(self)
+    }
+}
+
+/// Accepts a map keyed by strings or keywords, taking a keyword `:name` as
+/// the key `"name"`.
+impl<T: FromValue> FromValue for HashMap<String, T> {
+    fn from_value(value: &Value) -> Result<Self, EvalError> {
+        let entries = match value {
+            Value::Map(entries) => entries,
+            _ => return Err(value.type_mismatch("map")),
+        };
+        let mut map = HashMap::with_capacity(entries.len());
+        for (key, value) in entries.iter() {
+            let key = match key {
+                Value::String(name) | Value::Keyword(name) => name.to_string(),
+                _ => return Err(key.type_mismatch("string")),
+            };
+            map.insert(key, T::from_value(value)?);
+        }
+        Ok(map)
+    }
+}
+
+/// Builds a map keyed by strings.
+impl<T: IntoValue> IntoValue for HashMap<String, T> {
+    fn into_value(self) -> Value {
+        let entries: BTreeMap<Value, Value> = self
+            .into_iter()
+            .map(|(key, value)| (key.into_value(), value.into_value()))
+            .collect();
+        Value::Map(Rc::new(entries))
+    }
+}
+
+/// A Rust function or closure that can be registered as a host builtin,
+/// taking `FromValue` arguments and returning an `IntoResult`. `Args` is
+/// the tuple of its argument types, which only tells the impls apart.
+pub trait IntoNative<Args> {
+    fn into_native(self) -> Rc<NativeFn>;
+}
+
+macro_rules! into_native {
+    ($($arg:ident $value:ident),*) => {
+        impl<F, R, $($arg),*> IntoNative<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: IntoResult,
+            $($arg: FromValue,)*
+        {
+            fn into_native(self) -> Rc<NativeFn> {
+                Rc::new(move |args: &[Value]| match args {
+                    [$($value),*] => self($(<$arg>::from_value($value)?),*).into_result(),
+                    _ => Err(EvalErrorKind::ArityMismatch {
+                        expected: Arity::Exact(<[&str]>::len(&[$(stringify!($arg)),*])),
+                        actual: args.len(),
+                    }
+                    .into()),
+                })
+            }
+        }
+    };
+}
+
+into_native!();
+into_native!(A a);
+into_native!(A a, B b);
+into_native!(A a, B b, C c);
+into_native!(A a, B b, C c, D d);
+into_native!(A a, B b, C c, D d, E e);
+into_native!(A a, B b, C c, D d, E e, G g);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_integers() {
+        assert_eq!(i64::from_value(&Value::Integer(-3)), Ok(-3));
+        assert_eq!(u8::from_value(&Value::Integer(255)), Ok(255));
+        assert_eq!(
+            u8::from_value(&Value::Integer(256)).map_err(|err| err.kind),
+            Err(EvalErrorKind::IntegerOverflow)
+        );
+        assert_eq!(
+            usize::from_value(&Value::Integer(-1)).map_err(|err| err.kind),
+            Err(EvalErrorKind::IntegerOverflow)
+        );
+        assert_eq!(
+            i32::from_value(&Value::Float(1.0)).map_err(|err| err.kind),
+            Err(EvalErrorKind::TypeMismatch {
+                expected: "integer",
+                actual: "float"
+            })
+        );
+        assert_eq!(7u16.into_value(), Value::Integer(7));
+        assert_eq!(u64::MAX.into_value().to_string(), "18446744073709551615");
+        assert_eq!(
+            u64::from_value(&(i64::MAX as u64).into_value()),
+            Ok(i64::MAX as u64)
+        );
+        let max = u64::MAX.into_value();
+        assert_eq!(u64::from_value(&max), Ok(u64::MAX));
+        assert_eq!(
+            usize::from_value(&(usize::MAX).into_value()),
+            Ok(usize::MAX)
+        );
+        assert_eq!(
+            i64::from_value(&max).map_err(|err| err.kind),
+            Err(EvalErrorKind::IntegerOverflow)
+        );
+        assert_eq!(
+            u32::from_value(&max).map_err(|err| err.kind),
+            Err(EvalErrorKind::IntegerOverflow)
+        );
+    }
+
+    #[test]
+    fn test_scalars() {
+        assert_eq!(bool::from_value(&Value::Bool(true)), Ok(true));
+        assert_eq!(false.into_value(), Value::Bool(false));
+        assert_eq!(
+            String::from_value(&Value::String("text".into())),
+            Ok("text".to_string())
+        );
+        assert_eq!(
+            String::from_value(&Value::Keyword("text".into())).map_err(|err| err.kind),
+            Err(EvalErrorKind::TypeMismatch {
+                expected: "string",
+                actual: "keyword"
+            })
+        );
+        assert_eq!("text".into_value(), Value::String("text".into()));
+        assert_eq!(().into_value(), Value::Nil);
+    }
+
+    #[test]
+    fn test_options() {
+        assert_eq!(Option::<i64>::from_value(&Value::Nil), Ok(None));
+        assert_eq!(Option::<i64>::from_value(&Value::Integer(1)), Ok(Some(1)));
+        assert!(Option::<i64>::from_value(&Value::Bool(true)).is_err());
+        assert_eq!(None::<bool>.into_value(), Value::Nil);
+        assert_eq!(Some(2).into_value(), Value::Integer(2));
+    }
+
+    #[test]
+    fn test_collections() {
+        let list = vec![1, 2, 3].into_value();
+        assert_eq!(list.to_string(), "(1 2 3)");
+        assert_eq!(Vec::<i64>::from_value(&list), Ok(vec![1, 2, 3]));
+        let vector = Vector::new().push(Value::Integer(4));
+        assert_eq!(Vec::<u8>::from_value(&vector.into_value()), Ok(vec![4]));
+        assert_eq!(
+            Vec::<bool>::from_value(&list).map_err(|err| err.kind),
+            Err(EvalErrorKind::TypeMismatch {
+                expected: "bool",
+                actual: "integer"
+            })
+        );
+
+        let mut map = HashMap::new();
+        map.insert("a".to_string(), vec![true]);
+        let value = map.clone().into_value();
+        assert_eq!(value.to_string(), r#"{"a" (true)}"#);
+        assert_eq!(HashMap::from_value(&value), Ok(map));
+
+        let mut entries = BTreeMap::new();
+        entries.insert(Value::Keyword("b".into()), Value::Integer(1));
+        let keyed = HashMap::<String, i64>::from_value(&Value::Map(Rc::new(entries)));
+        assert_eq!(keyed.unwrap().get("b"), Some(&1));
+    }
+
+    #[test]
+    fn test_into_native() {
+        let add = (|a: i64, b: i64| a + b).into_native();
+        assert_eq!(
+            add(&[Value::Integer(1), Value::Integer(2)]),
+            Ok(Value::Integer(3))
+        );
+        assert_eq!(
+            add(&[Value::Integer(1)]).map_err(|err| err.kind),
+            Err(EvalErrorKind::ArityMismatch {
+                expected: Arity::Exact(2),
+                actual: 1
+            })
+        );
+        assert_eq!(
+            add(&[Value::Integer(1), Value::Nil]).map_err(|err| err.kind),
+            Err(EvalErrorKind::TypeMismatch {
+                expected: "integer",
+                actual: "nil"
+            })
+        );
+
+        let checked = (|a: i64, b: i64| {
+            a.checked_div(b)
+                .ok_or_else(|| EvalError::from(EvalErrorKind::DivisionByZero))
+        })
+        .into_native();
+        assert_eq!(
+            checked(&[Value::Integer(1), Value::Integer(0)]).map_err(|err| err.kind),
+            Err(EvalErrorKind::DivisionByZero)
+        );
+        assert_eq!((|| ()).into_native()(&[]), Ok(Value::Nil));
+    }
+}
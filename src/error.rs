@@ -0,0 +1,248 @@
+use crate::span::Span;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+    Range(usize, usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match *self {
+            Arity::Exact(n) => count == n,
+            Arity::AtLeast(n) => count >= n,
+            Arity::Range(min, max) => count >= min && count <= max,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arity::Exact(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+            Arity::Range(min, max) => write!(f, "{} to {}", min, max),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum EvalErrorKind {
+    UnknownOperator(String),
+    UnboundSymbol(String),
+    TypeMismatch {
+        expected: &'static str,
+        actual: &'static str,
+    },
+    ArityMismatch {
+        expected: Arity,
+        actual: usize,
+    },
+    DivisionByZero,
+    IntegerOverflow,
+    IndexOutOfBounds {
+        index: i64,
+        len: usize,
+    },
+    EmptyApplication,
+    InvalidSyntax {
+        expected: &'static str,
+    },
+    HeapLimitExceeded(usize),
+    StackOverflow(usize),
+    InvalidExitCode(i64),
+    Exit(i32),
+    /// Writing to the context's output failed, with the I/O error's message.
+    OutputFailed(String),
+}
+
+impl fmt::Display for EvalErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalErrorKind::UnknownOperator(name) => write!(f, "unknown operator `{}`", name),
+            EvalErrorKind::UnboundSymbol(name) => write!(f, "unbound symbol `{}`", name),
+            EvalErrorKind::TypeMismatch { expected, actual } => {
+                write!(f, "type mismatch: expected {}, found {}", expected, actual)
+            }
+            EvalErrorKind::ArityMismatch { expected, actual } => write!(
+                f,
+                "wrong number of arguments: expected {}, found {}",
+                expected, actual
+            ),
+            EvalErrorKind::DivisionByZero => write!(f, "division by zero"),
+            EvalErrorKind::IntegerOverflow => write!(f, "integer overflow"),
+            EvalErrorKind::IndexOutOfBounds { index, len } => {
+                write!(f, "index {} out of bounds for length {}", index, len)
+            }
+            EvalErrorKind::EmptyApplication => write!(f, "empty application `()`"),
+            EvalErrorKind::InvalidSyntax { expected } => {
+                write!(f, "invalid syntax: expected {}", expected)
+            }
+            EvalErrorKind::HeapLimitExceeded(limit) => {
+                write!(f, "heap limit exceeded: more than {} live cells", limit)
+            }
+            EvalErrorKind::StackOverflow(limit) => {
+                write!(f, "stack overflow: more than {} nested calls", limit)
+            }
+            EvalErrorKind::InvalidExitCode(code) => {
+                write!(f, "invalid exit status {}: expected 0 to 255", code)
+            }
+            EvalErrorKind::Exit(code) => write!(f, "exit with status {}", code),
+            EvalErrorKind::OutputFailed(message) => write!(f, "output failed: {}", message),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct EvalError {
+    pub kind: EvalErrorKind,
+    pub span: Option<Span>,
+}
+
+impl EvalError {
+    pub fn new(kind: EvalErrorKind) -> Self {
+        EvalError { kind, span: None }
+    }
+
+    /// Attaches `span` unless a more precise location is already known.
+    pub fn or_span(mut self, span: Span) -> Self {
+        self.span.get_or_insert(span);
+        self
+    }
+
+    pub fn report(&self, source: &str) -> String {
+        report(&self.to_string(), self.span, source)
+    }
+}
+
+impl From<EvalErrorKind> for EvalError {
+    fn from(kind: EvalErrorKind) -> Self {
+        EvalError::new(kind)
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{} at {}", self.kind, span),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+fn report(message: &str, span: Option<Span>, source: &str) -> String {
+    match span.and_then(|span| span.snippet(source)) {
+        Some(snippet) => format!("error: {}\n{}", message, snippet),
+        None => format!("error: {}", message),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParseErrorKind {
+    UnexpectedEof,
+    UnmatchedClose(char),
+    UnterminatedString,
+    InvalidEscape(String),
+    InvalidRatio(String),
+    OddMapLiteral,
+    MissingExpression,
+    TrailingInput,
+    TooDeeplyNested(usize),
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnexpectedEof => write!(f, "unexpected end of input"),
+            ParseErrorKind::UnmatchedClose(close) => write!(f, "unmatched `{}`", close),
+            ParseErrorKind::UnterminatedString => write!(f, "unterminated string"),
+            ParseErrorKind::OddMapLiteral => {
+                write!(f, "map literal needs an even number of forms")
+            }
+            ParseErrorKind::InvalidEscape(sequence) => {
+                write!(f, "invalid escape sequence `{}`", sequence)
+            }
+            ParseErrorKind::InvalidRatio(text) => {
+                write!(
+                    f,
+                    "invalid ratio `{}`: expected a non-zero, unsigned denominator",
+                    text
+                )
+            }
+            ParseErrorKind::MissingExpression => write!(f, "expected an expression"),
+            ParseErrorKind::TrailingInput => write!(f, "unexpected input after expression"),
+            ParseErrorKind::TooDeeplyNested(limit) => {
+                write!(f, "forms nested more than {} deep", limit)
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span,
+}
+
+impl ParseError {
+    pub fn new(kind: ParseErrorKind, span: Span) -> Self {
+        ParseError { kind, span }
+    }
+
+    /// Whether more input could turn this into a valid parse, as when a list
+    /// or string is left open at the end of the source.
+    pub fn is_incomplete(&self) -> bool {
+        matches!(
+            self.kind,
+            ParseErrorKind::UnexpectedEof | ParseErrorKind::UnterminatedString
+        )
+    }
+
+    pub fn report(&self, source: &str) -> String {
+        report(&self.to_string(), Some(self.span), source)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.kind, self.span)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Any failure that can occur while loading and running a program.
+#[derive(Debug)]
+pub enum Error {
+    /// A source file could not be read.
+    Io(PathBuf, std::io::Error),
+    Parse(ParseError, String),
+    Eval(EvalError, String),
+}
+
+impl Error {
+    pub fn report(&self) -> String {
+        match self {
+            Error::Io(..) => format!("error: {}", self),
+            Error::Parse(err, source) => err.report(source),
+            Error::Eval(err, source) => err.report(source),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            Error::Parse(err, _) => write!(f, "{}", err),
+            Error::Eval(err, _) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,84 +1,516 @@
 use crate::context::Context;
-use crate::ops::get_op;
-use crate::tokenizer::{tokenize, Token};
+use crate::error::{EvalError, EvalErrorKind, ParseError, ParseErrorKind};
+use crate::function::Function;
+use crate::intern::intern;
+use crate::ops::{get_op, Operation};
+use crate::span::Span;
+use crate::stack;
+use crate::tokenizer::{tokenize, Token, TokenKind};
 use crate::value::Value;
-use std::collections::VecDeque;
+use crate::vector::Vector;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::rc::Rc;
 
-#[derive(Debug, PartialEq)]
-pub enum Expression {
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExprKind {
     Value(Value),
-    Symbol(String),
-    Expression(Token, Vec<Expression>),
+    Symbol(Rc<str>),
+    List(Vec<Expression>),
+    /// A vector literal `[...]`, whose items are evaluated.
+    Vector(Vec<Expression>),
+    /// A map literal `{...}`, whose keys and values are evaluated.
+    Map(Vec<(Expression, Expression)>),
 }
 
-impl Expression {
-    pub fn eval(&self, context: &mut Context) -> Value {
+/// The result of evaluating an expression in tail position: a value, or a
+/// call to a user function left for the caller to make so that tail calls
+/// don't grow the stack. A call keeps the span of its call site, where
+/// failing to make it is reported.
+pub enum Tail {
+    Value(Value),
+    Call(Rc<Function>, Vec<Value>, Span),
+}
+
+impl Tail {
+    /// Makes the pending call, if any.
+    pub fn resolve(self, context: &mut Context) -> Result<Value, EvalError> {
         match self {
-            Expression::Value(v) => (*v).clone(),
-            Expression::Symbol(symbol) => context.globals.get(symbol).unwrap().clone(),
-            Expression::Expression(token, args) => {
-                let func_name = match token {
-                    Token::Symbol(text) => text,
-                    _ => panic!(),
-                };
-                let func = get_op(func_name).unwrap();
-                func.eval(args, context)
-            }
+            Tail::Value(value) => Ok(value),
+            Tail::Call(function, args, span) => function
+                .call(args, context)
+                .map_err(|err| err.or_span(span)),
         }
     }
 }
 
-impl From<&[Token]> for Expression {
-    fn from(tokens: &[Token]) -> Self {
-        Expression::from(&mut tokens.iter().cloned().collect::<VecDeque<Token>>())
+#[derive(Clone)]
+pub struct Expression {
+    pub kind: ExprKind,
+    pub span: Span,
+    /// The standard operation a symbol names, looked up once here so that
+    /// calls through it don't match on the name each time.
+    pub(crate) op: Option<&'static dyn Operation>,
+}
+
+impl fmt::Debug for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Expression")
+            .field("kind", &self.kind)
+            .field("span", &self.span)
+            .finish()
     }
 }
 
-impl From<&mut VecDeque<Token>> for Expression {
-    fn from(tokens: &mut VecDeque<Token>) -> Self {
-        assert!(tokens.len() >= 1);
+// Spans are ignored so that structurally identical trees compare equal
+// regardless of where they were parsed from.
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
 
-        let first_token = tokens.pop_front().unwrap();
-        match &first_token {
-            Token::Nil => {
-                return Expression::Value(Value::Nil);
-            }
-            Token::Integer(int) => {
-                return Expression::Value(Value::Integer(*int));
+impl Expression {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        let op = match &kind {
+            ExprKind::Symbol(name) => get_op(name),
+            _ => None,
+        };
+        Expression { kind, span, op }
+    }
+
+    pub fn parse(string: &str) -> Result<Vec<Expression>, ParseError> {
+        Expression::parse_tokens(tokenize(string)?)
+    }
+
+    /// Parses source that must contain exactly one expression.
+    pub fn parse_one(string: &str) -> Result<Expression, ParseError> {
+        single_expression(Expression::parse(string)?, Span::end_of(string))
+    }
+
+    pub(crate) fn parse_tokens(tokens: Vec<Token>) -> Result<Vec<Expression>, ParseError> {
+        let mut tokens = VecDeque::from(tokens);
+        let mut exprs = vec![];
+        while !tokens.is_empty() {
+            exprs.push(parse_expression(&mut tokens, 0)?);
+        }
+        Ok(exprs)
+    }
+
+    /// Builds the expression tree a value represents when treated as code.
+    /// Every node is given `span`, the location the value was produced at.
+    pub fn from_value(value: &Value, span: Span) -> Expression {
+        let kind = match value {
+            Value::Symbol(name) => ExprKind::Symbol(name.clone()),
+            Value::List(items) => ExprKind::List(
+                items
+                    .iter()
+                    .map(|item| Expression::from_value(item, span))
+                    .collect(),
+            ),
+            Value::Vector(items) => ExprKind::Vector(
+                items
+                    .iter()
+                    .map(|item| Expression::from_value(item, span))
+                    .collect(),
+            ),
+            Value::Map(entries) => ExprKind::Map(
+                entries
+                    .iter()
+                    .map(|(key, value)| {
+                        (
+                            Expression::from_value(key, span),
+                            Expression::from_value(value, span),
+                        )
+                    })
+                    .collect(),
+            ),
+            value => ExprKind::Value(value.clone()),
+        };
+        Expression::new(kind, span)
+    }
+
+    /// The inverse of `from_value`: the expression as quoted data.
+    pub fn to_value(&self) -> Value {
+        match &self.kind {
+            ExprKind::Value(value) => value.clone(),
+            ExprKind::Symbol(name) => Value::Symbol(name.clone()),
+            ExprKind::List(items) => {
+                Value::List(Rc::new(items.iter().map(Expression::to_value).collect()))
             }
-            Token::Bool(value) => {
-                return Expression::Value(Value::Bool(*value));
+            ExprKind::Vector(items) => {
+                Value::Vector(items.iter().map(Expression::to_value).collect())
             }
-            Token::String(string) => {
-                return Expression::Value(Value::String(string.to_string()));
+            ExprKind::Map(entries) => Value::Map(Rc::new(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.to_value(), value.to_value()))
+                    .collect(),
+            )),
+        }
+    }
+
+    pub fn eval(&self, context: &mut Context) -> Result<Value, EvalError> {
+        match &self.kind {
+            ExprKind::Value(v) => Ok((*v).clone()),
+            ExprKind::Symbol(symbol) => context.lookup(symbol).ok_or_else(|| {
+                EvalError::new(EvalErrorKind::UnboundSymbol(symbol.to_string())).or_span(self.span)
+            }),
+            ExprKind::List(_) => self
+                .eval_tail(context)
+                .and_then(|tail| tail.resolve(context))
+                .and_then(|value| self.track(value, context)),
+            _ if stack::exhausted() => Err(self.stack_overflow(context)),
+            ExprKind::Vector(items) => {
+                let mut vector = Vector::new();
+                for item in items {
+                    vector = vector.push(item.eval(context)?);
+                }
+                self.track(Value::Vector(vector), context)
             }
-            Token::Symbol(value) => {
-                return Expression::Symbol(value.to_string());
+            ExprKind::Map(entries) => {
+                let mut map = BTreeMap::new();
+                for (key, value) in entries {
+                    map.insert(key.eval(context)?, value.eval(context)?);
+                }
+                self.track(Value::Map(Rc::new(map)), context)
             }
-            _ => {}
         }
+    }
 
-        assert_eq!(first_token, Token::LParen);
+    /// Counts what the expression evaluated to against the heap limit.
+    fn track(&self, value: Value, context: &mut Context) -> Result<Value, EvalError> {
+        context
+            .heap
+            .track(&value)
+            .map_err(|err| err.or_span(self.span))?;
+        Ok(value)
+    }
 
-        let op = tokens.pop_front().unwrap().clone();
-        let mut args = Vec::<Expression>::new();
+    /// The error for an expression nested too deeply for the stack that is
+    /// left.
+    fn stack_overflow(&self, context: &Context) -> EvalError {
+        EvalError::new(EvalErrorKind::StackOverflow(context.depth)).or_span(self.span)
+    }
 
-        loop {
-            if tokens.front().unwrap() == &Token::RParen {
-                tokens.pop_front();
-                break;
+    /// Evaluates the expression as the last thing its enclosing function
+    /// does, leaving a call to a user function unmade.
+    pub fn eval_tail(&self, context: &mut Context) -> Result<Tail, EvalError> {
+        match &self.kind {
+            ExprKind::List(_) if stack::exhausted() => Err(self.stack_overflow(context)),
+            ExprKind::List(items) => {
+                let (head, args) = items.split_first().ok_or_else(|| {
+                    EvalError::new(EvalErrorKind::EmptyApplication).or_span(self.span)
+                })?;
+                self.apply(head, args, context)
+                    .map_err(|err| err.or_span(self.span))
             }
+            _ => self.eval(context).map(Tail::Value),
+        }
+    }
+
+    // User functions bound in scope take precedence over builtins of the same
+    // name, and those the host registered over the standard ones; bindings to
+    // anything else leave the builtin visible.
+    fn apply(
+        &self,
+        head: &Expression,
+        args: &[Expression],
+        context: &mut Context,
+    ) -> Result<Tail, EvalError> {
+        let function = match &head.kind {
+            ExprKind::Symbol(name) => match context.lookup(name) {
+                Some(Value::Function(ref function)) => function.clone(),
+                value => {
+                    if let Some(native) = context.builtins.get(name).cloned() {
+                        return native.eval_tail(args, context);
+                    }
+                    match (value, head.op) {
+                        (_, Some(op)) => return op.eval_tail(args, context),
+                        (None, None) => {
+                            let kind = EvalErrorKind::UnknownOperator(name.to_string());
+                            return Err(EvalError::new(kind).or_span(head.span));
+                        }
+                        (Some(_), None) => head.eval_function(context)?,
+                    }
+                }
+            },
+            _ => head.eval_function(context)?,
+        };
+
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(arg.eval(context)?);
+        }
+        Ok(Tail::Call(function, values, self.span))
+    }
+
+    pub fn eval_integer(&self, context: &mut Context) -> Result<i64, EvalError> {
+        let value = self.eval(context)?;
+        value.as_integer().map_err(|err| err.or_span(self.span))
+    }
 
-            args.push(Expression::from(&mut *tokens));
+    /// Evaluates to any kind of number, leaving it as a `Value`.
+    pub fn eval_number(&self, context: &mut Context) -> Result<Value, EvalError> {
+        let value = self.eval(context)?;
+        if value.is_number() {
+            Ok(value)
+        } else {
+            Err(value.type_mismatch("number").or_span(self.span))
         }
+    }
+
+    pub fn eval_bool(&self, context: &mut Context) -> Result<bool, EvalError> {
+        let value = self.eval(context)?;
+        value.as_bool().map_err(|err| err.or_span(self.span))
+    }
+
+    pub fn eval_string(&self, context: &mut Context) -> Result<Rc<str>, EvalError> {
+        let value = self.eval(context)?;
+        match &value {
+            Value::String(string) => Ok(string.clone()),
+            value => Err(value.type_mismatch("string").or_span(self.span)),
+        }
+    }
 
-        Expression::Expression(op, args)
+    pub fn eval_list(&self, context: &mut Context) -> Result<Rc<Vec<Value>>, EvalError> {
+        let value = self.eval(context)?;
+        value.into_list().map_err(|err| err.or_span(self.span))
     }
+
+    pub fn eval_vector(&self, context: &mut Context) -> Result<Vector, EvalError> {
+        match &self.eval(context)? {
+            Value::Vector(items) => Ok(items.clone()),
+            value => Err(value.type_mismatch("vector").or_span(self.span)),
+        }
+    }
+
+    pub fn eval_map(&self, context: &mut Context) -> Result<Rc<BTreeMap<Value, Value>>, EvalError> {
+        let value = self.eval(context)?;
+        value.into_map().map_err(|err| err.or_span(self.span))
+    }
+
+    pub fn eval_function(&self, context: &mut Context) -> Result<Rc<Function>, EvalError> {
+        let value = self.eval(context)?;
+        value
+            .as_function()
+            .cloned()
+            .map_err(|err| err.or_span(self.span))
+    }
+
+    pub fn as_symbol(&self) -> Result<&str, EvalError> {
+        match &self.kind {
+            ExprKind::Symbol(symbol) => Ok(symbol),
+            _ => Err(self.type_mismatch("symbol")),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&[Expression], EvalError> {
+        match &self.kind {
+            ExprKind::List(items) => Ok(items),
+            _ => Err(self.type_mismatch("list")),
+        }
+    }
+
+    pub fn syntax_error(&self, expected: &'static str) -> EvalError {
+        EvalError::new(EvalErrorKind::InvalidSyntax { expected }).or_span(self.span)
+    }
+
+    fn type_mismatch(&self, expected: &'static str) -> EvalError {
+        EvalError::new(EvalErrorKind::TypeMismatch {
+            expected,
+            actual: self.type_name(),
+        })
+        .or_span(self.span)
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match &self.kind {
+            ExprKind::Value(v) => v.type_name(),
+            ExprKind::Symbol(_) => "symbol",
+            ExprKind::List(_) => "list",
+            ExprKind::Vector(_) => "vector",
+            ExprKind::Map(_) => "map",
+        }
+    }
+}
+
+/// Evaluates each expression in turn, returning the value of the last one, or
+/// `nil` if there are none.
+pub fn eval_body(body: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+    eval_body_tail(body, context)?.resolve(context)
 }
 
+/// Like `eval_body`, with the last expression in tail position.
+pub fn eval_body_tail(body: &[Expression], context: &mut Context) -> Result<Tail, EvalError> {
+    let (last, init) = match body.split_last() {
+        Some(split) => split,
+        None => return Ok(Tail::Value(Value::Nil)),
+    };
+    for expr in init {
+        expr.eval(context)?;
+    }
+    last.eval_tail(context)
+}
+
+/// How deeply forms may nest. Parsing, expansion and evaluation all recurse
+/// on the Rust stack, so a limit here turns runaway input into an error
+/// rather than an overflow. Forms nested less deeply than this still fail to
+/// parse if the thread's stack runs low.
+const MAX_NESTING: usize = 2_000;
+
+/// Parses the expression starting at the front of `tokens`, which is inside
+/// `depth` enclosing forms.
+fn parse_expression(tokens: &mut VecDeque<Token>, depth: usize) -> Result<Expression, ParseError> {
+    let token = tokens.pop_front().unwrap();
+    let span = token.span;
+    if depth > MAX_NESTING {
+        return Err(ParseError::new(
+            ParseErrorKind::TooDeeplyNested(MAX_NESTING),
+            span,
+        ));
+    }
+    if stack::exhausted() {
+        return Err(ParseError::new(
+            ParseErrorKind::TooDeeplyNested(depth),
+            span,
+        ));
+    }
+    match token.kind {
+        TokenKind::LParen => {
+            let (items, end) = parse_until(TokenKind::RParen, span, tokens, depth + 1)?;
+            Ok(Expression::new(ExprKind::List(items), span.to(end)))
+        }
+        TokenKind::LBracket => {
+            let (items, end) = parse_until(TokenKind::RBracket, span, tokens, depth + 1)?;
+            Ok(Expression::new(ExprKind::Vector(items), span.to(end)))
+        }
+        TokenKind::LBrace => parse_map(span, tokens, depth + 1),
+        TokenKind::Quote => parse_quoted("quote", span, tokens, depth + 1),
+        TokenKind::Quasiquote => parse_quoted("quasiquote", span, tokens, depth + 1),
+        TokenKind::Unquote => parse_quoted("unquote", span, tokens, depth + 1),
+        TokenKind::UnquoteSplicing => parse_quoted("unquote-splicing", span, tokens, depth + 1),
+        TokenKind::Deref => parse_quoted("deref", span, tokens, depth + 1),
+        _ => parse_atom(token),
+    }
+}
+
+/// Parses a token that is not part of a nested form. Kept apart from
+/// `parse_expression` so the recursion doesn't carry its locals.
+#[inline(never)]
+fn parse_atom(token: Token) -> Result<Expression, ParseError> {
+    let span = token.span;
+    let kind = match token.kind {
+        TokenKind::Nil => ExprKind::Value(Value::Nil),
+        TokenKind::Integer(int) => ExprKind::Value(Value::Integer(int)),
+        TokenKind::Ratio(ratio) => ExprKind::Value(Value::from(ratio)),
+        TokenKind::Float(float) => ExprKind::Value(Value::Float(float)),
+        TokenKind::BigInt(big) => ExprKind::Value(Value::BigInt(big)),
+        TokenKind::Bool(value) => ExprKind::Value(Value::Bool(value)),
+        TokenKind::String(string) => ExprKind::Value(Value::String(string.into())),
+        TokenKind::Symbol(name) => ExprKind::Symbol(intern(&name)),
+        TokenKind::Keyword(name) => ExprKind::Value(Value::Keyword(intern(&name))),
+        TokenKind::RParen => {
+            return Err(ParseError::new(ParseErrorKind::UnmatchedClose(')'), span));
+        }
+        TokenKind::RBrace => {
+            return Err(ParseError::new(ParseErrorKind::UnmatchedClose('}'), span));
+        }
+        TokenKind::RBracket => {
+            return Err(ParseError::new(ParseErrorKind::UnmatchedClose(']'), span));
+        }
+        kind => unreachable!("`{}` opens a nested form", kind),
+    };
+    Ok(Expression::new(kind, span))
+}
+
+/// Parses expressions up to and including the `close` token, returning them
+/// with the span of the closing token.
+fn parse_until(
+    close: TokenKind,
+    span: Span,
+    tokens: &mut VecDeque<Token>,
+    depth: usize,
+) -> Result<(Vec<Expression>, Span), ParseError> {
+    let eof = || ParseError::new(ParseErrorKind::UnexpectedEof, span);
+
+    let mut items = Vec::<Expression>::new();
+
+    loop {
+        if tokens.front().ok_or_else(eof)?.kind == close {
+            let close = tokens.pop_front().unwrap();
+            return Ok((items, close.span));
+        }
+
+        items.push(parse_expression(tokens, depth)?);
+    }
+}
+
+/// Reads a map literal `{k v ...}` as its key-value pairs.
+fn parse_map(
+    span: Span,
+    tokens: &mut VecDeque<Token>,
+    depth: usize,
+) -> Result<Expression, ParseError> {
+    let (items, end) = parse_until(TokenKind::RBrace, span, tokens, depth)?;
+    let full_span = span.to(end);
+    if !items.len().is_multiple_of(2) {
+        return Err(ParseError::new(ParseErrorKind::OddMapLiteral, full_span));
+    }
+    let mut items = items.into_iter();
+    let mut entries = Vec::with_capacity(items.len() / 2);
+    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+        entries.push((key, value));
+    }
+    Ok(Expression::new(ExprKind::Map(entries), full_span))
+}
+
+/// Expands reader shorthand such as `'x` into `(quote x)`.
+fn parse_quoted(
+    name: &str,
+    span: Span,
+    tokens: &mut VecDeque<Token>,
+    depth: usize,
+) -> Result<Expression, ParseError> {
+    if tokens.is_empty() {
+        return Err(ParseError::new(ParseErrorKind::UnexpectedEof, span));
+    }
+    let quoted = parse_expression(tokens, depth)?;
+    let full_span = span.to(quoted.span);
+    let head = Expression::new(ExprKind::Symbol(intern(name)), span);
+    Ok(Expression::new(
+        ExprKind::List(vec![head, quoted]),
+        full_span,
+    ))
+}
+
+fn single_expression(exprs: Vec<Expression>, end: Span) -> Result<Expression, ParseError> {
+    let mut exprs = exprs.into_iter();
+    let expr = exprs
+        .next()
+        .ok_or_else(|| ParseError::new(ParseErrorKind::MissingExpression, end))?;
+    match exprs.next() {
+        Some(extra) => Err(ParseError::new(ParseErrorKind::TrailingInput, extra.span)),
+        None => Ok(expr),
+    }
+}
+
+// Shorthands for tests, which panic on malformed source. Embedders use
+// `Expression::parse_one`, which reports it.
+#[cfg(test)]
+impl From<&[Token]> for Expression {
+    fn from(tokens: &[Token]) -> Self {
+        let end = tokens.last().map_or_else(Span::default, |token| token.span);
+        Expression::parse_tokens(tokens.to_vec())
+            .and_then(|exprs| single_expression(exprs, end))
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+#[cfg(test)]
 impl From<&str> for Expression {
     fn from(string: &str) -> Self {
-        Expression::from(tokenize(string).as_slice())
+        Expression::parse_one(string).unwrap_or_else(|err| panic!("{}", err))
     }
 }
 
@@ -86,13 +518,26 @@ impl From<&str> for Expression {
 mod tests {
     use super::*;
 
+    fn value(value: Value) -> Expression {
+        Expression::new(ExprKind::Value(value), Span::default())
+    }
+
+    fn call(op: &str, args: Vec<Expression>) -> Expression {
+        let mut items = vec![Expression::new(
+            ExprKind::Symbol(op.into()),
+            Span::default(),
+        )];
+        items.extend(args);
+        Expression::new(ExprKind::List(items), Span::default())
+    }
+
     mod from_tokens {
         use super::*;
         #[test]
         fn test_int() {
             assert_eq!(
-                Expression::from(vec![Token::Integer(3)].as_slice()),
-                Expression::Value(Value::Integer(3))
+                Expression::from(vec![Token::from(TokenKind::Integer(3))].as_slice()),
+                value(Value::Integer(3))
             );
         }
 
@@ -100,12 +545,9 @@ mod tests {
         fn test_simple_add() {
             assert_eq!(
                 Expression::from("(+ 1 2)"),
-                Expression::Expression(
-                    Token::Symbol("+".to_string()),
-                    vec![
-                        Expression::Value(Value::Integer(1)),
-                        Expression::Value(Value::Integer(2))
-                    ]
+                call(
+                    "+",
+                    vec![value(Value::Integer(1)), value(Value::Integer(2))]
                 )
             );
         }
@@ -114,26 +556,51 @@ mod tests {
     mod from_string {
         use super::*;
 
+        #[test]
+        fn test_spans() {
+            let expr = Expression::from("(+ 4\n   (* 3 5))");
+            assert_eq!(expr.span, Span::new(0, 16, 1, 1));
+            if let ExprKind::List(items) = &expr.kind {
+                assert_eq!(items[0].span, Span::new(1, 2, 1, 2));
+                assert_eq!(items[1].span, Span::new(3, 4, 1, 4));
+                assert_eq!(items[2].span, Span::new(8, 15, 2, 4));
+            } else {
+                panic!("expected an application");
+            }
+        }
+
+        #[test]
+        fn test_standard_ops_resolved() {
+            let items = |expr: &Expression| match &expr.kind {
+                ExprKind::List(items) => items.clone(),
+                _ => panic!("expected an application"),
+            };
+            let expr = Expression::from("(+ (car xs) (f 1))");
+            let outer = items(&expr);
+            assert!(expr.op.is_none());
+            assert!(outer[0].op.is_some());
+            assert!(items(&outer[1])[0].op.is_some());
+            assert!(items(&outer[1])[1].op.is_none());
+            assert!(items(&outer[2])[0].op.is_none());
+        }
+
         #[test]
         fn test_nil() {
-            assert_eq!(Expression::from("nil"), Expression::Value(Value::Nil));
+            assert_eq!(Expression::from("nil"), value(Value::Nil));
         }
 
         #[test]
         fn test_int() {
-            assert_eq!(Expression::from("6"), Expression::Value(Value::Integer(6)));
+            assert_eq!(Expression::from("6"), value(Value::Integer(6)));
         }
 
         #[test]
         fn test_simple_add() {
             assert_eq!(
                 Expression::from("(+ 1 2)"),
-                Expression::Expression(
-                    Token::Symbol("+".to_string()),
-                    vec![
-                        Expression::Value(Value::Integer(1)),
-                        Expression::Value(Value::Integer(2))
-                    ]
+                call(
+                    "+",
+                    vec![value(Value::Integer(1)), value(Value::Integer(2))]
                 )
             );
         }
@@ -142,16 +609,13 @@ mod tests {
         fn test_math_expr() {
             assert_eq!(
                 Expression::from("(+ 4 (* 3 5))"),
-                Expression::Expression(
-                    Token::Symbol("+".to_string()),
+                call(
+                    "+",
                     vec![
-                        Expression::Value(Value::Integer(4)),
-                        Expression::Expression(
-                            Token::Symbol("*".to_string()),
-                            vec![
-                                Expression::Value(Value::Integer(3)),
-                                Expression::Value(Value::Integer(5))
-                            ]
+                        value(Value::Integer(4)),
+                        call(
+                            "*",
+                            vec![value(Value::Integer(3)), value(Value::Integer(5))]
                         )
                     ]
                 )
@@ -162,23 +626,17 @@ mod tests {
         fn test_math_expr2() {
             assert_eq!(
                 Expression::from("(+ 4 (* 3 5) (* 4 6))"),
-                Expression::Expression(
-                    Token::Symbol("+".to_string()),
+                call(
+                    "+",
                     vec![
-                        Expression::Value(Value::Integer(4)),
-                        Expression::Expression(
-                            Token::Symbol("*".to_string()),
-                            vec![
-                                Expression::Value(Value::Integer(3)),
-                                Expression::Value(Value::Integer(5))
-                            ]
+                        value(Value::Integer(4)),
+                        call(
+                            "*",
+                            vec![value(Value::Integer(3)), value(Value::Integer(5))]
                         ),
-                        Expression::Expression(
-                            Token::Symbol("*".to_string()),
-                            vec![
-                                Expression::Value(Value::Integer(4)),
-                                Expression::Value(Value::Integer(6))
-                            ]
+                        call(
+                            "*",
+                            vec![value(Value::Integer(4)), value(Value::Integer(6))]
                         )
                     ]
                 )
@@ -189,27 +647,21 @@ mod tests {
         fn test_math_expr3() {
             assert_eq!(
                 Expression::from("(+ 4 (* 3 5) (* 4 (* 3 5)))"),
-                Expression::Expression(
-                    Token::Symbol("+".to_string()),
+                call(
+                    "+",
                     vec![
-                        Expression::Value(Value::Integer(4)),
-                        Expression::Expression(
-                            Token::Symbol("*".to_string()),
-                            vec![
-                                Expression::Value(Value::Integer(3)),
-                                Expression::Value(Value::Integer(5))
-                            ]
+                        value(Value::Integer(4)),
+                        call(
+                            "*",
+                            vec![value(Value::Integer(3)), value(Value::Integer(5))]
                         ),
-                        Expression::Expression(
-                            Token::Symbol("*".to_string()),
+                        call(
+                            "*",
                             vec![
-                                Expression::Value(Value::Integer(4)),
-                                Expression::Expression(
-                                    Token::Symbol("*".to_string()),
-                                    vec![
-                                        Expression::Value(Value::Integer(3)),
-                                        Expression::Value(Value::Integer(5))
-                                    ]
+                                value(Value::Integer(4)),
+                                call(
+                                    "*",
+                                    vec![value(Value::Integer(3)), value(Value::Integer(5))]
                                 )
                             ]
                         )
@@ -219,313 +671,1644 @@ mod tests {
         }
     }
 
-    mod eval {
+    mod parse {
         use super::*;
-        #[test]
-        fn test_int() {
-            assert_eq!(
-                Expression::from("5").eval(&mut Context::new()),
-                Value::Integer(5)
-            );
+
+        fn parse_err(string: &str) -> ParseError {
+            Expression::parse(string).unwrap_err()
         }
 
         #[test]
-        fn test_nil() {
-            assert_eq!(
-                Expression::from("nil").eval(&mut Context::new()),
-                Value::Nil
-            );
+        fn test_empty() {
+            assert_eq!(Expression::parse(""), Ok(vec![]));
+            assert_eq!(Expression::parse("  \n"), Ok(vec![]));
         }
 
         #[test]
-        fn test_bool() {
+        fn test_multiple() {
             assert_eq!(
-                Expression::from("true").eval(&mut Context::new()),
-                Value::Bool(true)
-            );
-
-            assert_eq!(
-                Expression::from("false").eval(&mut Context::new()),
-                Value::Bool(false)
+                Expression::parse("1 (+ 1 2) nil"),
+                Ok(vec![
+                    value(Value::Integer(1)),
+                    call(
+                        "+",
+                        vec![value(Value::Integer(1)), value(Value::Integer(2))]
+                    ),
+                    value(Value::Nil)
+                ])
             );
         }
 
         #[test]
-        fn test_string() {
-            assert_eq!(
-                Expression::from("\"Hello, world!\"").eval(&mut Context::new()),
-                Value::String("Hello, world!".to_string())
-            );
+        fn test_nesting_limit() {
+            // Debug builds use several times the stack per level that
+            // release builds do.
+            std::thread::Builder::new()
+                .stack_size(64 << 20)
+                .spawn(|| {
+                    let deep = "(".repeat(MAX_NESTING + 1) + &")".repeat(MAX_NESTING + 1);
+                    assert!(Expression::parse(&deep).is_ok());
+                    let limit = MAX_NESTING + 1;
+                    let error = ParseError::new(
+                        ParseErrorKind::TooDeeplyNested(MAX_NESTING),
+                        Span::new(limit, limit + 1, 1, limit + 1),
+                    );
+                    assert_eq!(parse_err(&"[".repeat(100_000)), error);
+                    assert_eq!(parse_err(&"(list ".repeat(100_000)).kind, error.kind);
+                    assert_eq!(parse_err(&("'".repeat(100_000) + "x")), error);
+                })
+                .unwrap()
+                .join()
+                .unwrap();
         }
 
         #[test]
-        fn test_add() {
+        fn test_unexpected_eof() {
             assert_eq!(
-                Expression::from("(+ 4 5)").eval(&mut Context::new()),
-                Value::Integer(9)
+                parse_err("(block\n  (+ 1 2)"),
+                ParseError::new(ParseErrorKind::UnexpectedEof, Span::new(0, 1, 1, 1))
             );
-        }
-
-        #[test]
-        fn test_mul() {
             assert_eq!(
-                Expression::from("(* 4 5)").eval(&mut Context::new()),
-                Value::Integer(20)
+                parse_err("(print (+ 1"),
+                ParseError::new(ParseErrorKind::UnexpectedEof, Span::new(7, 8, 1, 8))
             );
-        }
-
-        #[test]
-        fn test_sub() {
             assert_eq!(
-                Expression::from("(- 4 5)").eval(&mut Context::new()),
-                Value::Integer(-1)
+                parse_err("("),
+                ParseError::new(ParseErrorKind::UnexpectedEof, Span::new(0, 1, 1, 1))
             );
         }
 
         #[test]
-        fn test_div() {
+        fn test_unmatched_close() {
             assert_eq!(
-                Expression::from("(/ 63 10)").eval(&mut Context::new()),
-                Value::Integer(6)
+                parse_err(")"),
+                ParseError::new(ParseErrorKind::UnmatchedClose(')'), Span::new(0, 1, 1, 1))
             );
-        }
-
-        #[test]
-        fn test_mod() {
             assert_eq!(
-                Expression::from("(% 63 100)").eval(&mut Context::new()),
-                Value::Integer(63)
+                parse_err("(+ 1 2))"),
+                ParseError::new(ParseErrorKind::UnmatchedClose(')'), Span::new(7, 8, 1, 8))
             );
-
             assert_eq!(
-                Expression::from("(% 101 2)").eval(&mut Context::new()),
-                Value::Integer(1)
+                parse_err("{:a 1)"),
+                ParseError::new(ParseErrorKind::UnmatchedClose(')'), Span::new(5, 6, 1, 6))
             );
         }
 
         #[test]
-        fn test_eq() {
-            assert_eq!(
-                Expression::from("(= 63 10)").eval(&mut Context::new()),
-                Value::Bool(false)
-            );
+        fn test_vector_literal() {
+            let expr = Expression::parse_one("[1 (+ 1 1)]").unwrap();
+            assert_eq!(expr.span, Span::new(0, 11, 1, 1));
             assert_eq!(
-                Expression::from("(= 63 63)").eval(&mut Context::new()),
-                Value::Bool(true)
+                expr.kind,
+                ExprKind::Vector(vec![
+                    value(Value::Integer(1)),
+                    call(
+                        "+",
+                        vec![value(Value::Integer(1)), value(Value::Integer(1))]
+                    )
+                ])
             );
+            assert_eq!(expr.to_value().to_string(), "[1 (+ 1 1)]");
+        }
 
+        #[test]
+        fn test_map_literal() {
             assert_eq!(
-                Expression::from("(= true true)").eval(&mut Context::new()),
-                Value::Bool(true)
+                Expression::parse_one("{:a 1 :b (+ 1 1)}")
+                    .unwrap()
+                    .to_value()
+                    .to_string(),
+                "{:a 1 :b (+ 1 1)}"
             );
             assert_eq!(
-                Expression::from("(= true false)").eval(&mut Context::new()),
-                Value::Bool(false)
+                parse_err("{:a 1 :b}"),
+                ParseError::new(ParseErrorKind::OddMapLiteral, Span::new(0, 9, 1, 1))
             );
-
             assert_eq!(
-                Expression::from("(= nil nil)").eval(&mut Context::new()),
-                Value::Bool(true)
+                parse_err("}"),
+                ParseError::new(ParseErrorKind::UnmatchedClose('}'), Span::new(0, 1, 1, 1))
             );
         }
 
         #[test]
-        fn test_lt() {
+        fn test_empty_list() {
             assert_eq!(
-                Expression::from("(< 63 63)").eval(&mut Context::new()),
-                Value::Bool(false)
-            );
-            assert_eq!(
-                Expression::from("(< 5 63)").eval(&mut Context::new()),
-                Value::Bool(true)
-            );
-            assert_eq!(
-                Expression::from("(< 7 3)").eval(&mut Context::new()),
-                Value::Bool(false)
+                Expression::parse_one("(block ( ))"),
+                Ok(call(
+                    "block",
+                    vec![Expression::new(ExprKind::List(vec![]), Span::default())]
+                ))
             );
         }
 
         #[test]
-        fn test_gt() {
-            assert_eq!(
-                Expression::from("(> 63 63)").eval(&mut Context::new()),
-                Value::Bool(false)
-            );
-            assert_eq!(
-                Expression::from("(> 5 63)").eval(&mut Context::new()),
-                Value::Bool(false)
-            );
+        fn test_unterminated_string() {
             assert_eq!(
-                Expression::from("(> 7 3)").eval(&mut Context::new()),
-                Value::Bool(true)
+                parse_err("(print \"abc)"),
+                ParseError::new(ParseErrorKind::UnterminatedString, Span::new(7, 12, 1, 8))
             );
         }
 
         #[test]
-        fn test_and() {
+        fn test_nested_head() {
             assert_eq!(
-                Expression::from("(and true true)").eval(&mut Context::new()),
-                Value::Bool(true)
+                Expression::parse_one("((f) 1)"),
+                Ok(Expression::new(
+                    ExprKind::List(vec![call("f", vec![]), value(Value::Integer(1))]),
+                    Span::default()
+                ))
             );
+        }
+
+        #[test]
+        fn test_parse_one() {
             assert_eq!(
-                Expression::from("(and true false)").eval(&mut Context::new()),
-                Value::Bool(false)
+                Expression::parse_one(" (+ 1 2) "),
+                Ok(call(
+                    "+",
+                    vec![value(Value::Integer(1)), value(Value::Integer(2))]
+                ))
             );
             assert_eq!(
-                Expression::from("(and false true)").eval(&mut Context::new()),
-                Value::Bool(false)
+                Expression::parse_one("(+ 1 2) 3"),
+                Err(ParseError::new(
+                    ParseErrorKind::TrailingInput,
+                    Span::new(8, 9, 1, 9)
+                ))
             );
             assert_eq!(
-                Expression::from("(and false false)").eval(&mut Context::new()),
-                Value::Bool(false)
+                Expression::parse_one("\n "),
+                Err(ParseError::new(
+                    ParseErrorKind::MissingExpression,
+                    Span::new(2, 2, 2, 2)
+                ))
             );
         }
 
         #[test]
-        fn test_or() {
+        fn test_quote_shorthand() {
+            let symbol =
+                |name: &str| Expression::new(ExprKind::Symbol(name.into()), Span::default());
             assert_eq!(
-                Expression::from("(or true true)").eval(&mut Context::new()),
-                Value::Bool(true)
-            );
-            assert_eq!(
-                Expression::from("(or true false)").eval(&mut Context::new()),
-                Value::Bool(true)
+                Expression::parse("'a `(b ,c ,@d)"),
+                Ok(vec![
+                    call("quote", vec![symbol("a")]),
+                    call(
+                        "quasiquote",
+                        vec![call(
+                            "b",
+                            vec![
+                                call("unquote", vec![symbol("c")]),
+                                call("unquote-splicing", vec![symbol("d")])
+                            ]
+                        )]
+                    )
+                ])
             );
             assert_eq!(
-                Expression::from("(or false true)").eval(&mut Context::new()),
-                Value::Bool(true)
+                Expression::parse("@a"),
+                Ok(vec![call("deref", vec![symbol("a")])])
             );
             assert_eq!(
-                Expression::from("(or false false)").eval(&mut Context::new()),
-                Value::Bool(false)
+                Expression::parse_one("''x").unwrap().span,
+                Span::new(0, 3, 1, 1)
             );
-        }
-
-        #[test]
-        fn test_not() {
             assert_eq!(
-                Expression::from("(not true)").eval(&mut Context::new()),
-                Value::Bool(false)
+                parse_err("(a ')"),
+                ParseError::new(ParseErrorKind::UnmatchedClose(')'), Span::new(4, 5, 1, 5))
             );
             assert_eq!(
-                Expression::from("(not false)").eval(&mut Context::new()),
-                Value::Bool(true)
+                parse_err("'"),
+                ParseError::new(ParseErrorKind::UnexpectedEof, Span::new(0, 1, 1, 1))
             );
         }
 
         #[test]
-        fn test_math() {
-            assert_eq!(
-                Expression::from("(+ 4 (* 3 5) (* 4 6))").eval(&mut Context::new()),
-                Value::Integer(43)
-            );
+        fn test_value_round_trip() {
+            let expr = Expression::parse_one("(if (< x 1) \"a\" (f 'b nil))").unwrap();
+            let value = expr.to_value();
+            assert_eq!(value.to_string(), "(if (< x 1) \"a\" (f (quote b) nil))");
+            assert_eq!(Expression::from_value(&value, Span::default()), expr);
         }
 
         #[test]
-        fn test_print_int() {
-            assert_eq!(
-                Expression::from("(print 5)").eval(&mut Context::new()),
-                Value::Nil
-            );
+        fn test_incomplete() {
+            assert!(parse_err("(block").is_incomplete());
+            assert!(parse_err("\"abc").is_incomplete());
+            assert!(!parse_err(")").is_incomplete());
         }
 
         #[test]
-        fn test_print_nil() {
+        fn test_report() {
+            let source = "(block\n  (print 1)))";
             assert_eq!(
-                Expression::from("(print nil)").eval(&mut Context::new()),
-                Value::Nil
+                parse_err(source).report(source),
+                "error: unmatched `)` at 2:13\n  |\n2 |   (print 1)))\n  |             ^"
             );
         }
+    }
 
-        #[test]
-        fn test_if() {
-            assert_eq!(
-                Expression::from("(if true 4 nil)").eval(&mut Context::new()),
-                Value::Integer(4)
-            );
+    /// The evaluation tests, instantiated once per engine so that the VM is
+    /// held to exactly what the tree walker does.
+    macro_rules! eval_tests {
+        ($engine:path) => {
+            use super::*;
+            use crate::context::Engine;
+            use crate::error::Arity;
 
-            assert_eq!(
-                Expression::from("(if false 4 nil)").eval(&mut Context::new()),
-                Value::Nil
-            );
-        }
+            trait Evaluate {
+                fn evaluate(&self, context: &mut Context) -> Result<Value, EvalError>;
+            }
 
-        #[test]
-        fn test_if_else() {
-            assert_eq!(
-                Expression::from("(if true 4 nil)").eval(&mut Context::new()),
-                Value::Integer(4)
-            );
+            impl Evaluate for Expression {
+                /// Evaluates with the engine under test.
+                fn evaluate(&self, context: &mut Context) -> Result<Value, EvalError> {
+                    context.engine = $engine;
+                    match context.engine {
+                        Engine::Tree => self.eval(context),
+                        Engine::Vm => crate::vm::eval(self, context),
+                    }
+                }
+            }
 
-            assert_eq!(
-                Expression::from("(if false 4 nil)").eval(&mut Context::new()),
-                Value::Nil
-            );
+            #[test]
+            fn test_int() {
+                assert_eq!(
+                    Expression::from("5").evaluate(&mut Context::new()),
+                    Ok(Value::Integer(5))
+                );
+            }
 
-            assert_eq!(
-                Expression::from("(if true nil 4)").eval(&mut Context::new()),
-                Value::Nil
-            );
+            #[test]
+            fn test_nil() {
+                assert_eq!(
+                    Expression::from("nil").evaluate(&mut Context::new()),
+                    Ok(Value::Nil)
+                );
+            }
 
-            assert_eq!(
-                Expression::from("(if false 4 4)").eval(&mut Context::new()),
-                Value::Integer(4)
-            );
-        }
+            #[test]
+            fn test_bool() {
+                assert_eq!(
+                    Expression::from("true").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(true))
+                );
 
-        #[test]
-        fn test_while() {
-            assert_eq!(
-                Expression::from(
-                    "(block (global i 0) (while (< i 10) (block (print i) (global i (+ i 1)))) i)"
-                )
-                .eval(&mut Context::new()),
-                Value::Integer(10)
-            );
-        }
+                assert_eq!(
+                    Expression::from("false").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(false))
+                );
+            }
 
-        #[test]
-        fn test_block() {
-            assert_eq!(
-                Expression::from("(block)").eval(&mut Context::new()),
-                Value::Nil
-            );
+            #[test]
+            fn test_string() {
+                assert_eq!(
+                    Expression::from("\"Hello, world!\"").evaluate(&mut Context::new()),
+                    Ok(Value::String("Hello, world!".into()))
+                );
+            }
 
-            assert_eq!(
-                Expression::from("(block 5)").eval(&mut Context::new()),
-                Value::Integer(5)
-            );
+            #[test]
+            fn test_add() {
+                assert_eq!(
+                    Expression::from("(+ 4 5)").evaluate(&mut Context::new()),
+                    Ok(Value::Integer(9))
+                );
+            }
 
-            assert_eq!(
-                Expression::from("(block 5 7)").eval(&mut Context::new()),
-                Value::Integer(7)
-            );
+            #[test]
+            fn test_mul() {
+                assert_eq!(
+                    Expression::from("(* 4 5)").evaluate(&mut Context::new()),
+                    Ok(Value::Integer(20))
+                );
+            }
 
-            assert_eq!(
-                Expression::from("(block (+ 5 2) 5 1 true)").eval(&mut Context::new()),
-                Value::Bool(true)
-            );
-        }
+            #[test]
+            fn test_sub() {
+                assert_eq!(
+                    Expression::from("(- 4 5)").evaluate(&mut Context::new()),
+                    Ok(Value::Integer(-1))
+                );
+            }
 
-        #[test]
-        fn test_global() {
-            let mut context = Context::new();
-            assert_eq!(
-                Expression::from("(global abc 4)").eval(&mut context),
-                Value::Nil
-            );
-            assert_eq!(context.globals.get("abc"), Option::Some(&Value::Integer(4)));
-        }
+            #[test]
+            fn test_div() {
+                assert_eq!(
+                    Expression::from("(/ 63 9)").evaluate(&mut Context::new()),
+                    Ok(Value::Integer(7))
+                );
+                assert_eq!(
+                    Expression::from("(/ 63 10)")
+                        .evaluate(&mut Context::new())
+                        .map(|value| value.to_string()),
+                    Ok("63/10".to_string())
+                );
+            }
 
-        #[test]
-        fn test_global_multi() {
-            let mut context = Context::new();
-            assert_eq!(
-                Expression::from("(block (global abc 4) (global a 1) (global abc 7) a)")
-                    .eval(&mut context),
-                Value::Integer(1)
-            );
-            assert_eq!(context.globals.get("abc"), Option::Some(&Value::Integer(7)));
-        }
+            #[test]
+            fn test_float_arithmetic() {
+                assert_eq!(run("(/ 63.0 10)"), Ok(Value::Float(6.3)));
+                assert_eq!(run("(+ 1 2.5 1e1)"), Ok(Value::Float(13.5)));
+                assert_eq!(run("(- 1 0.25)"), Ok(Value::Float(0.75)));
+                assert_eq!(run("(* 2 1.5)"), Ok(Value::Float(3.0)));
+                assert_eq!(run("(/ -1.0 0)"), Ok(Value::Float(f64::NEG_INFINITY)));
+                assert_eq!(run("(< 1 1.5)"), Ok(Value::Bool(true)));
+                assert_eq!(run("(= 2 2.0)"), Ok(Value::Bool(true)));
+                assert_eq!(
+                    run("(+ 0.5 (/ (pow 10 400) (+ (pow 10 400) 1)))"),
+                    Ok(Value::Float(1.5))
+                );
+                assert_eq!(run("(= nan nan)"), Ok(Value::Bool(true)));
+                assert_eq!(run("(= {nan 1} {nan 1})"), Ok(Value::Bool(true)));
+                assert_eq!(run("(> nan 1)"), Ok(Value::Bool(false)));
+            }
+
+            #[test]
+            fn test_float_display() {
+                let show = |source: &str| run(source).unwrap().to_string();
+                assert_eq!(show("(* 2 1.5)"), "3.0");
+                assert_eq!(show("1e-9"), "1e-9");
+                assert_eq!(show("(- 0 inf)"), "-inf");
+                assert_eq!(show("nan"), "nan");
+            }
+
+            #[test]
+            fn test_vectors() {
+                let show = |source: &str| run(source).map(|value| value.to_string());
+                assert_eq!(show("[1 (+ 1 1) [3]]"), Ok("[1 2 [3]]".to_string()));
+                assert_eq!(show("[]"), Ok("[]".to_string()));
+                assert_eq!(show("(conj [1] 2 3)"), Ok("[1 2 3]".to_string()));
+                assert_eq!(
+                    show("(assoc [1 2 3] 0 :a 3 :d)"),
+                    Ok("[:a 2 3 :d]".to_string())
+                );
+                assert_eq!(show("(nth [1 2 3] 1)"), Ok("2".to_string()));
+                assert_eq!(show("(subvec [0 1 2 3 4] 1 3)"), Ok("[1 2]".to_string()));
+                assert_eq!(show("(subvec [0 1 2 3 4] 3)"), Ok("[3 4]".to_string()));
+                assert_eq!(
+                    show("(conj (subvec [0 1 2 3] 0 2) :x)"),
+                    Ok("[0 1 :x]".to_string())
+                );
+                assert_eq!(show("(len [1 2])"), Ok("2".to_string()));
+                assert_eq!(show("(empty? [])"), Ok("true".to_string()));
+                assert_eq!(show("(= [1 [2]] (conj [1] [2]))"), Ok("true".to_string()));
+                assert_eq!(show("(= [1 2] (list 1 2))"), Ok("false".to_string()));
+            }
+
+            #[test]
+            fn test_vector_literals_are_data() {
+                let show = |source: &str| run(source).map(|value| value.to_string());
+                assert_eq!(show("'[1 x [y]]"), Ok("[1 x [y]]".to_string()));
+                assert_eq!(show("(first '[1 2])"), Ok("1".to_string()));
+                assert_eq!(show("(rest '[1 2])"), Ok("(2)".to_string()));
+                assert_eq!(show("(len '[1 2])"), Ok("2".to_string()));
+                assert_eq!(
+                    show("(let ((x 2)) `[1 ,x ,@(list 3 4) [,x]])"),
+                    Ok("[1 2 3 4 [2]]".to_string())
+                );
+                assert_eq!(show("(let ((vector 1)) [vector 2])"), Ok("[1 2]".to_string()));
+                assert_eq!(show("(eval '[(+ 1 2)])"), Ok("[3]".to_string()));
+            }
+
+            #[test]
+            fn test_vectors_are_persistent() {
+                assert_eq!(
+                    run("(let ((v [1 2 3])) (assoc v 0 9) (conj v 4) v)").map(|v| v.to_string()),
+                    Ok("[1 2 3]".to_string())
+                );
+                let source = "(let ((v []) (i 0)) \
+                    (while (< i 100) (block (set! v (conj v i)) (set! i (+ i 1)))) \
+                    (list (len v) (nth v 99)))";
+                assert_eq!(
+                    run(source).map(|v| v.to_string()),
+                    Ok("(100 99)".to_string())
+                );
+            }
+
+            #[test]
+            fn test_vector_errors() {
+                let kind = |source: &str| run(source).unwrap_err().kind;
+                assert_eq!(
+                    kind("(nth [1 2] 2)"),
+                    EvalErrorKind::IndexOutOfBounds { index: 2, len: 2 }
+                );
+                assert_eq!(
+                    kind("(assoc [1 2] 3 0)"),
+                    EvalErrorKind::IndexOutOfBounds { index: 3, len: 2 }
+                );
+                assert_eq!(
+                    kind("(subvec [1 2] 1 3)"),
+                    EvalErrorKind::IndexOutOfBounds { index: 3, len: 2 }
+                );
+                assert_eq!(
+                    run("(subvec [1 2 3] 2 1)").unwrap_err().kind.to_string(),
+                    "index 2 out of bounds for length 3"
+                );
+                assert_eq!(
+                    kind("(conj (list) 1)"),
+                    EvalErrorKind::TypeMismatch {
+                        expected: "vector",
+                        actual: "list"
+                    }
+                );
+            }
+
+            #[test]
+            fn test_atoms() {
+                let show = |source: &str| run(source).map(|value| value.to_string());
+                assert_eq!(show("(let ((a (atom 1))) @a)"), Ok("1".to_string()));
+                assert_eq!(
+                    show("(let ((a (atom 1))) (list (reset! a 2) (deref a)))"),
+                    Ok("(2 2)".to_string())
+                );
+                assert_eq!(
+                    show("(let ((a (atom 1))) (list (swap! a + 10) @a))"),
+                    Ok("(11 11)".to_string())
+                );
+                assert_eq!(
+                    show("(let ((a (atom [1]))) (swap! a (lambda (v x y) (conj v x y)) 2 3))"),
+                    Ok("[1 2 3]".to_string())
+                );
+                assert_eq!(show("(atom {:a 1})"), Ok("<atom {:a 1}>".to_string()));
+                assert_eq!(
+                    show("(let ((a (atom nil))) (reset! a [a]) a)"),
+                    Ok("<atom [<atom ...>]>".to_string())
+                );
+                assert_eq!(
+                    show("(let ((a (atom 0))) (list (= a a) (= a (atom 0))))"),
+                    Ok("(true false)".to_string())
+                );
+            }
+
+            #[test]
+            fn test_atoms_are_shared_by_closures() {
+                let source = "(defn make-counter () \
+                    (let ((count (atom 0))) \
+                      (list (lambda () (swap! count + 1)) (lambda () @count)))) \
+                    (let ((counter (make-counter))) \
+                      ((first counter)) ((first counter)) \
+                      ((nth counter 1)))";
+                assert_eq!(run(source), Ok(Value::Integer(2)));
+            }
+
+            #[test]
+            fn test_atom_errors() {
+                let kind = |source: &str| run(source).unwrap_err().kind;
+                assert_eq!(
+                    kind("(deref 1)"),
+                    EvalErrorKind::TypeMismatch {
+                        expected: "atom",
+                        actual: "integer"
+                    }
+                );
+                assert_eq!(
+                    kind("(swap! (atom 1) + true)"),
+                    EvalErrorKind::TypeMismatch {
+                        expected: "number",
+                        actual: "bool"
+                    }
+                );
+                assert_eq!(
+                    kind("(reset! (atom 1))"),
+                    EvalErrorKind::ArityMismatch {
+                        expected: Arity::Exact(2),
+                        actual: 1
+                    }
+                );
+            }
+
+            #[test]
+            fn test_maps() {
+                let show = |source: &str| run(source).map(|value| value.to_string());
+                assert_eq!(show("{:b (+ 1 1) :a 1}"), Ok("{:a 1 :b 2}".to_string()));
+                assert_eq!(show("{}"), Ok("{}".to_string()));
+                assert_eq!(show("(get {:a 1} :a)"), Ok("1".to_string()));
+                assert_eq!(show("(get {:a 1} :b)"), Ok("nil".to_string()));
+                assert_eq!(show("(get {:a 1} :b 0)"), Ok("0".to_string()));
+                assert_eq!(
+                    show("(assoc {:a 1} :b 2 :a 3)"),
+                    Ok("{:a 3 :b 2}".to_string())
+                );
+                assert_eq!(
+                    show("(dissoc {:a 1 :b 2 :c 3} :a :c)"),
+                    Ok("{:b 2}".to_string())
+                );
+                assert_eq!(show("(keys {:b 2 :a 1})"), Ok("(:a :b)".to_string()));
+                assert_eq!(show("(vals {:b 2 :a 1})"), Ok("(1 2)".to_string()));
+                assert_eq!(show("(has-key? {:a nil} :a)"), Ok("true".to_string()));
+                assert_eq!(show("(has-key? {:a nil} :b)"), Ok("false".to_string()));
+                assert_eq!(
+                    show("(merge {:a 1 :b 1} {:b 2} {:c 3})"),
+                    Ok("{:a 1 :b 2 :c 3}".to_string())
+                );
+                assert_eq!(
+                    show("{\"x\" 1 2 '(a) 1/2 :k}"),
+                    Ok("{1/2 :k 2 (a) \"x\" 1}".to_string())
+                );
+            }
+
+            #[test]
+            fn test_map_equality() {
+                assert_eq!(run("(= {:a 1 :b 2} {:b 2 :a 1})"), Ok(Value::Bool(true)));
+                assert_eq!(run("(= {:a 1} {:a 2})"), Ok(Value::Bool(false)));
+                assert_eq!(
+                    run("(= (assoc {} :a {:b 1}) {:a {:b 1}})"),
+                    Ok(Value::Bool(true))
+                );
+                assert_eq!(run("(= :a :a)"), Ok(Value::Bool(true)));
+                assert_eq!(run("(= :a 'a)"), Ok(Value::Bool(false)));
+            }
+
+            #[test]
+            fn test_map_literals_are_data() {
+                let show = |source: &str| run(source).map(|value| value.to_string());
+                assert_eq!(show("'{:a (+ 1 1)}"), Ok("{:a (+ 1 1)}".to_string()));
+                assert_eq!(show("(get '{:a x} :a)"), Ok("x".to_string()));
+                assert_eq!(
+                    show("(let ((x 2)) `{:a ,x :b {:c ,(+ x 1)}})"),
+                    Ok("{:a 2 :b {:c 3}}".to_string())
+                );
+                assert_eq!(
+                    show("(let ((hash-map 1)) {:a hash-map})"),
+                    Ok("{:a 1}".to_string())
+                );
+                assert_eq!(show("(eval '{:a (+ 1 2)})"), Ok("{:a 3}".to_string()));
+            }
+
+            #[test]
+            fn test_numeric_equality_nests() {
+                assert_eq!(run("(= 1 1.0)"), Ok(Value::Bool(true)));
+                assert_eq!(run("(= (list 1) (list 1.0))"), Ok(Value::Bool(true)));
+                assert_eq!(run("(= [1/2] [0.5])"), Ok(Value::Bool(true)));
+                assert_eq!(run("(= {:a 2} {:a 2.0})"), Ok(Value::Bool(true)));
+                assert_eq!(run("(get {1 :a} 1.0)"), Ok(Value::Keyword("a".into())));
+                assert_eq!(run("(len (assoc {1 :a} 1.0 :b))"), Ok(Value::Integer(1)));
+                assert_eq!(run("(= (list 1) (list 2.0))"), Ok(Value::Bool(false)));
+            }
+
+            #[test]
+            fn test_exact_float_comparison() {
+                let t = Ok(Value::Bool(true));
+                let f = Ok(Value::Bool(false));
+                assert_eq!(run("(= 9007199254740992 9007199254740992.0)"), t);
+                assert_eq!(run("(= 9007199254740993 9007199254740992.0)"), f);
+                assert_eq!(run("(< 9007199254740992.0 9007199254740993)"), t);
+                assert_eq!(run("(= (pow 2 64) 18446744073709551616.0)"), t);
+                assert_eq!(run("(< (pow 10 400) inf)"), t);
+                assert_eq!(run("(= 1/10 0.1)"), f);
+                assert_eq!(run("(= 1/4 0.25)"), t);
+                for source in [
+                    "{9007199254740992 :a 9007199254740993 :b 9007199254740992.0 :c}",
+                    "{9007199254740992.0 :c 9007199254740993 :b 9007199254740992 :a}",
+                    "{9007199254740993 :b 9007199254740992.0 :c 9007199254740992 :a}",
+                ] {
+                    assert_eq!(run(&format!("(len {})", source)), Ok(Value::Integer(2)));
+                    assert_eq!(
+                        run(&format!("(get {} 9007199254740993)", source)),
+                        Ok(Value::Keyword("b".into()))
+                    );
+                }
+            }
+
+            #[test]
+            fn test_map_errors() {
+                let kind = |source: &str| run(source).unwrap_err().kind;
+                assert_eq!(
+                    kind("(get (list 1) 0)"),
+                    EvalErrorKind::TypeMismatch {
+                        expected: "map",
+                        actual: "list"
+                    }
+                );
+                assert_eq!(
+                    kind("(assoc {} :a 1 :b)"),
+                    EvalErrorKind::InvalidSyntax {
+                        expected: "a value for every key"
+                    }
+                );
+            }
+
+            #[test]
+            fn test_string_display() {
+                assert_eq!(
+                    run(r#""say \"hi\"\n\tthen\\go""#).map(|value| value.to_string()),
+                    Ok(r#""say \"hi\"\n\tthen\\go""#.to_string())
+                );
+                assert_eq!(run(r#""\u{48}\u{e9}""#), Ok(Value::String("Hé".into())));
+            }
+
+            #[test]
+            fn test_string_builtins() {
+                let string = |s: &str| Ok(Value::String(s.into()));
+                assert_eq!(run(r#"(str-len "héllo")"#), Ok(Value::Integer(5)));
+                assert_eq!(run(r#"(concat "a" "b" "c")"#), string("abc"));
+                assert_eq!(run("(concat)"), string(""));
+                assert_eq!(run(r#"(substr "héllo" 1 3)"#), string("él"));
+                assert_eq!(run(r#"(substr "héllo" 2)"#), string("llo"));
+                assert_eq!(
+                    run(r#"(split "a,b,,c" ",")"#).map(|value| value.to_string()),
+                    Ok(r#"("a" "b" "" "c")"#.to_string())
+                );
+                assert_eq!(
+                    run(r#"(split "ab" "")"#).map(|value| value.to_string()),
+                    Ok(r#"("a" "b")"#.to_string())
+                );
+                assert_eq!(run(r#"(join (list "a" 1 'b) ", ")"#), string("a, 1, b"));
+                assert_eq!(run(r#"(join (split "a b" " "))"#), string("ab"));
+                assert_eq!(run(r#"(upper "abc")"#), string("ABC"));
+                assert_eq!(run(r#"(lower "ABC")"#), string("abc"));
+                assert_eq!(run("(trim \"  a b \n\")"), string("a b"));
+                assert_eq!(run(r#"(contains? "hello" "ell")"#), Ok(Value::Bool(true)));
+                assert_eq!(run(r#"(contains? "hello" "xyz")"#), Ok(Value::Bool(false)));
+                assert_eq!(run(r#"(replace "a-b-c" "-" "+")"#), string("a+b+c"));
+                assert_eq!(
+                    run(r#"(format "{} + {} = {} {{ok}}" 1 1/2 "three")"#),
+                    string("1 + 1/2 = three {ok}")
+                );
+            }
+
+            #[test]
+            fn test_string_builtin_errors() {
+                let kind = |source: &str| run(source).unwrap_err().kind;
+                assert_eq!(
+                    kind(r#"(substr "abc" 4)"#),
+                    EvalErrorKind::IndexOutOfBounds { index: 4, len: 3 }
+                );
+                assert_eq!(
+                    kind(r#"(substr "abc" 2 1)"#),
+                    EvalErrorKind::IndexOutOfBounds { index: 2, len: 3 }
+                );
+                assert_eq!(
+                    kind(r#"(upper 1)"#),
+                    EvalErrorKind::TypeMismatch {
+                        expected: "string",
+                        actual: "integer"
+                    }
+                );
+                assert_eq!(
+                    kind(r#"(format "{} {}" 1)"#),
+                    EvalErrorKind::ArityMismatch {
+                        expected: Arity::Exact(3),
+                        actual: 2
+                    }
+                );
+            }
+
+            #[test]
+            fn test_ratios() {
+                let show = |source: &str| run(source).map(|value| value.to_string());
+                assert_eq!(show("(+ 1/3 1/6)"), Ok("1/2".to_string()));
+                assert_eq!(show("(* 2/4 4)"), Ok("2".to_string()));
+                assert_eq!(show("(- 1 1/3)"), Ok("2/3".to_string()));
+                assert_eq!(show("(+ 1/2 0.25)"), Ok("0.75".to_string()));
+                assert_eq!(show("(< 1/3 0.34)"), Ok("true".to_string()));
+                assert_eq!(show("(= 2/4 1/2)"), Ok("true".to_string()));
+                assert_eq!(show("(= 1/2 0.5)"), Ok("true".to_string()));
+                assert_eq!(
+                    show("(list (numerator 6/4) (denominator 6/4))"),
+                    Ok("(3 2)".to_string())
+                );
+                assert_eq!(show("(denominator 5)"), Ok("1".to_string()));
+                assert_eq!(
+                    show("(list (floor -7/2) (ceil -7/2) (round 7/2))"),
+                    Ok("(-4 -3 4)".to_string())
+                );
+                assert_eq!(show("(pow 2/3 2)"), Ok("4/9".to_string()));
+                assert_eq!(
+                    run("(numerator 0.5)").map_err(|err| err.kind),
+                    Err(EvalErrorKind::TypeMismatch {
+                        expected: "rational",
+                        actual: "float"
+                    })
+                );
+            }
+
+            #[test]
+            fn test_numeric_builtins() {
+                assert_eq!(run("(floor -2.5)"), Ok(Value::Float(-3.0)));
+                assert_eq!(run("(ceil 2.1)"), Ok(Value::Float(3.0)));
+                assert_eq!(run("(round 2.5)"), Ok(Value::Float(3.0)));
+                assert_eq!(run("(floor 7)"), Ok(Value::Integer(7)));
+                assert_eq!(run("(sqrt 16)"), Ok(Value::Float(4.0)));
+                assert_eq!(run("(pow 3 4)"), Ok(Value::Integer(81)));
+                assert_eq!(run("(pow 4 0.5)"), Ok(Value::Float(2.0)));
+                assert_eq!(run("(abs -7)"), Ok(Value::Integer(7)));
+                assert_eq!(run("(abs -0.5)"), Ok(Value::Float(0.5)));
+                assert_eq!(run("(min 3 1.5 2)"), Ok(Value::Float(1.5)));
+                assert_eq!(run("(max 3 1.5 2)"), Ok(Value::Integer(3)));
+                for source in ["(min nan 1 2)", "(min 1 nan 2)", "(max 1 2 nan)", "(max nan 2)"] {
+                    assert_eq!(run(source), Ok(Value::Float(f64::NAN)), "{}", source);
+                }
+                assert_eq!(
+                    run("(sqrt \"4\")").map_err(|err| err.kind),
+                    Err(EvalErrorKind::TypeMismatch {
+                        expected: "number",
+                        actual: "string"
+                    })
+                );
+            }
+
+            #[test]
+            fn test_mod() {
+                assert_eq!(
+                    Expression::from("(% 63 100)").evaluate(&mut Context::new()),
+                    Ok(Value::Integer(63))
+                );
+
+                assert_eq!(
+                    Expression::from("(% 101 2)").evaluate(&mut Context::new()),
+                    Ok(Value::Integer(1))
+                );
+            }
+
+            #[test]
+            fn test_eq() {
+                assert_eq!(
+                    Expression::from("(= 63 10)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(false))
+                );
+                assert_eq!(
+                    Expression::from("(= 63 63)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(true))
+                );
+
+                assert_eq!(
+                    Expression::from("(= true true)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(true))
+                );
+                assert_eq!(
+                    Expression::from("(= true false)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(false))
+                );
+
+                assert_eq!(
+                    Expression::from("(= nil nil)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(true))
+                );
+            }
+
+            #[test]
+            fn test_lt() {
+                assert_eq!(
+                    Expression::from("(< 63 63)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(false))
+                );
+                assert_eq!(
+                    Expression::from("(< 5 63)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(true))
+                );
+                assert_eq!(
+                    Expression::from("(< 7 3)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(false))
+                );
+            }
+
+            #[test]
+            fn test_gt() {
+                assert_eq!(
+                    Expression::from("(> 63 63)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(false))
+                );
+                assert_eq!(
+                    Expression::from("(> 5 63)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(false))
+                );
+                assert_eq!(
+                    Expression::from("(> 7 3)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(true))
+                );
+            }
+
+            #[test]
+            fn test_and() {
+                assert_eq!(
+                    Expression::from("(and true true)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(true))
+                );
+                assert_eq!(
+                    Expression::from("(and true false)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(false))
+                );
+                assert_eq!(
+                    Expression::from("(and false true)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(false))
+                );
+                assert_eq!(
+                    Expression::from("(and false false)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(false))
+                );
+            }
+
+            #[test]
+            fn test_or() {
+                assert_eq!(
+                    Expression::from("(or true true)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(true))
+                );
+                assert_eq!(
+                    Expression::from("(or true false)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(true))
+                );
+                assert_eq!(
+                    Expression::from("(or false true)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(true))
+                );
+                assert_eq!(
+                    Expression::from("(or false false)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(false))
+                );
+            }
+
+            #[test]
+            fn test_not() {
+                assert_eq!(
+                    Expression::from("(not true)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(false))
+                );
+                assert_eq!(
+                    Expression::from("(not false)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(true))
+                );
+            }
+
+            #[test]
+            fn test_math() {
+                assert_eq!(
+                    Expression::from("(+ 4 (* 3 5) (* 4 6))").evaluate(&mut Context::new()),
+                    Ok(Value::Integer(43))
+                );
+            }
+
+            #[test]
+            fn test_print_int() {
+                assert_eq!(
+                    Expression::from("(print 5)").evaluate(&mut Context::new()),
+                    Ok(Value::Nil)
+                );
+            }
+
+            #[test]
+            fn test_print_nil() {
+                assert_eq!(
+                    Expression::from("(print nil)").evaluate(&mut Context::new()),
+                    Ok(Value::Nil)
+                );
+            }
+
+            #[test]
+            fn test_if() {
+                assert_eq!(
+                    Expression::from("(if true 4 nil)").evaluate(&mut Context::new()),
+                    Ok(Value::Integer(4))
+                );
+
+                assert_eq!(
+                    Expression::from("(if false 4 nil)").evaluate(&mut Context::new()),
+                    Ok(Value::Nil)
+                );
+            }
+
+            #[test]
+            fn test_if_else() {
+                assert_eq!(
+                    Expression::from("(if true 4 nil)").evaluate(&mut Context::new()),
+                    Ok(Value::Integer(4))
+                );
+
+                assert_eq!(
+                    Expression::from("(if false 4 nil)").evaluate(&mut Context::new()),
+                    Ok(Value::Nil)
+                );
+
+                assert_eq!(
+                    Expression::from("(if true nil 4)").evaluate(&mut Context::new()),
+                    Ok(Value::Nil)
+                );
+
+                assert_eq!(
+                    Expression::from("(if false 4 4)").evaluate(&mut Context::new()),
+                    Ok(Value::Integer(4))
+                );
+            }
+
+            #[test]
+            fn test_while() {
+                let mut context = Context::new();
+                context.set_output(Box::new(std::io::sink()));
+                assert_eq!(
+                    Expression::from(
+                        "(block (global i 0) (while (< i 10) (block (print i) (global i (+ i 1)))) i)"
+                    )
+                    .evaluate(&mut context),
+                    Ok(Value::Integer(10))
+                );
+            }
+
+            #[test]
+            fn test_block() {
+                assert_eq!(
+                    Expression::from("(block)").evaluate(&mut Context::new()),
+                    Ok(Value::Nil)
+                );
+
+                assert_eq!(
+                    Expression::from("(block 5)").evaluate(&mut Context::new()),
+                    Ok(Value::Integer(5))
+                );
+
+                assert_eq!(
+                    Expression::from("(block 5 7)").evaluate(&mut Context::new()),
+                    Ok(Value::Integer(7))
+                );
+
+                assert_eq!(
+                    Expression::from("(block (+ 5 2) 5 1 true)").evaluate(&mut Context::new()),
+                    Ok(Value::Bool(true))
+                );
+            }
+
+            #[test]
+            fn test_global() {
+                let mut context = Context::new();
+                assert_eq!(
+                    Expression::from("(global abc 4)").evaluate(&mut context),
+                    Ok(Value::Nil)
+                );
+                assert_eq!(context.globals.get("abc"), Option::Some(&Value::Integer(4)));
+            }
+
+            #[test]
+            fn test_global_multi() {
+                let mut context = Context::new();
+                assert_eq!(
+                    Expression::from("(block (global abc 4) (global a 1) (global abc 7) a)")
+                        .evaluate(&mut context),
+                    Ok(Value::Integer(1))
+                );
+                assert_eq!(context.globals.get("abc"), Option::Some(&Value::Integer(7)));
+            }
+
+            #[test]
+            fn test_args() {
+                let mut context = Context::new();
+                context.args = vec!["a".to_string(), "b".to_string()];
+                assert_eq!(
+                    Expression::from("(arg-count)").evaluate(&mut context),
+                    Ok(Value::Integer(2))
+                );
+                assert_eq!(
+                    Expression::from("(arg 1)").evaluate(&mut context),
+                    Ok(Value::String("b".into()))
+                );
+                assert_eq!(
+                    Expression::from("(arg 2)").evaluate(&mut context),
+                    Ok(Value::Nil)
+                );
+                assert_eq!(
+                    Expression::from("(arg -1)").evaluate(&mut context),
+                    Ok(Value::Nil)
+                );
+            }
+
+            #[test]
+            fn test_exit() {
+                assert_eq!(
+                    Expression::from("(block (exit 3) (print 1))")
+                        .evaluate(&mut Context::new())
+                        .map_err(|err| err.kind),
+                    Err(EvalErrorKind::Exit(3))
+                );
+                assert_eq!(
+                    Expression::from("(exit)")
+                        .evaluate(&mut Context::new())
+                        .map_err(|err| err.kind),
+                    Err(EvalErrorKind::Exit(0))
+                );
+                for (source, code) in &[("(exit 300)", 300), ("(exit -1)", -1)] {
+                    let err = Expression::from(*source)
+                        .evaluate(&mut Context::new())
+                        .unwrap_err();
+                    assert_eq!(err.kind, EvalErrorKind::InvalidExitCode(*code));
+                    assert_eq!(err.span.map(|span| span.start), Some(6));
+                }
+            }
+
+            fn run(source: &str) -> Result<Value, EvalError> {
+                let mut context = Context::new();
+                let mut result = Ok(Value::Nil);
+                for expr in Expression::parse(source).unwrap() {
+                    result = expr.evaluate(&mut context);
+                }
+                result
+            }
+
+            #[test]
+            fn test_lambda() {
+                assert_eq!(run("((lambda (a b) (- a b)) 7 2)"), Ok(Value::Integer(5)));
+                assert_eq!(run("((lambda () 42))"), Ok(Value::Integer(42)));
+                assert_eq!(
+                    run("((lambda (x) (print x) (+ x 1)) 1)"),
+                    Ok(Value::Integer(2))
+                );
+            }
+
+            #[test]
+            fn test_defn() {
+                assert_eq!(
+                    run("(defn square (x) (* x x)) (square 12)"),
+                    Ok(Value::Integer(144))
+                );
+                assert_eq!(
+                    run("(defn fact (n) (if (< n 2) 1 (* n (fact (- n 1))))) (fact 10)"),
+                    Ok(Value::Integer(3628800))
+                );
+            }
+
+            #[test]
+            fn test_first_class_functions() {
+                assert_eq!(
+                    run("(defn twice (f x) (f (f x))) (twice (lambda (x) (* x 3)) 2)"),
+                    Ok(Value::Integer(18))
+                );
+                assert_eq!(
+                    run("(global inc (lambda (x) (+ x 1))) (inc 1)"),
+                    Ok(Value::Integer(2))
+                );
+            }
+
+            #[test]
+            fn test_closure() {
+                assert_eq!(
+                    run("(defn adder (n) (lambda (x) (+ x n))) (global add5 (adder 5)) (global n 100) (add5 1)"),
+                    Ok(Value::Integer(6))
+                );
+            }
+
+            #[test]
+            fn test_params_are_local() {
+                let mut context = Context::new();
+                Expression::from("(defn f (x) x)")
+                    .evaluate(&mut context)
+                    .unwrap();
+                Expression::from("(f 1)").evaluate(&mut context).unwrap();
+                assert_eq!(context.globals.get("x"), None);
+                assert!(context.scope.is_none());
+            }
+
+            #[test]
+            fn test_function_shadows_builtin() {
+                assert_eq!(
+                    run("(defn print (x) (* x 2)) (print 4)"),
+                    Ok(Value::Integer(8))
+                );
+                assert_eq!(run("(global print 1) (print 4)"), Ok(Value::Nil));
+                assert_eq!(
+                    run("((lambda (+) (+ 1 2)) (lambda (a b) (- a b)))"),
+                    Ok(Value::Integer(-1))
+                );
+                assert_eq!(
+                    run("(let ((< (lambda (a b) true))) (let ((x 1)) (< 2 x)))"),
+                    Ok(Value::Bool(true))
+                );
+                assert_eq!(
+                    run("((lambda (& not) (not false)) 2)"),
+                    Ok(Value::Bool(true))
+                );
+                assert_eq!(
+                    run("(let ((+ (lambda (a b) (concat a b)))) (+ \"a\" \"b\"))"),
+                    Ok(Value::String("ab".into()))
+                );
+                assert_eq!(
+                    run("(let ((if (lambda (a b c) c))) (if true 1 2))"),
+                    Ok(Value::Integer(2))
+                );
+                assert_eq!(
+                    run("(defn + (n) (if (= n 0) :done (+ (- n 1)))) (+ 100000)"),
+                    Ok(Value::Keyword("done".into()))
+                );
+            }
+
+            #[test]
+            fn test_function_errors() {
+                assert_eq!(
+                    run("(defn f (a b) a) (f 1)").map_err(|err| err.kind),
+                    Err(EvalErrorKind::ArityMismatch {
+                        expected: Arity::Exact(2),
+                        actual: 1
+                    })
+                );
+                assert_eq!(
+                    run("(global x 1) (x 2)").map_err(|err| err.kind),
+                    Err(EvalErrorKind::TypeMismatch {
+                        expected: "function",
+                        actual: "integer"
+                    })
+                );
+                assert_eq!(
+                    run("(lambda (1) 1)").map_err(|err| err.kind),
+                    Err(EvalErrorKind::TypeMismatch {
+                        expected: "symbol",
+                        actual: "integer"
+                    })
+                );
+                assert_eq!(
+                    run("(block ())").map_err(|err| err.kind),
+                    Err(EvalErrorKind::EmptyApplication)
+                );
+            }
+
+            #[test]
+            fn test_scope_restored_after_error() {
+                let mut context = Context::new();
+                Expression::from("(defn f (x) (+ x true))")
+                    .evaluate(&mut context)
+                    .unwrap();
+                assert!(Expression::from("(f 1)").evaluate(&mut context).is_err());
+                assert!(context.scope.is_none());
+            }
+
+            #[test]
+            fn test_tail_calls() {
+                assert_eq!(
+                    run(
+                        "(defn count (n acc) (if (= n 0) acc (count (- n 1) (+ acc 1)))) \
+                         (count 100000 0)"
+                    ),
+                    Ok(Value::Integer(100_000))
+                );
+                assert_eq!(
+                    run("(defn even? (n) (if (= n 0) true (odd? (- n 1)))) \
+                         (defn odd? (n) (if (= n 0) false (even? (- n 1)))) \
+                         (even? 10001)"),
+                    Ok(Value::Bool(false))
+                );
+                assert_eq!(
+                    run(
+                        "(defn f (n) (block 1 (let ((m (- n 1))) (if (= m 0) :done (f m))))) \
+                         (f 10000)"
+                    ),
+                    Ok(Value::Keyword("done".into()))
+                );
+            }
+
+            #[test]
+            fn test_stack_overflow() {
+                let mut context = Context::new();
+                context.max_depth = 50;
+                Expression::from("(defn f (n) (if (= n 0) 0 (+ 1 (f (- n 1)))))")
+                    .evaluate(&mut context)
+                    .unwrap();
+                assert_eq!(
+                    Expression::from("(f 49)").evaluate(&mut context),
+                    Ok(Value::Integer(49))
+                );
+                assert_eq!(
+                    Expression::from("(f 50)")
+                        .evaluate(&mut context)
+                        .map_err(|err| err.kind),
+                    Err(EvalErrorKind::StackOverflow(50))
+                );
+                assert_eq!(context.depth, 0);
+                assert!(context.scope.is_none());
+            }
+
+            #[test]
+            fn test_let() {
+                assert_eq!(run("(let ((x 1) (y 2)) (+ x y))"), Ok(Value::Integer(3)));
+                assert_eq!(run("(let () 1 2)"), Ok(Value::Integer(2)));
+                assert_eq!(run("(let ((x 1)))"), Ok(Value::Nil));
+                assert_eq!(
+                    run("(let ((x 1)) (let ((x 2) (y x)) (+ x y)))"),
+                    Ok(Value::Integer(3))
+                );
+                assert_eq!(
+                    run("(let ((x 1) (y 2) (x 3)) (list x y))").map(|v| v.to_string()),
+                    Ok("(3 2)".to_string())
+                );
+                assert_eq!(
+                    run("(defn f (a a) a) (f 1 2)"),
+                    Ok(Value::Integer(2))
+                );
+            }
+
+            #[test]
+            fn test_let_is_local() {
+                let mut context = Context::new();
+                Expression::from("(let ((x 1)) x)")
+                    .evaluate(&mut context)
+                    .unwrap();
+                assert!(context.globals.is_empty());
+                assert!(context.scope.is_none());
+                assert_eq!(
+                    run("(let ((x 1)) x) x").map_err(|err| err.kind),
+                    Err(EvalErrorKind::UnboundSymbol("x".to_string()))
+                );
+            }
+
+            #[test]
+            fn test_let_shadows_global() {
+                let mut context = Context::new();
+                Expression::from("(global x 1)").evaluate(&mut context).unwrap();
+                assert_eq!(
+                    Expression::from("(let ((x 2)) (set! x 3) x)").evaluate(&mut context),
+                    Ok(Value::Integer(3))
+                );
+                assert_eq!(context.globals.get("x"), Some(&Value::Integer(1)));
+            }
+
+            #[test]
+            fn test_set() {
+                assert_eq!(
+                    run("(let ((i 0)) (while (< i 10) (set! i (+ i 1))) i)"),
+                    Ok(Value::Integer(10))
+                );
+                assert_eq!(run("(global g 1) (set! g 2) g"), Ok(Value::Integer(2)));
+                assert_eq!(
+                    run("(let ((x 1)) (let ((y 2)) (set! x y)) x)"),
+                    Ok(Value::Integer(2))
+                );
+                assert_eq!(
+                    run("(set! nope 1)").map_err(|err| err.kind),
+                    Err(EvalErrorKind::UnboundSymbol("nope".to_string()))
+                );
+            }
+
+            #[test]
+            fn test_set_captured() {
+                assert_eq!(
+                    run(
+                        "(defn counter () (let ((n 0)) (lambda () (set! n (+ n 1)) n))) \
+                         (global c (counter)) (c) (c) (c)"
+                    ),
+                    Ok(Value::Integer(3))
+                );
+            }
+
+            #[test]
+            fn test_let_errors() {
+                assert_eq!(
+                    run("(let (x 1) x)").map_err(|err| err.kind),
+                    Err(EvalErrorKind::TypeMismatch {
+                        expected: "list",
+                        actual: "symbol"
+                    })
+                );
+                assert_eq!(
+                    run("(let ((x 1 2)) x)").map_err(|err| err.kind),
+                    Err(EvalErrorKind::ArityMismatch {
+                        expected: Arity::Exact(2),
+                        actual: 3
+                    })
+                );
+            }
+
+            fn ints(values: &[i64]) -> Value {
+                Value::List(Rc::new(values.iter().map(|v| Value::Integer(*v)).collect()))
+            }
+
+            #[test]
+            fn test_list() {
+                assert_eq!(run("(list)"), Ok(ints(&[])));
+                assert_eq!(run("(list 1 (+ 1 1) 3)"), Ok(ints(&[1, 2, 3])));
+                assert_eq!(
+                    run("(list 1 \"a\" (list true nil))").map(|v| v.to_string()),
+                    Ok("(1 \"a\" (true nil))".to_string())
+                );
+            }
+
+            #[test]
+            fn test_cons() {
+                assert_eq!(run("(cons 1 (list 2 3))"), Ok(ints(&[1, 2, 3])));
+                assert_eq!(run("(cons 1 (list))"), Ok(ints(&[1])));
+            }
+
+            #[test]
+            fn test_first_rest() {
+                assert_eq!(run("(car (list 1 2 3))"), Ok(Value::Integer(1)));
+                assert_eq!(run("(first (list 1 2 3))"), Ok(Value::Integer(1)));
+                assert_eq!(run("(first (list))"), Ok(Value::Nil));
+                assert_eq!(run("(cdr (list 1 2 3))"), Ok(ints(&[2, 3])));
+                assert_eq!(run("(rest (list 1))"), Ok(ints(&[])));
+                assert_eq!(run("(rest (list))"), Ok(ints(&[])));
+            }
+
+            #[test]
+            fn test_len_nth() {
+                assert_eq!(run("(len (list 1 2 3))"), Ok(Value::Integer(3)));
+                assert_eq!(run("(len (list))"), Ok(Value::Integer(0)));
+                assert_eq!(run("(nth (list 1 2 3) 2)"), Ok(Value::Integer(3)));
+                assert_eq!(
+                    run("(nth (list 1 2 3) 3)").map_err(|err| err.kind),
+                    Err(EvalErrorKind::IndexOutOfBounds { index: 3, len: 3 })
+                );
+                assert_eq!(
+                    run("(nth (list 1 2 3) -1)").map_err(|err| err.kind),
+                    Err(EvalErrorKind::IndexOutOfBounds { index: -1, len: 3 })
+                );
+            }
+
+            #[test]
+            fn test_append_reverse() {
+                assert_eq!(
+                    run("(append (list 1) (list) (list 2 3))"),
+                    Ok(ints(&[1, 2, 3]))
+                );
+                assert_eq!(run("(append)"), Ok(ints(&[])));
+                assert_eq!(run("(reverse (list 1 2 3))"), Ok(ints(&[3, 2, 1])));
+            }
+
+            #[test]
+            fn test_empty() {
+                assert_eq!(run("(empty? (list))"), Ok(Value::Bool(true)));
+                assert_eq!(run("(empty? (list nil))"), Ok(Value::Bool(false)));
+            }
+
+            #[test]
+            fn test_list_equality() {
+                assert_eq!(
+                    run("(= (list 1 (list 2)) (cons 1 (list (list 2))))"),
+                    Ok(Value::Bool(true))
+                );
+                assert_eq!(run("(= (list 1) (list 2))"), Ok(Value::Bool(false)));
+            }
+
+            #[test]
+            fn test_list_recursion() {
+                assert_eq!(
+                    run(
+                        "(defn sum (xs) (if (empty? xs) 0 (+ (first xs) (sum (rest xs))))) \
+                         (sum (list 1 2 3 4))"
+                    ),
+                    Ok(Value::Integer(10))
+                );
+            }
+
+            #[test]
+            fn test_list_type_mismatch() {
+                assert_eq!(
+                    run("(first 1)").map_err(|err| err.kind),
+                    Err(EvalErrorKind::TypeMismatch {
+                        expected: "list",
+                        actual: "integer"
+                    })
+                );
+            }
+
+            fn symbol(name: &str) -> Value {
+                Value::Symbol(name.into())
+            }
+
+            #[test]
+            fn test_quote() {
+                assert_eq!(run("'x"), Ok(symbol("x")));
+                assert_eq!(run("(quote x)"), Ok(symbol("x")));
+                assert_eq!(run("'5"), Ok(Value::Integer(5)));
+                assert_eq!(run("'()"), Ok(ints(&[])));
+                assert_eq!(
+                    run("'(+ 1 (f x))"),
+                    Ok(Value::List(Rc::new(vec![
+                        symbol("+"),
+                        Value::Integer(1),
+                        Value::List(Rc::new(vec![symbol("f"), symbol("x")]))
+                    ])))
+                );
+                assert_eq!(run("(first '(a b))"), Ok(symbol("a")));
+                assert_eq!(run("(= 'a 'a)"), Ok(Value::Bool(true)));
+                assert_eq!(run("(= 'a \"a\")"), Ok(Value::Bool(false)));
+            }
+
+            #[test]
+            fn test_quasiquote() {
+                assert_eq!(run("`(1 2)"), Ok(ints(&[1, 2])));
+                assert_eq!(
+                    run("(let ((x 2) (ys (list 3 4))) `(1 ,x ,@ys 5))"),
+                    Ok(ints(&[1, 2, 3, 4, 5]))
+                );
+                assert_eq!(
+                    run("(let ((x 2)) `(a (b ,x) ,(+ x 1)))"),
+                    Ok(Value::List(Rc::new(vec![
+                        symbol("a"),
+                        Value::List(Rc::new(vec![symbol("b"), Value::Integer(2)])),
+                        Value::Integer(3)
+                    ])))
+                );
+                assert_eq!(run("`,(+ 1 2)"), Ok(Value::Integer(3)));
+                assert_eq!(
+                    run("`(1 `(2 ,(3 ,(+ 1 2))))").map(|v| v.to_string()),
+                    Ok("(1 (quasiquote (2 (unquote (3 3)))))".to_string())
+                );
+                assert_eq!(
+                    run("(let ((x '(a b))) `(`(,@x ,,@x ,,x)))").map(|v| v.to_string()),
+                    Ok(
+                        "((quasiquote ((unquote-splicing x) (unquote a b) (unquote (a b)))))"
+                            .to_string()
+                    )
+                );
+                assert_eq!(
+                    run("`(1 ,@2)").map_err(|err| err.kind),
+                    Err(EvalErrorKind::TypeMismatch {
+                        expected: "list",
+                        actual: "integer"
+                    })
+                );
+            }
+
+            #[test]
+            fn test_eval() {
+                assert_eq!(run("(eval '(+ 1 2))"), Ok(Value::Integer(3)));
+                assert_eq!(run("(eval (cons '* (list 6 7)))"), Ok(Value::Integer(42)));
+                assert_eq!(run("(let ((x 4)) (eval 'x))"), Ok(Value::Integer(4)));
+                assert_eq!(run("(eval \"s\")"), Ok(Value::String("s".into())));
+            }
+
+            #[test]
+            fn test_unknown_operator() {
+                assert_eq!(
+                    Expression::from("(frobnicate 1 2)")
+                        .evaluate(&mut Context::new())
+                        .map_err(|err| err.kind),
+                    Err(EvalErrorKind::UnknownOperator("frobnicate".to_string()))
+                );
+            }
+
+            #[test]
+            fn test_unbound_symbol() {
+                assert_eq!(
+                    Expression::from("(+ 1 x)")
+                        .evaluate(&mut Context::new())
+                        .map_err(|err| err.kind),
+                    Err(EvalErrorKind::UnboundSymbol("x".to_string()))
+                );
+            }
+
+            #[test]
+            fn test_type_mismatch() {
+                assert_eq!(
+                    Expression::from("(+ 1 true)")
+                        .evaluate(&mut Context::new())
+                        .map_err(|err| err.kind),
+                    Err(EvalErrorKind::TypeMismatch {
+                        expected: "number",
+                        actual: "bool"
+                    })
+                );
+                assert_eq!(
+                    Expression::from("(if 1 2 3)")
+                        .evaluate(&mut Context::new())
+                        .map_err(|err| err.kind),
+                    Err(EvalErrorKind::TypeMismatch {
+                        expected: "bool",
+                        actual: "integer"
+                    })
+                );
+            }
+
+            #[test]
+            fn test_arity_mismatch() {
+                assert_eq!(
+                    Expression::from("(- 1 2 3)")
+                        .evaluate(&mut Context::new())
+                        .map_err(|err| err.kind),
+                    Err(EvalErrorKind::ArityMismatch {
+                        expected: Arity::Exact(2),
+                        actual: 3
+                    })
+                );
+                assert_eq!(
+                    Expression::from("(if true)")
+                        .evaluate(&mut Context::new())
+                        .map_err(|err| err.kind),
+                    Err(EvalErrorKind::ArityMismatch {
+                        expected: Arity::Range(2, 3),
+                        actual: 1
+                    })
+                );
+            }
+
+            #[test]
+            fn test_division_by_zero() {
+                assert_eq!(
+                    Expression::from("(/ 1 0)")
+                        .evaluate(&mut Context::new())
+                        .map_err(|err| err.kind),
+                    Err(EvalErrorKind::DivisionByZero)
+                );
+                assert_eq!(
+                    Expression::from("(% 1 0)")
+                        .evaluate(&mut Context::new())
+                        .map_err(|err| err.kind),
+                    Err(EvalErrorKind::DivisionByZero)
+                );
+            }
+
+            #[test]
+            fn test_promotion() {
+                let eval = |source: &str| {
+                    Expression::from(source)
+                        .evaluate(&mut Context::new())
+                        .map(|value| value.to_string())
+                };
+                assert_eq!(eval("(* 65536 65536)"), Ok("4294967296".to_string()));
+                assert_eq!(
+                    eval("(* 9223372036854775807 2)"),
+                    Ok("18446744073709551614".to_string())
+                );
+                assert_eq!(
+                    eval("(+ 100000000000000000000 1)"),
+                    Ok("100000000000000000001".to_string())
+                );
+                assert_eq!(
+                    Expression::from("(- (+ 9223372036854775807 1) 1)").evaluate(&mut Context::new()),
+                    Ok(Value::Integer(i64::MAX))
+                );
+                assert_eq!(
+                    eval("(< 9223372036854775807 9223372036854775808)"),
+                    Ok("true".to_string())
+                );
+            }
+
+            #[test]
+            fn test_overflow() {
+                assert_eq!(
+                    Expression::from("(nth (list 1) 9223372036854775808)")
+                        .evaluate(&mut Context::new())
+                        .map_err(|err| err.kind),
+                    Err(EvalErrorKind::IntegerOverflow)
+                );
+            }
+
+            #[test]
+            fn test_error_spans() {
+                let span = |source: &str| {
+                    Expression::from(source)
+                        .evaluate(&mut Context::new())
+                        .unwrap_err()
+                        .span
+                };
+                assert_eq!(span("(+ 1 true)"), Some(Span::new(5, 9, 1, 6)));
+                assert_eq!(span("(block\n  (foo 1))"), Some(Span::new(10, 13, 2, 4)));
+                assert_eq!(span("(not\n  x)"), Some(Span::new(7, 8, 2, 3)));
+                assert_eq!(span("(/ 1 (- 2 2))"), Some(Span::new(5, 12, 1, 6)));
+                assert_eq!(span("(block (if true))"), Some(Span::new(7, 16, 1, 8)));
+            }
+
+            /// The source text an error in `source` is reported at.
+            fn error_site(source: &str) -> &str {
+                let mut context = Context::new();
+                context.max_depth = 50;
+                let err = Expression::from(source).evaluate(&mut context).unwrap_err();
+                let span = err.span.unwrap();
+                &source[span.start..span.end]
+            }
+
+            #[test]
+            fn test_call_error_spans() {
+                let f = "(defn f (a & r) r)";
+                let cases = [
+                    (format!("(block {} (print (f)))", f), "(f)"),
+                    (format!("(block {} (f))", f), "(f)"),
+                    (format!("(block {} (print (if true (f))))", f), "(f)"),
+                    (format!("(block {} (defn g () (f)) (print (g)))", f), "(g)"),
+                    (
+                        "(block (defn g (n) (+ 1 (g (+ n 1)))) (print (g 0)))".to_string(),
+                        "(g (+ n 1))",
+                    ),
+                ];
+                for (source, site) in cases.iter() {
+                    assert_eq!(error_site(source), *site, "in {}", source);
+                }
+            }
+
+            #[test]
+            fn test_error_report() {
+                let source = "(block\n  (print (+ 1 \"a\")))";
+                let err = Expression::from(source)
+                    .evaluate(&mut Context::new())
+                    .unwrap_err();
+                assert_eq!(
+                    err.report(source),
+                    "error: type mismatch: expected number, found string at 2:15\n  |\n2 |   (print (+ 1 \"a\")))\n  |               ^^^"
+                );
+            }
+
+            #[test]
+            fn test_error_keeps_globals() {
+                let mut context = Context::new();
+                Expression::from("(global a 1)").evaluate(&mut context).unwrap();
+                assert!(Expression::from("(global a (+ a true))")
+                    .evaluate(&mut context)
+                    .is_err());
+                assert_eq!(context.globals.get("a"), Option::Some(&Value::Integer(1)));
+            }
+        };
+    }
+
+    mod eval {
+        eval_tests!(crate::context::Engine::Tree);
+    }
+
+    mod vm {
+        eval_tests!(crate::context::Engine::Vm);
     }
 }
@@ -0,0 +1,120 @@
+use crate::compiler::{compile_function, Chunk};
+use crate::context::{Context, Engine, Env, Frame, Layout};
+use crate::error::{Arity, EvalError, EvalErrorKind};
+use crate::expression::{eval_body_tail, Expression, Tail};
+use crate::registry::Registry;
+use crate::stack;
+use crate::value::Value;
+use crate::vm;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+pub struct Function {
+    pub name: Option<String>,
+    pub params: Vec<Rc<str>>,
+    pub rest: Option<Rc<str>>,
+    /// Where `params`, then `rest`, are bound in the frame of each call.
+    pub(crate) layout: Layout,
+    pub body: Vec<Expression>,
+    pub(crate) env: Option<Env>,
+    /// The body compiled for the VM on first call, and again whenever the
+    /// registered builtins change.
+    pub(crate) code: RefCell<Option<Rc<Chunk>>>,
+}
+
+impl Function {
+    pub fn arity(&self) -> Arity {
+        match self.rest {
+            Some(_) => Arity::AtLeast(self.params.len()),
+            None => Arity::Exact(self.params.len()),
+        }
+    }
+
+    /// Calls the function, running any calls it makes in tail position in
+    /// the same loop so that tail recursion uses constant stack. Fails with
+    /// `StackOverflow` once `context.max_depth` other calls are in progress,
+    /// or once the thread's stack is nearly used up.
+    pub fn call(
+        self: &Rc<Self>,
+        args: Vec<Value>,
+        context: &mut Context,
+    ) -> Result<Value, EvalError> {
+        if context.depth >= context.max_depth {
+            return Err(EvalErrorKind::StackOverflow(context.max_depth).into());
+        }
+        if stack::exhausted() {
+            return Err(EvalErrorKind::StackOverflow(context.depth).into());
+        }
+        if let Engine::Vm = context.engine {
+            return vm::call(self, args, context);
+        }
+        context.depth += 1;
+        let result = self.run(args, context);
+        context.depth -= 1;
+        result
+    }
+
+    fn run(
+        self: &Rc<Self>,
+        mut args: Vec<Value>,
+        context: &mut Context,
+    ) -> Result<Value, EvalError> {
+        let mut function = self.clone();
+        loop {
+            let frame = function.bind(args)?;
+            let tail =
+                context.with_frame(frame, |context| eval_body_tail(&function.body, context))?;
+            match tail {
+                Tail::Value(value) => return Ok(value),
+                // Failing to make a tail call fails this call, so its error
+                // is left for the caller to place.
+                Tail::Call(next, next_args, _) => {
+                    function = next;
+                    args = next_args;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn chunk(&self, builtins: &Registry) -> Rc<Chunk> {
+        let mut code = self.code.borrow_mut();
+        match &*code {
+            Some(chunk) if chunk.generation == builtins.generation() => chunk.clone(),
+            _ => {
+                let chunk = Rc::new(compile_function(self, builtins));
+                *code = Some(chunk.clone());
+                chunk
+            }
+        }
+    }
+
+    /// A frame binding the parameters to `args`, failing if there are too
+    /// many or too few.
+    pub(crate) fn bind(&self, mut args: Vec<Value>) -> Result<Frame, EvalError> {
+        if !self.arity().accepts(args.len()) {
+            return Err(EvalErrorKind::ArityMismatch {
+                expected: self.arity(),
+                actual: args.len(),
+            }
+            .into());
+        }
+
+        let rest = args.split_off(self.params.len());
+        let rest = self.rest.as_ref().map(|_| Value::List(Rc::new(rest)));
+        let values = args.into_iter().chain(rest);
+        Ok(self.layout.frame(values, self.env.clone()))
+    }
+}
+
+// The captured environment may refer back to this function, so it is left
+// out to keep formatting finite.
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Function")
+            .field("name", &self.name)
+            .field("params", &self.params)
+            .field("rest", &self.rest)
+            .finish()
+    }
+}
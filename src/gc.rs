@@ -0,0 +1,605 @@
+use crate::context::{Env, Frame};
+use crate::error::{EvalError, EvalErrorKind};
+use crate::value::Value;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
+
+const INITIAL_THRESHOLD: usize = 1024;
+
+/// The mutable objects a cycle must pass through: an environment frame
+/// (captured by a closure) or an atom.
+#[derive(Clone)]
+enum Object {
+    Frame(Weak<RefCell<Frame>>),
+    Atom(Weak<RefCell<Value>>),
+}
+
+impl Object {
+    fn id(&self) -> usize {
+        match self {
+            Object::Frame(frame) => id(frame.as_ptr()),
+            Object::Atom(atom) => id(atom.as_ptr()),
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        match self {
+            Object::Frame(frame) => frame.strong_count() > 0,
+            Object::Atom(atom) => atom.strong_count() > 0,
+        }
+    }
+
+    /// Empties the object, dropping whatever it refers to.
+    fn clear(&self) {
+        match self {
+            Object::Frame(frame) => {
+                if let Some(frame) = frame.upgrade() {
+                    let mut frame = frame.borrow_mut();
+                    frame.vars.clear();
+                    frame.parent = None;
+                }
+            }
+            Object::Atom(atom) => {
+                if let Some(atom) = atom.upgrade() {
+                    atom.replace(Value::Nil);
+                }
+            }
+        }
+    }
+}
+
+/// A string or collection counted against the heap limit, which only needs
+/// to know whether it is still alive.
+enum Data {
+    String(Weak<str>),
+    Collection(Weak<dyn Any>),
+}
+
+impl Data {
+    fn is_live(&self) -> bool {
+        match self {
+            Data::String(string) => string.strong_count() > 0,
+            Data::Collection(collection) => collection.strong_count() > 0,
+        }
+    }
+}
+
+/// Tracks every frame and atom so that reference cycles through them, which
+/// reference counting alone never frees, can be collected.
+///
+/// Collection needs no root set: it counts the references each traced object
+/// receives from other traced objects, and anything with more strong
+/// references than that is held from outside the heap (a global, the Rust
+/// stack) and is live. Objects not reachable from a live one only survive
+/// through cycles, and are cleared to break them.
+///
+/// While a limit is set, the strings and collections builtins return are
+/// tracked too, so that growing data counts against it and not only frames.
+pub struct Heap {
+    objects: Vec<Object>,
+    threshold: usize,
+    /// Tracked strings and collections by address, with their sizes.
+    data: HashMap<usize, (Data, usize)>,
+    data_threshold: usize,
+    cells: usize,
+    /// The most cells that may be live at once: one for each frame and atom,
+    /// each item of a collection and each byte of a string. Allocating past
+    /// it fails with `HeapLimitExceeded`.
+    pub limit: Option<usize>,
+    pub collections: usize,
+    pub freed: usize,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            threshold: INITIAL_THRESHOLD,
+            data: HashMap::new(),
+            data_threshold: INITIAL_THRESHOLD,
+            cells: 0,
+            limit: None,
+            collections: 0,
+            freed: 0,
+        }
+    }
+
+    /// The number of objects still alive.
+    pub fn live(&self) -> usize {
+        self.objects
+            .iter()
+            .filter(|object| object.is_live())
+            .count()
+    }
+
+    pub fn alloc(&mut self, frame: Frame) -> Result<Env, EvalError> {
+        self.reserve()?;
+        let env = Rc::new(RefCell::new(frame));
+        self.objects.push(Object::Frame(Rc::downgrade(&env)));
+        Ok(env)
+    }
+
+    pub fn alloc_atom(&mut self, value: Value) -> Result<Rc<RefCell<Value>>, EvalError> {
+        self.reserve()?;
+        let atom = Rc::new(RefCell::new(value));
+        self.objects.push(Object::Atom(Rc::downgrade(&atom)));
+        Ok(atom)
+    }
+
+    /// Counts `value` against the limit if it is a string or collection not
+    /// already counted, failing if that takes the heap past it. Does nothing
+    /// while there is no limit.
+    pub fn track(&mut self, value: &Value) -> Result<(), EvalError> {
+        if self.limit.is_none() {
+            return Ok(());
+        }
+        let (address, data, cells) = match value {
+            Value::String(string) => (
+                id(Rc::as_ptr(string)),
+                Data::String(Rc::downgrade(string)),
+                string.len(),
+            ),
+            Value::List(items) => (
+                id(Rc::as_ptr(items)),
+                Data::Collection(Rc::downgrade(items) as Weak<dyn Any>),
+                items.len(),
+            ),
+            Value::Map(map) => (
+                id(Rc::as_ptr(map)),
+                Data::Collection(Rc::downgrade(map) as Weak<dyn Any>),
+                map.len(),
+            ),
+            Value::Vector(vector) => {
+                let root = vector.root();
+                (id(root.as_ptr()), Data::Collection(root), vector.len())
+            }
+            _ => return Ok(()),
+        };
+        if cells == 0 || matches!(self.data.get(&address), Some((data, _)) if data.is_live()) {
+            return Ok(());
+        }
+        if self.data.len() >= self.data_threshold {
+            self.prune();
+            self.data_threshold = INITIAL_THRESHOLD.max(self.data.len() * 2);
+        }
+        self.make_room(cells)?;
+        if let Some((_, freed)) = self.data.insert(address, (data, cells)) {
+            self.cells -= freed;
+        }
+        self.cells += cells;
+        Ok(())
+    }
+
+    /// Makes room for one more object, collecting first if the heap has
+    /// grown enough since the last collection or is at its limit.
+    fn reserve(&mut self) -> Result<(), EvalError> {
+        if self.objects.len() >= self.threshold {
+            self.prune();
+            if self.objects.len() >= self.threshold / 2 {
+                self.collect();
+            }
+            self.threshold = INITIAL_THRESHOLD.max(self.objects.len() * 2);
+        }
+        self.make_room(1)
+    }
+
+    /// Fails unless `cells` more fit under the limit once everything that
+    /// can be freed has been.
+    fn make_room(&mut self, cells: usize) -> Result<(), EvalError> {
+        if let Some(limit) = self.limit {
+            if self.used() + cells > limit {
+                self.prune();
+            }
+            if self.used() + cells > limit {
+                self.collect();
+            }
+            if self.used() + cells > limit {
+                return Err(EvalErrorKind::HeapLimitExceeded(limit).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn used(&self) -> usize {
+        self.objects.len() + self.cells
+    }
+
+    fn prune(&mut self) {
+        self.objects.retain(Object::is_live);
+        let cells = &mut self.cells;
+        self.data.retain(|_, (data, size)| {
+            let live = data.is_live();
+            if !live {
+                *cells -= *size;
+            }
+            live
+        });
+    }
+
+    /// Frees every object kept alive only by a reference cycle, returning how
+    /// many there were.
+    pub fn collect(&mut self) -> usize {
+        self.collections += 1;
+        self.prune();
+
+        let mut tracer = Tracer::default();
+        for object in &self.objects {
+            if tracer.nodes.contains_key(&object.id()) {
+                continue;
+            }
+            let node = match object {
+                Object::Frame(frame) => frame.upgrade().map(|env| tracer.frame(&env)),
+                Object::Atom(atom) => atom.upgrade().map(|atom| tracer.atom(&atom)),
+            };
+            // Discount the reference `upgrade` made while tracing.
+            if let Some(node) = node {
+                tracer.nodes.get_mut(&node).unwrap().strong -= 1;
+            }
+        }
+        tracer.finish();
+        if tracer.blocked {
+            return 0;
+        }
+
+        let garbage = tracer.unreachable();
+        // Hold every garbage object until all are cleared, so none is freed
+        // while another still points into it.
+        let held: Vec<Value> = garbage
+            .iter()
+            .filter_map(|object| match object {
+                Object::Frame(_) => None,
+                Object::Atom(atom) => atom.upgrade().map(Value::Atom),
+            })
+            .collect();
+        let frames: Vec<Env> = garbage
+            .iter()
+            .filter_map(|object| match object {
+                Object::Frame(frame) => frame.upgrade(),
+                Object::Atom(_) => None,
+            })
+            .collect();
+        garbage.iter().for_each(Object::clear);
+        drop((held, frames));
+
+        self.prune();
+        self.freed += garbage.len();
+        garbage.len()
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+fn id<T: ?Sized>(ptr: *const T) -> usize {
+    ptr as *const () as usize
+}
+
+struct Node {
+    strong: usize,
+    internal: usize,
+    edges: Vec<usize>,
+    object: Option<Object>,
+}
+
+/// Contents still to be traced: the `Rc` they are in, and the code tracing
+/// them.
+type Pending = (usize, Box<dyn FnOnce(&mut Tracer)>);
+
+/// Walks the object graph, recording for each `Rc` how many references it
+/// has in total and how many of those come from other traced objects.
+///
+/// Contents are traced from a worklist rather than by recursing, so that
+/// deeply nested data can't overflow the stack.
+#[derive(Default)]
+pub struct Tracer {
+    nodes: HashMap<usize, Node>,
+    current: Option<usize>,
+    pending: Vec<Pending>,
+    blocked: bool,
+}
+
+impl Tracer {
+    /// Records a reference to `rc` from the object being traced, queueing
+    /// `children` to trace its contents the first time it is seen.
+    pub fn edge<T: 'static>(
+        &mut self,
+        rc: &Rc<T>,
+        children: impl FnOnce(&T, &mut Tracer) + 'static,
+    ) -> usize {
+        self.enter(rc, None, children)
+    }
+
+    /// Traces the contents of everything seen so far, and of everything
+    /// they refer to in turn.
+    fn finish(&mut self) {
+        while let Some((node, children)) = self.pending.pop() {
+            self.current = Some(node);
+            children(self);
+        }
+        self.current = None;
+    }
+
+    fn frame(&mut self, env: &Env) -> usize {
+        let object = Object::Frame(Rc::downgrade(env));
+        self.enter(env, Some(object), |cell, tracer| match cell.try_borrow() {
+            Ok(frame) => {
+                for (_, value) in &frame.vars {
+                    tracer.value(value);
+                }
+                if let Some(parent) = &frame.parent {
+                    tracer.frame(parent);
+                }
+            }
+            // A frame being updated can't be traced, so nothing it refers
+            // to can safely be freed this time.
+            Err(_) => tracer.blocked = true,
+        })
+    }
+
+    fn atom(&mut self, atom: &Rc<RefCell<Value>>) -> usize {
+        let object = Object::Atom(Rc::downgrade(atom));
+        self.enter(atom, Some(object), |cell, tracer| match cell.try_borrow() {
+            Ok(value) => tracer.value(&value),
+            Err(_) => tracer.blocked = true,
+        })
+    }
+
+    fn enter<T: 'static>(
+        &mut self,
+        rc: &Rc<T>,
+        object: Option<Object>,
+        children: impl FnOnce(&T, &mut Tracer) + 'static,
+    ) -> usize {
+        let node = id(Rc::as_ptr(rc));
+        if let Some(from) = self.current {
+            self.nodes.get_mut(&from).unwrap().edges.push(node);
+        }
+        let internal = self.current.is_some() as usize;
+        if let Some(existing) = self.nodes.get_mut(&node) {
+            existing.internal += internal;
+            return node;
+        }
+        self.nodes.insert(
+            node,
+            Node {
+                strong: Rc::strong_count(rc),
+                internal,
+                edges: Vec::new(),
+                object,
+            },
+        );
+        // The count was taken first, so this reference isn't included in it.
+        let rc = rc.clone();
+        self.pending
+            .push((node, Box::new(move |tracer| children(&rc, tracer))));
+        node
+    }
+
+    pub fn value(&mut self, value: &Value) {
+        match value {
+            Value::List(items) => {
+                self.edge(items, |items, tracer| {
+                    items.iter().for_each(|item| tracer.value(item))
+                });
+            }
+            Value::Map(map) => {
+                self.edge(map, |map, tracer| {
+                    for (key, value) in map.iter() {
+                        tracer.value(key);
+                        tracer.value(value);
+                    }
+                });
+            }
+            Value::Vector(vector) => vector.trace(self),
+            Value::Function(function) => {
+                self.edge(function, |function, tracer| {
+                    if let Some(env) = &function.env {
+                        tracer.frame(env);
+                    }
+                });
+            }
+            Value::Atom(atom) => {
+                self.atom(atom);
+            }
+            _ => {}
+        }
+    }
+
+    /// The objects not reachable from anything referenced from outside the
+    /// traced graph.
+    fn unreachable(&self) -> Vec<Object> {
+        let mut marked: Vec<usize> = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.strong > node.internal)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut reachable: HashSet<usize> = marked.iter().copied().collect();
+        while let Some(node) = marked.pop() {
+            for edge in &self.nodes[&node].edges {
+                if reachable.insert(*edge) {
+                    marked.push(*edge);
+                }
+            }
+        }
+        self.nodes
+            .iter()
+            .filter(|(id, _)| !reachable.contains(id))
+            .filter_map(|(_, node)| node.object.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::{Context, Engine};
+    use crate::program::Program;
+
+    fn run(source: &str, context: &mut Context) -> Value {
+        Program::parse(source).unwrap().eval(context).unwrap()
+    }
+
+    #[test]
+    fn test_collects_closure_cycle() {
+        let mut context = Context::new();
+        run("(let ((f nil)) (set! f (lambda () f)) 1)", &mut context);
+        assert_eq!(context.heap.live(), 1);
+        assert_eq!(context.heap.collect(), 1);
+        assert_eq!(context.heap.live(), 0);
+    }
+
+    #[test]
+    fn test_keeps_reachable_frames() {
+        let mut context = Context::new();
+        run(
+            "(global counter (let ((n 0) (self nil)) (set! self (lambda () (set! n (+ n 1)) n)) self))",
+            &mut context,
+        );
+        assert_eq!(context.heap.collect(), 0);
+        assert_eq!(run("(counter) (counter)", &mut context), Value::Integer(2));
+
+        // Frames referenced only from the Rust stack are live too.
+        let counter = context.globals.remove("counter").unwrap();
+        assert_eq!(context.heap.collect(), 0);
+        drop(counter);
+        assert_eq!(context.heap.collect(), 1);
+    }
+
+    #[test]
+    fn test_cycle_through_collections() {
+        let mut context = Context::new();
+        run(
+            "(let ((fs nil)) (set! fs (list (lambda () fs) {:f (lambda () fs)} [(lambda () fs)])) 1)",
+            &mut context,
+        );
+        assert_eq!(context.heap.collect(), 1);
+        assert_eq!(context.heap.live(), 0);
+    }
+
+    #[test]
+    fn test_heap_limit() {
+        let mut context = Context::new();
+        context.heap.limit = Some(100);
+        let result = Program::parse("(defn f (n) (if (= n 0) 0 (+ 1 (f (- n 1))))) (f 200)")
+            .unwrap()
+            .eval(&mut context);
+        assert_eq!(
+            result.map_err(|err| err.kind),
+            Err(EvalErrorKind::HeapLimitExceeded(100))
+        );
+        assert_eq!(run("(f 50)", &mut context), Value::Integer(50));
+    }
+
+    #[test]
+    fn test_limit_counts_data() {
+        for engine in [Engine::Tree, Engine::Vm].iter() {
+            let growing = [
+                "(global s \"ab\") (while true (global s (concat s s)))",
+                "(global l (list)) (while true (global l (cons 1 l)))",
+                "(global v []) (while true (global v (conj v [v])))",
+                "(global m {}) (while true (global m (assoc m (len m) 0)))",
+            ];
+            for source in growing.iter() {
+                let mut context = Context::new();
+                context.engine = *engine;
+                context.set_heap_limit(Some(1000));
+                let result = Program::parse(source).unwrap().eval(&mut context);
+                assert_eq!(
+                    result.map_err(|err| err.kind),
+                    Err(EvalErrorKind::HeapLimitExceeded(1000)),
+                    "{}",
+                    source
+                );
+            }
+
+            // Data that is no longer live stops counting.
+            let mut context = Context::new();
+            context.engine = *engine;
+            context.set_heap_limit(Some(1000));
+            run(
+                "(global i 0) (while (< i 1000) (block (concat \"abc\" \"def\") (list i i) (global i (+ i 1))))",
+                &mut context,
+            );
+        }
+    }
+
+    #[test]
+    fn test_cycles_collected_under_limit() {
+        let mut context = Context::new();
+        context.heap.limit = Some(10);
+        run(
+            "(global i 0) (while (< i 100) (block (let ((f nil)) (set! f (lambda () f))) (global i (+ i 1))))",
+            &mut context,
+        );
+        assert!(context.heap.live() <= 10);
+        assert!(context.heap.freed >= 90);
+    }
+
+    #[test]
+    fn test_builtins() {
+        let mut context = Context::new();
+        assert_eq!(
+            run("(let ((f nil)) (set! f (lambda () f))) (gc)", &mut context),
+            Value::Integer(1)
+        );
+        assert_eq!(run("(gc)", &mut context), Value::Integer(0));
+        assert_eq!(
+            run("(gc-stats)", &mut context).to_string(),
+            "{:collections 2 :freed 1 :limit nil :live 0}"
+        );
+    }
+
+    #[test]
+    fn test_collects_atom_cycles() {
+        let mut context = Context::new();
+        run(
+            "(let ((a (atom nil))) (reset! a [a]) nil) (let ((b (atom nil)) (c (atom nil))) (reset! b c) (reset! c {:b b}) nil)",
+            &mut context,
+        );
+        assert_eq!(context.heap.live(), 3);
+        assert_eq!(context.heap.collect(), 3);
+        assert_eq!(context.heap.live(), 0);
+    }
+
+    #[test]
+    fn test_deep_data_on_default_stack() {
+        // Each atom is allocated before the one it will hold, so tracing
+        // from the oldest has the whole chain still to visit.
+        std::thread::spawn(|| {
+            for link in &["next", "(list next)", "[next]"] {
+                let mut context = Context::new();
+                let source = format!(
+                    "(global head (atom nil)) (global tail head) (global i 0)
+                     (while (< i 20000)
+                       (block
+                         (let ((next (atom nil))) (reset! tail {}) (global tail next))
+                         (global i (+ i 1))))
+                     (gc)",
+                    link
+                );
+                assert_eq!(run(&source, &mut context), Value::Integer(0));
+                assert!(context.heap.collections > 1);
+            }
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_keeps_reachable_atoms() {
+        let mut context = Context::new();
+        run(
+            "(global a (atom nil)) (reset! a (list a 1)) (let ((b (atom 2))) (global f (lambda () @b)))",
+            &mut context,
+        );
+        assert_eq!(context.heap.collect(), 0);
+        assert_eq!(run("(f)", &mut context), Value::Integer(2));
+        assert_eq!(run("(nth @a 1)", &mut context), Value::Integer(1));
+    }
+}
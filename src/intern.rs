@@ -0,0 +1,173 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+/// An interned name together with its number. Compiled code refers to
+/// globals by symbol, so reaching one is an index rather than a hash of its
+/// name. A symbol keeps its name alive, and so its number taken.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    index: usize,
+    name: Rc<str>,
+}
+
+impl Symbol {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn name(&self) -> Rc<str> {
+        self.name.clone()
+    }
+}
+
+// Two live symbols have the same number exactly when they have the same name.
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl Eq for Symbol {}
+
+struct Entry {
+    name: Weak<str>,
+    index: usize,
+}
+
+/// The names in use, held weakly: once the last symbol, expression or value
+/// sharing a name is dropped, its entry is pruned and its number reused.
+#[derive(Default)]
+struct Table {
+    entries: HashMap<Box<str>, Entry>,
+    /// Numbers freed by pruning.
+    free: Vec<usize>,
+    next: usize,
+    /// How many entries the table may reach before it is next pruned.
+    prune_at: usize,
+}
+
+/// The fewest entries worth pruning.
+const MIN_PRUNE: usize = 256;
+
+impl Table {
+    fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(entry) = self.entries.get_mut(name) {
+            if let Some(interned) = entry.name.upgrade() {
+                return Symbol {
+                    index: entry.index,
+                    name: interned,
+                };
+            }
+            // Dropped but not yet pruned, so the number is still free to
+            // take back.
+            let interned: Rc<str> = Rc::from(name);
+            entry.name = Rc::downgrade(&interned);
+            return Symbol {
+                index: entry.index,
+                name: interned,
+            };
+        }
+
+        if self.entries.len() >= self.prune_at {
+            self.prune();
+        }
+        let index = self.free.pop().unwrap_or_else(|| {
+            self.next += 1;
+            self.next - 1
+        });
+        let interned: Rc<str> = Rc::from(name);
+        let entry = Entry {
+            name: Rc::downgrade(&interned),
+            index,
+        };
+        self.entries.insert(name.into(), entry);
+        Symbol {
+            index,
+            name: interned,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<Symbol> {
+        let entry = self.entries.get(name)?;
+        Some(Symbol {
+            index: entry.index,
+            name: entry.name.upgrade()?,
+        })
+    }
+
+    /// Drops the entries of names no longer in use, then lets the table grow
+    /// to twice what is left before pruning again, so interning stays
+    /// amortized constant time.
+    fn prune(&mut self) {
+        let free = &mut self.free;
+        self.entries.retain(|_, entry| {
+            let live = entry.name.strong_count() > 0;
+            if !live {
+                free.push(entry.index);
+            }
+            live
+        });
+        self.prune_at = (self.entries.len() * 2).max(MIN_PRUNE);
+    }
+}
+
+thread_local! {
+    static TABLE: RefCell<Table> = RefCell::new(Table::default());
+}
+
+/// Returns the shared copy of `name`, so every symbol and keyword with the
+/// same text points at one allocation while any of them is alive. String
+/// values are never interned.
+pub fn intern(name: &str) -> Rc<str> {
+    symbol(name).name
+}
+
+/// The symbol for `name`, interning it if it is new.
+pub fn symbol(name: &str) -> Symbol {
+    TABLE.with(|table| table.borrow_mut().intern(name))
+}
+
+/// The symbol for `name` if it is interned and in use, without adding it.
+pub fn lookup(name: &str) -> Option<Symbol> {
+    TABLE.with(|table| table.borrow().lookup(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let a = intern("name");
+        let b = intern(&String::from("name"));
+        assert!(Rc::ptr_eq(&a, &b));
+        assert!(!Rc::ptr_eq(&a, &intern("other")));
+    }
+
+    #[test]
+    fn test_symbols() {
+        let a = symbol("symbol-a");
+        assert_eq!(symbol("symbol-a"), a);
+        assert_ne!(symbol("symbol-b"), a);
+        assert_eq!(&*a.name(), "symbol-a");
+        assert_eq!(lookup("symbol-a"), Some(a));
+        assert_eq!(lookup("never-interned"), None);
+    }
+
+    #[test]
+    fn test_unused_names_are_dropped() {
+        let kept = symbol("kept");
+        for i in 0..10 * MIN_PRUNE {
+            intern(&format!("dropped-{}", i));
+        }
+        assert_eq!(lookup("dropped-0"), None);
+        assert_eq!(lookup("kept"), Some(kept.clone()));
+        TABLE.with(|table| {
+            let table = table.borrow();
+            assert!(table.entries.len() <= 2 * MIN_PRUNE);
+            assert!(table.next <= 2 * MIN_PRUNE + 1);
+        });
+        assert_eq!(symbol("kept").index(), kept.index());
+    }
+}
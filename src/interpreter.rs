@@ -0,0 +1,288 @@
+use crate::context::Context;
+use crate::error::{Error, EvalError};
+use crate::expression::{ExprKind, Expression};
+use crate::intern::intern;
+use crate::program::Program;
+use crate::span::Span;
+use crate::value::Value;
+use std::path::Path;
+
+/// A lisplike interpreter for use from Rust. Globals, macros and registered
+/// builtins persist from one call to the next.
+///
+/// Deep recursion fails with `StackOverflow` before it can overflow the
+/// thread's stack, so any thread can run any program. How deep a program
+/// may recurse before that depends on the stack, though, so one recursing
+/// thousands of calls deep without tail calls may want a thread with a
+/// larger stack; the `lisplike` binary uses 512 MiB.
+pub struct Interpreter {
+    context: Context,
+}
+
+impl Interpreter {
+    /// An interpreter with the prelude macros defined.
+    pub fn new() -> Self {
+        Interpreter {
+            context: Context::with_prelude(),
+        }
+    }
+
+    /// Evaluates each form of `source` in turn, returning the value of the
+    /// last one.
+    pub fn eval_str(&mut self, source: &str) -> Result<Value, Error> {
+        let program =
+            Program::parse(source).map_err(|err| Error::Parse(err, source.to_string()))?;
+        program.run(&mut self.context)
+    }
+
+    /// Reads and evaluates a source file, as `eval_str` does.
+    pub fn eval_file(&mut self, path: impl AsRef<Path>) -> Result<Value, Error> {
+        Program::load(path)?.run(&mut self.context)
+    }
+
+    /// Binds the global `name`, replacing any value it had.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.context.globals.insert(name, value);
+    }
+
+    /// The value of the global `name`, if it is bound.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.context.globals.get(name).cloned()
+    }
+
+    /// Calls the function or builtin `name` with already evaluated
+    /// arguments, resolving the name as a call in the program would.
+    pub fn call_function(&mut self, name: &str, args: Vec<Value>) -> Result<Value, EvalError> {
+        let span = Span::default();
+        let mut call = vec![Expression::new(ExprKind::Symbol(intern(name)), span)];
+        for arg in args {
+            call.push(Expression::new(ExprKind::Value(arg), span));
+        }
+        self.context
+            .eval(&Expression::new(ExprKind::List(call), span))
+    }
+
+    /// Bounds the memory programs may use, as `Context::set_heap_limit`
+    /// does.
+    pub fn set_heap_limit(&mut self, limit: Option<usize>) {
+        self.context.set_heap_limit(limit);
+    }
+
+    /// The interpreter's state, for reading its settings and globals.
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// The interpreter's state, for registering builtins and changing
+    /// settings such as the engine and the maximum call depth.
+    pub fn context_mut(&mut self) -> &mut Context {
+        &mut self.context
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Engine;
+    use crate::error::{Arity, EvalErrorKind, ParseErrorKind};
+    use std::fs;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_eval_str() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.eval_str("(+ 1 2) (* 3 4)").unwrap(),
+            Value::Integer(12)
+        );
+        assert_eq!(
+            interpreter.eval_str("(global x 5) (when true x)").unwrap(),
+            Value::Integer(5)
+        );
+        match interpreter.eval_str("(+ 1") {
+            Err(Error::Parse(err, source)) => {
+                assert!(err.is_incomplete());
+                assert_eq!(source, "(+ 1");
+            }
+            result => panic!("expected a parse error, got {:?}", result),
+        }
+        match interpreter.eval_str("(+ 1 true)") {
+            Err(Error::Eval(err, _)) => assert_eq!(
+                err.kind,
+                EvalErrorKind::TypeMismatch {
+                    expected: "number",
+                    actual: "bool"
+                }
+            ),
+            result => panic!("expected an evaluation error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_eval_file() {
+        let path = std::env::temp_dir().join("lisplike_test_eval_file.lisplike");
+        fs::write(&path, "(defn twice (x) (* 2 x))\n(twice 21)\n").unwrap();
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.eval_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap(), Value::Integer(42));
+        match interpreter.eval_file("no/such/file.lisplike") {
+            Err(err @ Error::Io(..)) => {
+                assert!(err.report().starts_with("error: no/such/file.lisplike: "))
+            }
+            result => panic!("expected an I/O error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_globals() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(interpreter.get_global("limit"), None);
+        interpreter.set_global("limit", Value::Integer(3));
+        assert_eq!(
+            interpreter.eval_str("(+ limit 1)").unwrap(),
+            Value::Integer(4)
+        );
+        interpreter.eval_str("(set! limit 10)").unwrap();
+        assert_eq!(interpreter.get_global("limit"), Some(Value::Integer(10)));
+    }
+
+    #[test]
+    fn test_settings() {
+        let mut interpreter = Interpreter::new();
+        let context = interpreter.context_mut();
+        assert_eq!(context.engine(), Engine::Vm);
+        context.set_engine(Engine::Tree);
+        context.set_max_depth(20);
+        context.set_args(vec!["a".to_string()]);
+        assert_eq!(interpreter.context().engine(), Engine::Tree);
+        assert_eq!(interpreter.context().args(), ["a".to_string()]);
+        assert_eq!(
+            interpreter.eval_str("(arg 0)").unwrap(),
+            Value::String("a".into())
+        );
+        interpreter
+            .eval_str("(defn down (n) (if (= n 0) 0 (+ 1 (down (- n 1)))))")
+            .unwrap();
+        match interpreter.eval_str("(down 100)") {
+            Err(Error::Eval(err, _)) => assert_eq!(err.kind, EvalErrorKind::StackOverflow(20)),
+            result => panic!("expected a stack overflow, got {:?}", result),
+        }
+        assert_eq!(interpreter.context().max_depth(), 20);
+    }
+
+    #[test]
+    fn test_deep_recursion_on_default_stack() {
+        // Calls between VM functions don't recurse on the Rust stack, but
+        // those the tree walker or a builtin such as `first` make do.
+        let direct = "(defn f (n) (if (= n 0) 0 (+ 1 (f (- n 1)))))";
+        let through_builtin = "(defn f (n) (if (= n 0) 0 (+ 1 (first (list (f (- n 1)))))))";
+        let cases = [
+            (Engine::Tree, direct),
+            (Engine::Tree, through_builtin),
+            (Engine::Vm, through_builtin),
+        ];
+        for (engine, program) in cases.iter().copied() {
+            std::thread::spawn(move || {
+                let mut interpreter = Interpreter::new();
+                interpreter.context_mut().set_engine(engine);
+                interpreter.context_mut().set_max_depth(1_000_000);
+                interpreter.eval_str(program).unwrap();
+                match interpreter.eval_str("(f 100000)") {
+                    Err(Error::Eval(err, _)) => match err.kind {
+                        EvalErrorKind::StackOverflow(_) => {}
+                        kind => panic!("expected a stack overflow, got {:?}", kind),
+                    },
+                    result => panic!("expected a stack overflow, got {:?}", result),
+                }
+                assert_eq!(interpreter.eval_str("(f 10)").unwrap(), Value::Integer(10));
+            })
+            .join()
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_deep_nesting_on_default_stack() {
+        for engine in [Engine::Tree, Engine::Vm].iter().copied() {
+            std::thread::spawn(move || {
+                let mut interpreter = Interpreter::new();
+                interpreter.context_mut().set_engine(engine);
+                let source = "(+ 1 ".repeat(2_000) + "0" + &")".repeat(2_000);
+                match interpreter.eval_str(&source) {
+                    Ok(value) => assert_eq!(value, Value::Integer(2_000)),
+                    Err(Error::Parse(err, _)) => match err.kind {
+                        ParseErrorKind::TooDeeplyNested(_) => {}
+                        kind => panic!("expected the nesting limit, got {:?}", kind),
+                    },
+                    Err(Error::Eval(err, _)) => match err.kind {
+                        EvalErrorKind::StackOverflow(_) => {}
+                        kind => panic!("expected a stack overflow, got {:?}", kind),
+                    },
+                    Err(err) => panic!("unexpected error {:?}", err),
+                }
+            })
+            .join()
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_heap_limit() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_heap_limit(Some(100));
+        assert_eq!(interpreter.context().heap_limit(), Some(100));
+        let grow = "(global s \"0123456789\") (while true (global s (concat s s)))";
+        match interpreter.eval_str(grow) {
+            Err(Error::Eval(err, _)) => {
+                assert_eq!(err.kind, EvalErrorKind::HeapLimitExceeded(100))
+            }
+            result => panic!("expected the heap limit, got {:?}", result),
+        }
+        interpreter.set_heap_limit(None);
+        assert_eq!(
+            interpreter.eval_str("(str-len (concat s s))").unwrap(),
+            Value::Integer(80)
+        );
+    }
+
+    #[test]
+    fn test_call_function() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str("(defn pair (a b) (list a b))")
+            .unwrap();
+        let list = Value::List(Rc::new(vec![Value::Integer(1)]));
+        assert_eq!(
+            interpreter
+                .call_function("pair", vec![list.clone(), Value::Bool(true)])
+                .map(|value| value.to_string()),
+            Ok("((1) true)".to_string())
+        );
+        assert_eq!(
+            interpreter.call_function("+", vec![Value::Integer(1), Value::Integer(2)]),
+            Ok(Value::Integer(3))
+        );
+        assert_eq!(
+            interpreter
+                .call_function("pair", vec![list])
+                .map_err(|err| err.kind),
+            Err(EvalErrorKind::ArityMismatch {
+                expected: Arity::Exact(2),
+                actual: 1
+            })
+        );
+        assert_eq!(
+            interpreter
+                .call_function("missing", vec![])
+                .map_err(|err| err.kind),
+            Err(EvalErrorKind::UnknownOperator("missing".to_string()))
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! An interpreter for lisplike, a small Lisp, that can be embedded in Rust
+//! programs.
+//!
+//! ```
+//! use lisplike::{Interpreter, Value};
+//!
+//! let mut interpreter = Interpreter::new();
+//! interpreter.set_global("base", Value::Integer(10));
+//! interpreter.eval_str("(defn scale (x) (* base x))").unwrap();
+//! let scaled = interpreter.call_function("scale", vec![Value::Integer(4)]);
+//! assert_eq!(scaled, Ok(Value::Integer(40)));
+//! ```
+//!
+//! Host functions are added through the registry on the interpreter's
+//! `Context`. Their arguments and results convert with `FromValue` and
+//! `IntoValue`, so they can take and return plain Rust types:
+//!
+//! ```
+//! use lisplike::{EvalErrorKind, Interpreter, Value};
+//!
+//! let mut interpreter = Interpreter::new();
+//! let builtins = &mut interpreter.context_mut().builtins;
+//! builtins.register_typed("shout", |text: String| text.to_uppercase());
+//! builtins.register_typed("total", |items: Vec<i64>| items.iter().sum::<i64>());
+//! let value = interpreter.eval_str(r#"(shout "hi")"#).unwrap();
+//! assert_eq!(value, Value::String("HI".into()));
+//! let value = interpreter.eval_str("(total '(1 2 3))").unwrap();
+//! assert_eq!(value, Value::Integer(6));
+//!
+//! let err = interpreter.call_function("shout", vec![Value::Integer(1)]);
+//! assert_eq!(
+//!     err.map_err(|err| err.kind),
+//!     Err(EvalErrorKind::TypeMismatch { expected: "string", actual: "integer" })
+//! );
+//! ```
+//!
+//! `register_fn` takes a function of the raw argument values instead, for
+//! builtins of any number of arguments.
+
+mod bigint;
+mod compiler;
+mod context;
+mod convert;
+mod error;
+mod expression;
+mod function;
+mod gc;
+mod intern;
+mod interpreter;
+mod macros;
+mod numeric;
+mod ops;
+mod program;
+mod ratio;
+mod registry;
+mod span;
+mod stack;
+mod tokenizer;
+mod value;
+mod vector;
+mod vm;
+
+pub use bigint::BigInt;
+pub use context::{Context, Engine, Globals, DEFAULT_MAX_DEPTH};
+pub use convert::{FromValue, IntoNative, IntoResult, IntoValue};
+pub use error::{Arity, Error, EvalError, EvalErrorKind, ParseError, ParseErrorKind};
+pub use expression::{ExprKind, Expression, Tail};
+pub use function::Function;
+pub use interpreter::Interpreter;
+pub use ops::Operation;
+pub use program::Program;
+pub use ratio::Ratio;
+pub use registry::{Native, NativeFn, Registry};
+pub use span::Span;
+pub use value::Value;
+pub use vector::Vector;
@@ -0,0 +1,410 @@
+use crate::context::Context;
+use crate::error::{EvalError, EvalErrorKind};
+use crate::expression::{ExprKind, Expression};
+use crate::stack;
+
+/// Rewrites every macro call in `expr` into its expansion, repeating until no
+/// macro calls remain. Quoted data and binding positions are left untouched.
+/// A macro that keeps expanding into itself fails with `StackOverflow` once
+/// expansions nest `max_depth` deep, or sooner if the thread's stack is
+/// nearly used up.
+pub fn expand(expr: &Expression, context: &mut Context) -> Result<Expression, EvalError> {
+    if context.expansion_depth >= context.max_depth {
+        return Err(
+            EvalError::new(EvalErrorKind::StackOverflow(context.max_depth)).or_span(expr.span),
+        );
+    }
+    if stack::exhausted() {
+        return Err(
+            EvalError::new(EvalErrorKind::StackOverflow(context.expansion_depth))
+                .or_span(expr.span),
+        );
+    }
+    context.expansion_depth += 1;
+    let result = expand_form(expr, context);
+    context.expansion_depth -= 1;
+    result
+}
+
+fn expand_form(expr: &Expression, context: &mut Context) -> Result<Expression, EvalError> {
+    let items = match &expr.kind {
+        ExprKind::List(items) if !items.is_empty() => items,
+        ExprKind::Vector(items) => {
+            let mut expanded = Vec::with_capacity(items.len());
+            for item in items {
+                expanded.push(expand(item, context)?);
+            }
+            return Ok(Expression::new(ExprKind::Vector(expanded), expr.span));
+        }
+        ExprKind::Map(entries) => {
+            let mut expanded = Vec::with_capacity(entries.len());
+            for (key, value) in entries {
+                expanded.push((expand(key, context)?, expand(value, context)?));
+            }
+            return Ok(Expression::new(ExprKind::Map(expanded), expr.span));
+        }
+        _ => return Ok(expr.clone()),
+    };
+
+    let head = match &items[0].kind {
+        ExprKind::Symbol(name) => &**name,
+        _ => return expand_from(expr, items, 0, context),
+    };
+
+    if let Some(function) = context.macros.get(head).cloned() {
+        let args = items[1..].iter().map(Expression::to_value).collect();
+        // Errors raised while expanding are reported at the call site, since
+        // the macro body may come from another source such as the prelude.
+        let expansion = function.call(args, context).map_err(|err| EvalError {
+            span: Some(expr.span),
+            ..err
+        })?;
+        return expand(&Expression::from_value(&expansion, expr.span), context);
+    }
+
+    match head {
+        "quote" => Ok(expr.clone()),
+        "quasiquote" => {
+            let mut expanded = vec![items[0].clone()];
+            for item in &items[1..] {
+                expanded.push(expand_quasiquoted(item, 1, context)?);
+            }
+            Ok(Expression::new(ExprKind::List(expanded), expr.span))
+        }
+        "lambda" => expand_from(expr, items, 2, context),
+        "defn" | "defmacro" => expand_from(expr, items, 3, context),
+        "let" if items.len() >= 2 => {
+            let mut expanded = vec![items[0].clone(), expand_bindings(&items[1], context)?];
+            for item in &items[2..] {
+                expanded.push(expand(item, context)?);
+            }
+            Ok(Expression::new(ExprKind::List(expanded), expr.span))
+        }
+        _ => expand_from(expr, items, 0, context),
+    }
+}
+
+/// Expands the items of a list from index `start` onwards, keeping the ones
+/// before it verbatim.
+fn expand_from(
+    expr: &Expression,
+    items: &[Expression],
+    start: usize,
+    context: &mut Context,
+) -> Result<Expression, EvalError> {
+    let mut expanded = Vec::with_capacity(items.len());
+    for (i, item) in items.iter().enumerate() {
+        if i < start {
+            expanded.push(item.clone());
+        } else {
+            expanded.push(expand(item, context)?);
+        }
+    }
+    Ok(Expression::new(ExprKind::List(expanded), expr.span))
+}
+
+/// Expands the initializers of a `let` binding list, but not the names.
+fn expand_bindings(bindings: &Expression, context: &mut Context) -> Result<Expression, EvalError> {
+    let pairs = match &bindings.kind {
+        ExprKind::List(pairs) => pairs,
+        _ => return Ok(bindings.clone()),
+    };
+    let mut expanded = Vec::with_capacity(pairs.len());
+    for pair in pairs {
+        match &pair.kind {
+            ExprKind::List(items) if !items.is_empty() => {
+                expanded.push(expand_from(pair, items, 1, context)?)
+            }
+            _ => expanded.push(pair.clone()),
+        }
+    }
+    Ok(Expression::new(ExprKind::List(expanded), bindings.span))
+}
+
+/// Within a quasiquoted template, `level` quasiquotes deep, only the parts
+/// unquoted at level 1 are code.
+fn expand_quasiquoted(
+    expr: &Expression,
+    level: usize,
+    context: &mut Context,
+) -> Result<Expression, EvalError> {
+    let items = match &expr.kind {
+        ExprKind::List(items) => items,
+        ExprKind::Vector(items) => {
+            let mut expanded = Vec::with_capacity(items.len());
+            for item in items {
+                expanded.push(expand_quasiquoted(item, level, context)?);
+            }
+            return Ok(Expression::new(ExprKind::Vector(expanded), expr.span));
+        }
+        ExprKind::Map(entries) => {
+            let mut expanded = Vec::with_capacity(entries.len());
+            for (key, value) in entries {
+                expanded.push((
+                    expand_quasiquoted(key, level, context)?,
+                    expand_quasiquoted(value, level, context)?,
+                ));
+            }
+            return Ok(Expression::new(ExprKind::Map(expanded), expr.span));
+        }
+        _ => return Ok(expr.clone()),
+    };
+    let level = match items.first().map(|head| &head.kind) {
+        Some(ExprKind::Symbol(name)) if &**name == "unquote" || &**name == "unquote-splicing" => {
+            if level == 1 {
+                return expand_from(expr, items, 1, context);
+            }
+            level - 1
+        }
+        Some(ExprKind::Symbol(name)) if &**name == "quasiquote" => level + 1,
+        _ => level,
+    };
+    let mut expanded = Vec::with_capacity(items.len());
+    for item in items {
+        expanded.push(expand_quasiquoted(item, level, context)?);
+    }
+    Ok(Expression::new(ExprKind::List(expanded), expr.span))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Program;
+    use crate::value::Value;
+
+    fn run(source: &str) -> Result<Value, EvalError> {
+        Program::parse(source)
+            .unwrap()
+            .eval(&mut Context::with_prelude())
+    }
+
+    fn expand_str(source: &str) -> String {
+        let mut context = Context::with_prelude();
+        let expr = Expression::parse_one(source).unwrap();
+        expand(&expr, &mut context).unwrap().to_value().to_string()
+    }
+
+    #[test]
+    fn test_defmacro() {
+        assert_eq!(
+            run("(defmacro swap-args (f a b) `(,f ,b ,a)) (swap-args - 1 10)"),
+            Ok(Value::Integer(9))
+        );
+        assert_eq!(
+            run("(defmacro ignore (x) nil) (ignore (undefined-function 1))"),
+            Ok(Value::Nil)
+        );
+    }
+
+    #[test]
+    fn test_args_are_unevaluated() {
+        assert_eq!(
+            run("(defmacro show (form) `(quote ,form)) (show (+ 1 2))").map(|v| v.to_string()),
+            Ok("(+ 1 2)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_recursive_expansion() {
+        assert_eq!(
+            run("(defmacro my-when (c & body) `(when ,c ,@body)) (my-when true 1 2)"),
+            Ok(Value::Integer(2))
+        );
+    }
+
+    #[test]
+    fn test_expansion_inside_functions() {
+        assert_eq!(
+            run("(defn abs (x) (when (< x 0) (set! x (- 0 x))) x) (abs -5)"),
+            Ok(Value::Integer(5))
+        );
+    }
+
+    #[test]
+    fn test_quoted_forms_not_expanded() {
+        assert_eq!(expand_str("'(when a b)"), "(quote (when a b))");
+        assert_eq!(
+            expand_str("`(when ,(when a b))"),
+            "(quasiquote (when (unquote (if a (block b)))))"
+        );
+        assert_eq!(
+            expand_str("`(a `(,(when b c) ,,(when d e)))"),
+            "(quasiquote (a (quasiquote ((unquote (when b c)) (unquote (unquote (if d (block e))))))))"
+        );
+    }
+
+    #[test]
+    fn test_vector_arguments() {
+        assert_eq!(
+            run("(defmacro count-items (items) (len items)) (count-items [a b c])"),
+            Ok(Value::Integer(3))
+        );
+        assert_eq!(
+            expand_str("[(when a b) '(when c d)]"),
+            "[(if a (block b)) (quote (when c d))]"
+        );
+    }
+
+    #[test]
+    fn test_macro_writing_macros() {
+        assert_eq!(
+            run(
+                "(defmacro def-adder (name n) `(defmacro ,name (x) `(+ ,x ,,n)))
+                 (def-adder add-two (+ 1 1))
+                 (add-two 5)"
+            ),
+            Ok(Value::Integer(7))
+        );
+    }
+
+    #[test]
+    fn test_binding_positions_not_expanded() {
+        assert_eq!(
+            run("(defmacro m () 1) (let ((m 2)) m)"),
+            Ok(Value::Integer(2))
+        );
+        assert_eq!(
+            run("(defmacro m () 1) ((lambda (m) m) 3)"),
+            Ok(Value::Integer(3))
+        );
+    }
+
+    #[test]
+    fn test_macroexpand() {
+        assert_eq!(
+            run("(macroexpand '(unless (< 1 2) (print 1)))").map(|v| v.to_string()),
+            Ok("(if (< 1 2) nil (block (print 1)))".to_string())
+        );
+        assert_eq!(
+            run("(macroexpand '(+ 1 2))").map(|v| v.to_string()),
+            Ok("(+ 1 2)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_gensym() {
+        let mut context = Context::new();
+        let program = Program::parse("(list (gensym) (gensym \"x\"))").unwrap();
+        let symbols = program.eval(&mut context).unwrap();
+        assert_eq!(symbols.to_string(), "(G__1 x__2)");
+    }
+
+    #[test]
+    fn test_when_unless() {
+        assert_eq!(run("(when (< 1 2) 1 2)"), Ok(Value::Integer(2)));
+        assert_eq!(run("(when (> 1 2) 1 2)"), Ok(Value::Nil));
+        assert_eq!(run("(unless (> 1 2) 3)"), Ok(Value::Integer(3)));
+        assert_eq!(run("(unless (< 1 2) 3)"), Ok(Value::Nil));
+    }
+
+    #[test]
+    fn test_cond() {
+        let classify =
+            "(defn classify (n) (cond ((< n 0) 'negative) ((= n 0) 'zero) (else 'positive)))";
+        assert_eq!(
+            run(&format!(
+                "{} (list (classify -3) (classify 0) (classify 7))",
+                classify
+            ))
+            .map(|v| v.to_string()),
+            Ok("(negative zero positive)".to_string())
+        );
+        assert_eq!(run("(cond ((> 1 2) 1))"), Ok(Value::Nil));
+        assert_eq!(run("(cond)"), Ok(Value::Nil));
+    }
+
+    #[test]
+    fn test_for() {
+        assert_eq!(
+            run("(global total 0) (for (i 0 5) (global total (+ total i))) total"),
+            Ok(Value::Integer(10))
+        );
+        assert_eq!(
+            run("(let ((end 3) (n 0)) (for (i 0 end) (set! n (+ n end))) n)"),
+            Ok(Value::Integer(9))
+        );
+    }
+
+    #[test]
+    fn test_expansion_error_span() {
+        let source = "(block\n  (for 1 2))";
+        let err = run(source).unwrap_err();
+        assert_eq!(
+            err.kind,
+            EvalErrorKind::TypeMismatch {
+                expected: "list",
+                actual: "integer"
+            }
+        );
+        assert_eq!(err.span.unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_rest_params() {
+        assert_eq!(
+            run("(defn f (a & more) (list a more)) (f 1 2 3)").map(|v| v.to_string()),
+            Ok("(1 (2 3))".to_string())
+        );
+        assert_eq!(
+            run("(defn f (& xs) xs) (f)").map(|v| v.to_string()),
+            Ok("()".to_string())
+        );
+        assert_eq!(
+            run("(defn f (a b & more) a) (f 1)").map_err(|err| err.kind),
+            Err(EvalErrorKind::ArityMismatch {
+                expected: crate::error::Arity::AtLeast(2),
+                actual: 1
+            })
+        );
+        assert_eq!(
+            run("(lambda (a &) a)").map_err(|err| err.kind),
+            Err(EvalErrorKind::InvalidSyntax {
+                expected: "a parameter name after `&`"
+            })
+        );
+    }
+
+    #[test]
+    fn test_runaway_expansion() {
+        let mut context = Context::with_prelude();
+        context.max_depth = 50;
+        for source in &[
+            "(defmacro rec () '(rec)) (rec)",
+            "(defmacro grow () '(list (grow))) (grow)",
+        ] {
+            let err = Program::parse(source)
+                .unwrap()
+                .eval(&mut context)
+                .unwrap_err();
+            assert_eq!(err.kind, EvalErrorKind::StackOverflow(50));
+            assert_eq!(context.expansion_depth, 0);
+        }
+        assert_eq!(
+            Program::parse("(when true 1)").unwrap().eval(&mut context),
+            Ok(Value::Integer(1))
+        );
+    }
+
+    #[test]
+    fn test_runaway_expansion_on_default_stack() {
+        std::thread::spawn(|| {
+            let mut context = Context::with_prelude();
+            for source in &[
+                "(defmacro m (x) `(m ,x)) (m 1)",
+                "(defmacro m (x) `(list (m ,x))) (m 1)",
+            ] {
+                let err = Program::parse(source)
+                    .unwrap()
+                    .eval(&mut context)
+                    .unwrap_err();
+                match err.kind {
+                    EvalErrorKind::StackOverflow(_) => {}
+                    kind => panic!("expected a stack overflow, got {:?}", kind),
+                }
+                assert_eq!(context.expansion_depth, 0);
+            }
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -1,15 +1,94 @@
-mod context;
-mod expression;
-mod ops;
-mod tokenizer;
-mod value;
-
-use context::Context;
-use expression::Expression;
+use lisplike::{Engine, Error, EvalError, EvalErrorKind, Interpreter};
+use std::env;
 use std::io::{self, Write};
+use std::process;
+use std::thread;
+
+const USAGE: &str = "usage:
+    lisplike run <file> [args...]    run a script
+    lisplike run --tree <file> ...   run a script with the tree-walking interpreter
+    lisplike eval -e <expr>          evaluate an expression and print its value
+    lisplike repl                    start an interactive session (default)";
+
+/// Some non-tail calls recurse on the Rust stack, and fail with
+/// `StackOverflow` once it runs low rather than at `DEFAULT_MAX_DEPTH`. The
+/// costliest, a tree-walker call made from inside a builtin's argument,
+/// takes about 7 KiB of stack in release builds and 29 KiB in debug ones, so
+/// 10000 of them need about 75 MiB and 290 MiB. The thread gets enough for
+/// the debug figure with room to spare; pages it never touches cost nothing.
+const STACK_SIZE: usize = 512 << 20;
 
 fn main() {
-    let mut context = Context::new();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let code = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || dispatch(&args))
+        .expect("failed to start the interpreter thread")
+        .join()
+        .unwrap_or(101);
+    process::exit(code);
+}
+
+fn dispatch(args: &[String]) -> i32 {
+    match args.first().map(String::as_str) {
+        None => repl(),
+        Some("repl") if args.len() == 1 => repl(),
+        Some("run") if args.len() >= 3 && args[1] == "--tree" => {
+            run_file(Engine::Tree, &args[2], &args[3..])
+        }
+        Some("run") if args.len() >= 2 => run_file(Engine::Vm, &args[1], &args[2..]),
+        Some("eval") if args.len() == 3 && args[1] == "-e" => eval(&args[2]),
+        Some("help") | Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            0
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    }
+}
+
+/// Maps a failed run to a process status, reporting anything other than an
+/// explicit `(exit n)`.
+fn exit_status(err: &Error) -> i32 {
+    match err {
+        Error::Eval(
+            EvalError {
+                kind: EvalErrorKind::Exit(code),
+                ..
+            },
+            _,
+        ) => *code,
+        _ => {
+            eprintln!("{}", err.report());
+            1
+        }
+    }
+}
+
+fn run_file(engine: Engine, path: &str, script_args: &[String]) -> i32 {
+    let mut interpreter = Interpreter::new();
+    interpreter.context_mut().set_engine(engine);
+    interpreter.context_mut().set_args(script_args.to_vec());
+    match interpreter.eval_file(path) {
+        Ok(_) => 0,
+        Err(err) => exit_status(&err),
+    }
+}
+
+fn eval(source: &str) -> i32 {
+    match Interpreter::new().eval_str(source) {
+        Ok(value) => {
+            println!("{}", value);
+            0
+        }
+        Err(err) => exit_status(&err),
+    }
+}
+
+fn repl() -> i32 {
+    let mut interpreter = Interpreter::new();
     let mut line = String::new();
     loop {
         if line.is_empty() {
@@ -18,17 +97,24 @@ fn main() {
         io::stdout().flush().unwrap();
         if io::stdin().read_line(&mut line).unwrap() == 0 {
             println!();
-            break;
+            return 0;
         }
         if line.is_empty() || line == "\n" {
             line.clear();
             continue;
         }
-        if line.chars().filter(|c| *c == '(').count() > line.chars().filter(|c| *c == ')').count() {
-            continue;
+        match interpreter.eval_str(&line) {
+            Ok(value) => println!("{}", value),
+            Err(Error::Parse(err, _)) if err.is_incomplete() => continue,
+            Err(Error::Eval(
+                EvalError {
+                    kind: EvalErrorKind::Exit(code),
+                    ..
+                },
+                _,
+            )) => return code,
+            Err(err) => eprintln!("{}", err.report()),
         }
-        let expr: Expression = Expression::from(line.as_str());
         line.clear();
-        println!("{}", expr.eval(&mut context));
     }
 }
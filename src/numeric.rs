@@ -0,0 +1,433 @@
+use crate::bigint::BigInt;
+use crate::error::{EvalError, EvalErrorKind};
+use crate::ratio::Ratio;
+use crate::value::Value;
+use std::cmp::Ordering;
+
+// Integer arithmetic works on `i64` and falls back to `BigInt` only when a
+// result overflows; `Value::from` demotes big results and whole ratios again.
+// Mixed operands are lifted to the more general of the two: integers to
+// ratios, and anything to a float as soon as either operand is one.
+
+fn to_big(value: &Value) -> Result<BigInt, EvalError> {
+    match value {
+        Value::Integer(int) => Ok(BigInt::from(*int)),
+        Value::BigInt(big) => Ok(big.clone()),
+        _ => Err(value.type_mismatch("integer")),
+    }
+}
+
+pub fn to_ratio(value: &Value) -> Result<Ratio, EvalError> {
+    match value {
+        Value::Ratio(ratio) => Ok(ratio.clone()),
+        Value::Integer(_) | Value::BigInt(_) => Ok(Ratio::from(to_big(value)?)),
+        _ => Err(value.type_mismatch("rational")),
+    }
+}
+
+pub fn to_f64(value: &Value) -> Result<f64, EvalError> {
+    match value {
+        Value::Integer(int) => Ok(*int as f64),
+        Value::BigInt(big) => Ok(big.to_f64()),
+        Value::Ratio(ratio) => Ok(ratio.to_f64()),
+        Value::Float(float) => Ok(*float),
+        _ => Err(value.type_mismatch("number")),
+    }
+}
+
+/// Both operands as floats, if either of them is one.
+fn floats(a: &Value, b: &Value) -> Result<Option<(f64, f64)>, EvalError> {
+    match (a, b) {
+        (Value::Float(_), _) | (_, Value::Float(_)) => Ok(Some((to_f64(a)?, to_f64(b)?))),
+        _ => Ok(None),
+    }
+}
+
+/// Both operands as ratios, if either of them is one.
+fn ratios(a: &Value, b: &Value) -> Result<Option<(Ratio, Ratio)>, EvalError> {
+    match (a, b) {
+        (Value::Ratio(_), _) | (_, Value::Ratio(_)) => Ok(Some((to_ratio(a)?, to_ratio(b)?))),
+        _ => Ok(None),
+    }
+}
+
+pub fn add(a: &Value, b: &Value) -> Result<Value, EvalError> {
+    if let (Value::Integer(x), Value::Integer(y)) = (a, b) {
+        if let Some(sum) = x.checked_add(*y) {
+            return Ok(Value::Integer(sum));
+        }
+    }
+    if let Some((x, y)) = floats(a, b)? {
+        return Ok(Value::Float(x + y));
+    }
+    if let Some((x, y)) = ratios(a, b)? {
+        return Ok(Value::from(x.add(&y)));
+    }
+    Ok(Value::from(&to_big(a)? + &to_big(b)?))
+}
+
+pub fn sub(a: &Value, b: &Value) -> Result<Value, EvalError> {
+    if let (Value::Integer(x), Value::Integer(y)) = (a, b) {
+        if let Some(difference) = x.checked_sub(*y) {
+            return Ok(Value::Integer(difference));
+        }
+    }
+    if let Some((x, y)) = floats(a, b)? {
+        return Ok(Value::Float(x - y));
+    }
+    if let Some((x, y)) = ratios(a, b)? {
+        return Ok(Value::from(x.sub(&y)));
+    }
+    Ok(Value::from(&to_big(a)? - &to_big(b)?))
+}
+
+pub fn mul(a: &Value, b: &Value) -> Result<Value, EvalError> {
+    if let (Value::Integer(x), Value::Integer(y)) = (a, b) {
+        if let Some(product) = x.checked_mul(*y) {
+            return Ok(Value::Integer(product));
+        }
+    }
+    if let Some((x, y)) = floats(a, b)? {
+        return Ok(Value::Float(x * y));
+    }
+    if let Some((x, y)) = ratios(a, b)? {
+        return Ok(Value::from(x.mul(&y)));
+    }
+    Ok(Value::from(&to_big(a)? * &to_big(b)?))
+}
+
+/// Exact division: integers that don't divide evenly give a ratio. Float
+/// division follows IEEE 754, so only exact division by zero fails.
+pub fn div(a: &Value, b: &Value) -> Result<Value, EvalError> {
+    if let (Value::Integer(x), Value::Integer(y)) = (a, b) {
+        if x.checked_rem(*y) == Some(0) {
+            return Ok(Value::Integer(x / y));
+        }
+    }
+    if let Some((x, y)) = floats(a, b)? {
+        return Ok(Value::Float(x / y));
+    }
+    to_ratio(a)?
+        .div(&to_ratio(b)?)
+        .map(Value::from)
+        .ok_or_else(|| EvalErrorKind::DivisionByZero.into())
+}
+
+/// The remainder of truncating division, with the sign of the dividend.
+pub fn rem(a: &Value, b: &Value) -> Result<Value, EvalError> {
+    if let (Value::Integer(x), Value::Integer(y)) = (a, b) {
+        if let Some(remainder) = x.checked_rem(*y) {
+            return Ok(Value::Integer(remainder));
+        }
+    }
+    if let Some((x, y)) = floats(a, b)? {
+        return Ok(Value::Float(x % y));
+    }
+    if let Some((x, y)) = ratios(a, b)? {
+        let quotient = x.div(&y).ok_or(EvalErrorKind::DivisionByZero)?.trunc();
+        return Ok(Value::from(x.sub(&y.mul(&Ratio::from(quotient)))));
+    }
+    let (_, remainder) = to_big(a)?
+        .div_rem(&to_big(b)?)
+        .ok_or(EvalErrorKind::DivisionByZero)?;
+    Ok(Value::from(remainder))
+}
+
+/// Orders two numbers exactly, or returns `None` if either is NaN. Unlike
+/// arithmetic, comparison doesn't round an exact number to a float, so no
+/// two different integers both equal the same float.
+pub fn compare(a: &Value, b: &Value) -> Result<Option<Ordering>, EvalError> {
+    for value in [a, b] {
+        if !value.is_number() {
+            return Err(value.type_mismatch("number"));
+        }
+    }
+    match (a, b) {
+        (Value::Integer(x), Value::Integer(y)) => Ok(Some(x.cmp(y))),
+        (Value::Float(x), Value::Float(y)) => Ok(x.partial_cmp(y)),
+        (exact, Value::Float(y)) => compare_to_float(exact, *y),
+        (Value::Float(x), exact) => Ok(compare_to_float(exact, *x)?.map(Ordering::reverse)),
+        _ => match ratios(a, b)? {
+            Some((x, y)) => Ok(Some(x.cmp(&y))),
+            None => Ok(Some(to_big(a)?.cmp(&to_big(b)?))),
+        },
+    }
+}
+
+/// Orders an exact number against a float.
+fn compare_to_float(exact: &Value, float: f64) -> Result<Option<Ordering>, EvalError> {
+    // Integers this small convert to floats exactly.
+    const EXACT_FLOAT: u64 = 1 << 53;
+    match (exact, Ratio::from_f64(float)) {
+        (Value::Integer(int), _) if int.unsigned_abs() <= EXACT_FLOAT => {
+            Ok((*int as f64).partial_cmp(&float))
+        }
+        (_, Some(float)) => Ok(Some(to_ratio(exact)?.cmp(&float))),
+        _ if float.is_nan() => Ok(None),
+        _ if float > 0.0 => Ok(Some(Ordering::Less)),
+        _ => Ok(Some(Ordering::Greater)),
+    }
+}
+
+pub fn is_nan(value: &Value) -> bool {
+    matches!(value, Value::Float(float) if float.is_nan())
+}
+
+/// Orders two numbers for sorting and map keys: as `compare` does, with NaN
+/// equal to itself and above every other number.
+pub fn total_cmp(a: &Value, b: &Value) -> Ordering {
+    match (is_nan(a), is_nan(b)) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => compare(a, b).ok().flatten().unwrap_or(Ordering::Equal),
+    }
+}
+
+pub fn abs(value: &Value) -> Result<Value, EvalError> {
+    match value {
+        Value::Float(float) => Ok(Value::Float(float.abs())),
+        _ if compare(value, &Value::Integer(0))? == Some(Ordering::Less) => {
+            sub(&Value::Integer(0), value)
+        }
+        _ => Ok(value.clone()),
+    }
+}
+
+/// The largest exact power `pow` computes, in bits of its numerator or
+/// denominator.
+const MAX_POWER_BITS: u64 = 1 << 16;
+
+/// `floor(log2(|value|))` of the larger of a ratio's parts, or of an
+/// integer, which is how many bits each factor adds to a power of it.
+fn growth_bits(value: &Value) -> Result<u64, EvalError> {
+    let bits = match value {
+        Value::Ratio(ratio) => ratio.numer().bits().max(ratio.denom().bits()),
+        _ => to_big(value)?.bits(),
+    };
+    Ok(bits.saturating_sub(1))
+}
+
+/// Raises `base` to `exponent`, exactly when the base is rational and the
+/// exponent an integer, and in floating point otherwise. An exact result of
+/// more than `MAX_POWER_BITS` is an `IntegerOverflow`, caught before any
+/// multiplying.
+pub fn pow(base: &Value, exponent: &Value) -> Result<Value, EvalError> {
+    match (base, exponent) {
+        (Value::Integer(_), Value::Integer(exponent))
+        | (Value::BigInt(_), Value::Integer(exponent))
+        | (Value::Ratio(_), Value::Integer(exponent)) => {
+            if growth_bits(base)?.saturating_mul(exponent.unsigned_abs()) > MAX_POWER_BITS {
+                return Err(EvalErrorKind::IntegerOverflow.into());
+            }
+            let mut result = Value::Integer(1);
+            let mut square = base.clone();
+            let mut remaining = exponent.unsigned_abs();
+            while remaining > 0 {
+                if remaining & 1 == 1 {
+                    result = mul(&result, &square)?;
+                }
+                remaining >>= 1;
+                if remaining > 0 {
+                    square = mul(&square, &square)?;
+                }
+            }
+            if *exponent < 0 {
+                div(&Value::Integer(1), &result)
+            } else {
+                Ok(result)
+            }
+        }
+        _ => Ok(Value::Float(to_f64(base)?.powf(to_f64(exponent)?))),
+    }
+}
+
+/// Rounds a number to a whole one: floats with `float` and stay floats,
+/// ratios with `exact` and become integers, and integers pass through.
+pub fn round_with(
+    value: Value,
+    float: fn(f64) -> f64,
+    exact: fn(&Ratio) -> BigInt,
+) -> Result<Value, EvalError> {
+    match &value {
+        Value::Float(x) => Ok(Value::Float(float(*x))),
+        Value::Ratio(ratio) => Ok(Value::from(exact(ratio))),
+        Value::Integer(_) | Value::BigInt(_) => Ok(value),
+        _ => Err(value.type_mismatch("number")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(text: &str) -> Value {
+        Value::from(BigInt::parse(text).unwrap())
+    }
+
+    #[test]
+    fn test_promotion() {
+        let max = Value::Integer(i64::MAX);
+        assert_eq!(
+            add(&max, &Value::Integer(1)),
+            Ok(big("9223372036854775808"))
+        );
+        assert_eq!(
+            sub(&Value::Integer(i64::MIN), &Value::Integer(1)),
+            Ok(big("-9223372036854775809"))
+        );
+        assert_eq!(
+            mul(&max, &max),
+            Ok(big("85070591730234615847396907784232501249"))
+        );
+        assert_eq!(
+            div(&Value::Integer(i64::MIN), &Value::Integer(-1)),
+            Ok(big("9223372036854775808"))
+        );
+        assert_eq!(
+            rem(&Value::Integer(i64::MIN), &Value::Integer(-1)),
+            Ok(Value::Integer(0))
+        );
+    }
+
+    #[test]
+    fn test_demotion() {
+        let huge = big("9223372036854775808");
+        assert_eq!(sub(&huge, &Value::Integer(1)), Ok(Value::Integer(i64::MAX)));
+        assert_eq!(div(&huge, &huge), Ok(Value::Integer(1)));
+    }
+
+    #[test]
+    fn test_compare() {
+        let huge = big("9223372036854775808");
+        assert_eq!(
+            compare(&Value::Integer(i64::MAX), &huge),
+            Ok(Some(Ordering::Less))
+        );
+        assert_eq!(
+            compare(&huge, &Value::Integer(0)),
+            Ok(Some(Ordering::Greater))
+        );
+        assert_eq!(compare(&huge, &huge), Ok(Some(Ordering::Equal)));
+        assert_eq!(
+            compare(&Value::Float(0.5), &Value::Integer(1)),
+            Ok(Some(Ordering::Less))
+        );
+        assert_eq!(
+            compare(&Value::Float(f64::NAN), &Value::Integer(1)),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_mixed() {
+        assert_eq!(
+            add(&Value::Integer(1), &Value::Float(0.5)),
+            Ok(Value::Float(1.5))
+        );
+        assert_eq!(
+            div(&Value::Integer(7), &Value::Float(2.0)),
+            Ok(Value::Float(3.5))
+        );
+        assert_eq!(
+            div(&Value::Float(1.0), &Value::Integer(0)),
+            Ok(Value::Float(f64::INFINITY))
+        );
+        assert_eq!(
+            mul(&big("18446744073709551616"), &Value::Float(0.5)),
+            Ok(Value::Float(9223372036854775808.0))
+        );
+    }
+
+    #[test]
+    fn test_ratios() {
+        let third = div(&Value::Integer(1), &Value::Integer(3)).unwrap();
+        assert_eq!(third.to_string(), "1/3");
+        assert_eq!(mul(&third, &Value::Integer(3)), Ok(Value::Integer(1)));
+        assert_eq!(
+            add(&third, &Value::Float(0.5)),
+            Ok(Value::Float(1.0 / 3.0 + 0.5))
+        );
+        assert_eq!(
+            rem(
+                &div(&Value::Integer(7), &Value::Integer(2)).unwrap(),
+                &third
+            )
+            .map(|v| v.to_string()),
+            Ok("1/6".to_string())
+        );
+        assert_eq!(
+            compare(&third, &Value::Float(0.3)),
+            Ok(Some(Ordering::Greater))
+        );
+        assert_eq!(
+            div(&big("18446744073709551616"), &Value::Integer(4)),
+            Ok(big("4611686018427387904"))
+        );
+        assert_eq!(
+            div(&third, &Value::Integer(0)).map_err(|err| err.kind),
+            Err(EvalErrorKind::DivisionByZero)
+        );
+    }
+
+    #[test]
+    fn test_pow() {
+        assert_eq!(
+            pow(&Value::Integer(2), &Value::Integer(10)),
+            Ok(Value::Integer(1024))
+        );
+        assert_eq!(
+            pow(&Value::Integer(2), &Value::Integer(64)),
+            Ok(big("18446744073709551616"))
+        );
+        assert_eq!(
+            pow(&Value::Integer(2), &Value::Integer(-2)).map(|v| v.to_string()),
+            Ok("1/4".to_string())
+        );
+        assert_eq!(
+            pow(&Value::Float(9.0), &Value::Float(0.5)),
+            Ok(Value::Float(3.0))
+        );
+    }
+
+    #[test]
+    fn test_pow_limit() {
+        let too_large = |base: Value, exponent: i64| {
+            pow(&base, &Value::Integer(exponent)).map_err(|err| err.kind)
+                == Err(EvalErrorKind::IntegerOverflow)
+        };
+        assert!(too_large(Value::Integer(2), 1_000_000_000));
+        assert!(too_large(Value::Integer(-3), -1_000_000));
+        assert!(too_large(big("18446744073709551616"), 2_000));
+        let half = Value::from(Ratio::new(BigInt::from(1), BigInt::from(2)).unwrap());
+        assert!(too_large(half.clone(), i64::MAX));
+        assert!(!too_large(half, 1_000));
+        assert!(!too_large(Value::Integer(2), MAX_POWER_BITS as i64));
+        assert_eq!(
+            pow(&Value::Integer(-1), &Value::Integer(1_000_000_001)),
+            Ok(Value::Integer(-1))
+        );
+        assert_eq!(
+            pow(&Value::Integer(0), &Value::Integer(i64::MAX)),
+            Ok(Value::Integer(0))
+        );
+    }
+
+    #[test]
+    fn test_abs() {
+        assert_eq!(abs(&Value::Integer(-3)), Ok(Value::Integer(3)));
+        assert_eq!(
+            abs(&Value::Integer(i64::MIN)),
+            Ok(big("9223372036854775808"))
+        );
+        assert_eq!(abs(&Value::Float(-2.5)), Ok(Value::Float(2.5)));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        assert_eq!(
+            div(&big("9223372036854775808"), &Value::Integer(0)).map_err(|err| err.kind),
+            Err(EvalErrorKind::DivisionByZero)
+        );
+    }
+}
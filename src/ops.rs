@@ -1,125 +1,346 @@
-use crate::context::Context;
-use crate::expression::Expression;
+use crate::context::{Context, Frame, Layout};
+use crate::error::{Arity, EvalError, EvalErrorKind};
+use crate::expression::{eval_body, eval_body_tail, ExprKind, Expression, Tail};
+use crate::function::Function;
+use crate::intern::intern;
+use crate::macros::expand;
+use crate::numeric;
+use crate::ratio::Ratio;
 use crate::value::Value;
+use crate::vector::Vector;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
 
 pub trait Operation {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value;
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError>;
+
+    /// Evaluates the operation in tail position. Forms that evaluate one of
+    /// their arguments last, like `if`, pass that position on.
+    fn eval_tail(&self, args: &[Expression], context: &mut Context) -> Result<Tail, EvalError> {
+        self.eval(args, context).map(Tail::Value)
+    }
 }
 
-pub fn get_op(name: &str) -> Option<Box<dyn Operation>> {
+// Lets a standard operation be kept wherever a registered one can.
+impl Operation for &'static dyn Operation {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        (**self).eval(args, context)
+    }
+
+    fn eval_tail(&self, args: &[Expression], context: &mut Context) -> Result<Tail, EvalError> {
+        (**self).eval_tail(args, context)
+    }
+}
+
+/// The standard operation `name` refers to. Expressions resolve this once,
+/// when they are built, rather than on every call.
+pub fn get_op(name: &str) -> Option<&'static dyn Operation> {
     match name {
-        "+" => Option::Some(Box::new(OpAdd {})),
-        "*" => Option::Some(Box::new(OpMul {})),
-        "-" => Option::Some(Box::new(OpSub {})),
-        "/" => Option::Some(Box::new(OpDiv {})),
-        "%" => Option::Some(Box::new(OpMod {})),
-        "=" => Option::Some(Box::new(OpEq {})),
-        "<" => Option::Some(Box::new(OpLt {})),
-        ">" => Option::Some(Box::new(OpGt {})),
-        "and" => Option::Some(Box::new(OpAnd {})),
-        "or" => Option::Some(Box::new(OpOr {})),
-        "not" => Option::Some(Box::new(OpNot {})),
-        "print" => Option::Some(Box::new(OpPrint {})),
-        "if" => Option::Some(Box::new(OpIf {})),
-        "while" => Option::Some(Box::new(OpWhile {})),
-        "block" => Option::Some(Box::new(OpBlock {})),
-        "global" => Option::Some(Box::new(OpGlobal {})),
+        "+" => Option::Some(&OpAdd {}),
+        "*" => Option::Some(&OpMul {}),
+        "-" => Option::Some(&OpSub {}),
+        "/" => Option::Some(&OpDiv {}),
+        "%" => Option::Some(&OpMod {}),
+        "floor" => Option::Some(&OpFloor {}),
+        "ceil" => Option::Some(&OpCeil {}),
+        "round" => Option::Some(&OpRound {}),
+        "numerator" => Option::Some(&OpNumerator {}),
+        "denominator" => Option::Some(&OpDenominator {}),
+        "sqrt" => Option::Some(&OpSqrt {}),
+        "pow" => Option::Some(&OpPow {}),
+        "abs" => Option::Some(&OpAbs {}),
+        "min" => Option::Some(&OpMin {}),
+        "max" => Option::Some(&OpMax {}),
+        "=" => Option::Some(&OpEq {}),
+        "<" => Option::Some(&OpLt {}),
+        ">" => Option::Some(&OpGt {}),
+        "and" => Option::Some(&OpAnd {}),
+        "or" => Option::Some(&OpOr {}),
+        "not" => Option::Some(&OpNot {}),
+        "print" => Option::Some(&OpPrint {}),
+        "if" => Option::Some(&OpIf {}),
+        "while" => Option::Some(&OpWhile {}),
+        "block" => Option::Some(&OpBlock {}),
+        "global" => Option::Some(&OpGlobal {}),
+        "let" => Option::Some(&OpLet {}),
+        "set!" => Option::Some(&OpSet {}),
+        "lambda" => Option::Some(&OpLambda {}),
+        "defn" => Option::Some(&OpDefn {}),
+        "arg" => Option::Some(&OpArg {}),
+        "arg-count" => Option::Some(&OpArgCount {}),
+        "exit" => Option::Some(&OpExit {}),
+        "list" => Option::Some(&OpList {}),
+        "cons" => Option::Some(&OpCons {}),
+        "car" | "first" => Option::Some(&OpFirst {}),
+        "cdr" | "rest" => Option::Some(&OpRest {}),
+        "len" => Option::Some(&OpLen {}),
+        "nth" => Option::Some(&OpNth {}),
+        "append" => Option::Some(&OpAppend {}),
+        "reverse" => Option::Some(&OpReverse {}),
+        "empty?" => Option::Some(&OpEmpty {}),
+        "vector" => Option::Some(&OpVector {}),
+        "conj" => Option::Some(&OpConj {}),
+        "subvec" => Option::Some(&OpSubvec {}),
+        "hash-map" => Option::Some(&OpHashMap {}),
+        "get" => Option::Some(&OpGet {}),
+        "assoc" => Option::Some(&OpAssoc {}),
+        "dissoc" => Option::Some(&OpDissoc {}),
+        "keys" => Option::Some(&OpKeys {}),
+        "vals" => Option::Some(&OpVals {}),
+        "has-key?" => Option::Some(&OpHasKey {}),
+        "merge" => Option::Some(&OpMerge {}),
+        "str-len" => Option::Some(&OpStrLen {}),
+        "concat" => Option::Some(&OpConcat {}),
+        "substr" => Option::Some(&OpSubstr {}),
+        "split" => Option::Some(&OpSplit {}),
+        "join" => Option::Some(&OpJoin {}),
+        "upper" => Option::Some(&OpUpper {}),
+        "lower" => Option::Some(&OpLower {}),
+        "trim" => Option::Some(&OpTrim {}),
+        "contains?" => Option::Some(&OpContains {}),
+        "replace" => Option::Some(&OpReplace {}),
+        "format" => Option::Some(&OpFormat {}),
+        "quote" => Option::Some(&OpQuote {}),
+        "quasiquote" => Option::Some(&OpQuasiquote {}),
+        "eval" => Option::Some(&OpEval {}),
+        "defmacro" => Option::Some(&OpDefmacro {}),
+        "macroexpand" => Option::Some(&OpMacroexpand {}),
+        "gensym" => Option::Some(&OpGensym {}),
+        "atom" => Option::Some(&OpAtom {}),
+        "deref" => Option::Some(&OpDeref {}),
+        "reset!" => Option::Some(&OpReset {}),
+        "swap!" => Option::Some(&OpSwap {}),
+        "gc" => Option::Some(&OpGc {}),
+        "gc-stats" => Option::Some(&OpGcStats {}),
         _ => Option::None,
     }
 }
 
+fn check_arity(args: &[Expression], expected: Arity) -> Result<(), EvalError> {
+    if expected.accepts(args.len()) {
+        Ok(())
+    } else {
+        Err(EvalErrorKind::ArityMismatch {
+            expected,
+            actual: args.len(),
+        }
+        .into())
+    }
+}
+
 struct OpAdd {}
 
 impl Operation for OpAdd {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert!(args.len() > 0);
-        let mut sum = 0;
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::AtLeast(1))?;
+        let mut sum = Value::Integer(0);
         for val in args {
-            let val = val.eval(context);
-            sum += val.unwrap_integer();
+            sum = numeric::add(&sum, &val.eval_number(context)?)?;
         }
-        Value::Integer(sum)
+        Ok(sum)
     }
 }
 
 struct OpMul {}
 
 impl Operation for OpMul {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert!(args.len() > 0);
-        let mut product = 1;
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::AtLeast(1))?;
+        let mut product = Value::Integer(1);
         for val in args {
-            let val = val.eval(context);
-            product *= val.unwrap_integer();
+            product = numeric::mul(&product, &val.eval_number(context)?)?;
         }
-        Value::Integer(product)
+        Ok(product)
     }
 }
 
 struct OpSub {}
 
 impl Operation for OpSub {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert_eq!(args.len(), 2);
-        let left = args[0].eval(context).unwrap_integer();
-        let right = args[1].eval(context).unwrap_integer();
-        Value::Integer(left - right)
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let left = args[0].eval_number(context)?;
+        let right = args[1].eval_number(context)?;
+        numeric::sub(&left, &right)
     }
 }
 
 struct OpDiv {}
 
 impl Operation for OpDiv {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert_eq!(args.len(), 2);
-        let left = args[0].eval(context).unwrap_integer();
-        let right = args[1].eval(context).unwrap_integer();
-        Value::Integer(left / right)
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let left = args[0].eval_number(context)?;
+        let right = args[1].eval_number(context)?;
+        numeric::div(&left, &right).map_err(|err| err.or_span(args[1].span))
     }
 }
 
 struct OpMod {}
 
 impl Operation for OpMod {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert_eq!(args.len(), 2);
-        let left = args[0].eval(context).unwrap_integer();
-        let right = args[1].eval(context).unwrap_integer();
-        Value::Integer(left % right)
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let left = args[0].eval_number(context)?;
+        let right = args[1].eval_number(context)?;
+        numeric::rem(&left, &right).map_err(|err| err.or_span(args[1].span))
+    }
+}
+
+struct OpFloor {}
+
+impl Operation for OpFloor {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let value = args[0].eval_number(context)?;
+        numeric::round_with(value, f64::floor, Ratio::floor)
+    }
+}
+
+struct OpCeil {}
+
+impl Operation for OpCeil {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let value = args[0].eval_number(context)?;
+        numeric::round_with(value, f64::ceil, Ratio::ceil)
+    }
+}
+
+struct OpRound {}
+
+impl Operation for OpRound {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let value = args[0].eval_number(context)?;
+        numeric::round_with(value, f64::round, Ratio::round)
+    }
+}
+
+struct OpNumerator {}
+
+impl Operation for OpNumerator {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let value = args[0].eval(context)?;
+        let ratio = numeric::to_ratio(&value).map_err(|err| err.or_span(args[0].span))?;
+        Ok(Value::from(ratio.numer().clone()))
+    }
+}
+
+struct OpDenominator {}
+
+impl Operation for OpDenominator {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let value = args[0].eval(context)?;
+        let ratio = numeric::to_ratio(&value).map_err(|err| err.or_span(args[0].span))?;
+        Ok(Value::from(ratio.denom().clone()))
+    }
+}
+
+struct OpSqrt {}
+
+impl Operation for OpSqrt {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let value = args[0].eval_number(context)?;
+        Ok(Value::Float(numeric::to_f64(&value)?.sqrt()))
+    }
+}
+
+struct OpPow {}
+
+impl Operation for OpPow {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let base = args[0].eval_number(context)?;
+        let exponent = args[1].eval_number(context)?;
+        numeric::pow(&base, &exponent)
+    }
+}
+
+struct OpAbs {}
+
+impl Operation for OpAbs {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        numeric::abs(&args[0].eval_number(context)?)
+    }
+}
+
+/// Picks the argument that compares as `wanted` against every other one,
+/// keeping the earliest on ties. A NaN argument makes the result NaN, in
+/// whatever position it comes.
+fn extremum(
+    args: &[Expression],
+    context: &mut Context,
+    wanted: Ordering,
+) -> Result<Value, EvalError> {
+    check_arity(args, Arity::AtLeast(1))?;
+    let mut best = args[0].eval_number(context)?;
+    for arg in &args[1..] {
+        let value = arg.eval_number(context)?;
+        let replace = match numeric::compare(&value, &best)? {
+            Some(ordering) => ordering == wanted,
+            None => !numeric::is_nan(&best),
+        };
+        if replace {
+            best = value;
+        }
+    }
+    Ok(best)
+}
+
+struct OpMin {}
+
+impl Operation for OpMin {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        extremum(args, context, Ordering::Less)
+    }
+}
+
+struct OpMax {}
+
+impl Operation for OpMax {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        extremum(args, context, Ordering::Greater)
     }
 }
 
 struct OpPrint {}
 
 impl Operation for OpPrint {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert_eq!(args.len(), 1);
-        let val = args[0].eval(context);
-        if val.is_string() {
-            // Don't print quotes
-            println!("{}", val.unwrap_string());
-        } else {
-            println!("{}", val);
-        }
-        Value::Nil
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let val = args[0].eval(context)?;
+        context.print(&val.to_text())?;
+        Ok(Value::Nil)
     }
 }
 
 struct OpIf {}
 
 impl Operation for OpIf {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert!(args.len() >= 2 && args.len() <= 3);
-        let control = args[0].eval(context).unwrap_bool();
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        self.eval_tail(args, context)?.resolve(context)
+    }
+
+    fn eval_tail(&self, args: &[Expression], context: &mut Context) -> Result<Tail, EvalError> {
+        check_arity(args, Arity::Range(2, 3))?;
+        let control = args[0].eval_bool(context)?;
 
         if control {
-            return args[1].eval(context);
+            return args[1].eval_tail(context);
         }
 
         if args.len() == 3 {
-            args[2].eval(context)
+            args[2].eval_tail(context)
         } else {
-            Value::Nil
+            Ok(Tail::Value(Value::Nil))
         }
     }
 }
@@ -127,111 +348,1042 @@ impl Operation for OpIf {
 struct OpWhile {}
 
 impl Operation for OpWhile {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert_eq!(args.len(), 2);
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
         loop {
-            let control = args[0].eval(context).unwrap_bool();
+            let control = args[0].eval_bool(context)?;
             if !control {
                 break;
             }
-            args[1].eval(context);
+            args[1].eval(context)?;
         }
-        Value::Nil
+        Ok(Value::Nil)
     }
 }
 
 struct OpBlock {}
 
 impl Operation for OpBlock {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        let mut last_val = Value::Nil;
-
-        for arg in args {
-            last_val = arg.eval(context);
-        }
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        eval_body(args, context)
+    }
 
-        last_val
+    fn eval_tail(&self, args: &[Expression], context: &mut Context) -> Result<Tail, EvalError> {
+        eval_body_tail(args, context)
     }
 }
 
 struct OpGlobal {}
 
 impl Operation for OpGlobal {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert_eq!(args.len(), 2);
-        let global_name = if let Expression::Symbol(symbol) = &args[0] {
-            symbol.to_string()
-        } else {
-            panic!();
-        };
-        let value = args[1].eval(context);
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let global_name = args[0].as_symbol()?;
+        let value = args[1].eval(context)?;
         context.globals.insert(global_name, value);
 
-        Value::Nil
+        Ok(Value::Nil)
+    }
+}
+
+struct OpLet {}
+
+impl Operation for OpLet {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        self.eval_tail(args, context)?.resolve(context)
+    }
+
+    fn eval_tail(&self, args: &[Expression], context: &mut Context) -> Result<Tail, EvalError> {
+        check_arity(args, Arity::AtLeast(1))?;
+        let mut frame = Frame::new(context.scope.clone());
+        for binding in args[0].as_list()? {
+            let pair = binding.as_list()?;
+            if pair.len() != 2 {
+                return Err(EvalError::new(EvalErrorKind::ArityMismatch {
+                    expected: Arity::Exact(2),
+                    actual: pair.len(),
+                })
+                .or_span(binding.span));
+            }
+            let name = intern(pair[0].as_symbol()?);
+            let value = pair[1].eval(context)?;
+            frame.bind(name, value);
+        }
+        context.with_frame(frame, |context| eval_body_tail(&args[1..], context))
+    }
+}
+
+struct OpSet {}
+
+impl Operation for OpSet {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let name = args[0].as_symbol()?;
+        let value = args[1].eval(context)?;
+        if !context.assign(name, value) {
+            return Err(
+                EvalError::new(EvalErrorKind::UnboundSymbol(name.to_string()))
+                    .or_span(args[0].span),
+            );
+        }
+
+        Ok(Value::Nil)
+    }
+}
+
+fn make_function(
+    name: Option<String>,
+    params: &Expression,
+    body: &[Expression],
+    context: &Context,
+) -> Result<Function, EvalError> {
+    let mut names = vec![];
+    let mut rest = None;
+    let mut params = params.as_list()?.iter();
+    while let Some(param) = params.next() {
+        match param.as_symbol()? {
+            // `&` introduces a single trailing parameter that collects any
+            // remaining arguments into a list.
+            "&" => {
+                let name = params
+                    .next()
+                    .ok_or_else(|| param.syntax_error("a parameter name after `&`"))?;
+                rest = Some(intern(name.as_symbol()?));
+                if let Some(extra) = params.next() {
+                    return Err(extra.syntax_error("`)` after the rest parameter"));
+                }
+            }
+            name => names.push(intern(name)),
+        }
+    }
+    let layout = Layout::new(names.iter().chain(&rest).cloned());
+    Ok(Function {
+        name,
+        params: names,
+        rest,
+        layout,
+        body: body.to_vec(),
+        env: context.scope.clone(),
+        code: RefCell::new(None),
+    })
+}
+
+struct OpLambda {}
+
+impl Operation for OpLambda {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::AtLeast(2))?;
+        let function = make_function(None, &args[0], &args[1..], context)?;
+        Ok(Value::Function(Rc::new(function)))
+    }
+}
+
+struct OpDefn {}
+
+impl Operation for OpDefn {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::AtLeast(3))?;
+        let name = args[0].as_symbol()?.to_string();
+        let function = make_function(Some(name.clone()), &args[1], &args[2..], context)?;
+        context
+            .globals
+            .insert(&name, Value::Function(Rc::new(function)));
+
+        Ok(Value::Nil)
     }
 }
 
 struct OpEq {}
 
 impl Operation for OpEq {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert_eq!(args.len(), 2);
-        let left = args[0].eval(context);
-        let right = args[1].eval(context);
-        Value::Bool(left == right)
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let left = args[0].eval(context)?;
+        let right = args[1].eval(context)?;
+        Ok(Value::Bool(left == right))
     }
 }
 
 struct OpLt {}
 
 impl Operation for OpLt {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert_eq!(args.len(), 2);
-        let left = args[0].eval(context).unwrap_integer();
-        let right = args[1].eval(context).unwrap_integer();
-        Value::Bool(left < right)
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let left = args[0].eval_number(context)?;
+        let right = args[1].eval_number(context)?;
+        Ok(Value::Bool(
+            numeric::compare(&left, &right)? == Some(Ordering::Less),
+        ))
     }
 }
 
 struct OpGt {}
 
 impl Operation for OpGt {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert_eq!(args.len(), 2);
-        let left = args[0].eval(context).unwrap_integer();
-        let right = args[1].eval(context).unwrap_integer();
-        Value::Bool(left > right)
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let left = args[0].eval_number(context)?;
+        let right = args[1].eval_number(context)?;
+        Ok(Value::Bool(
+            numeric::compare(&left, &right)? == Some(Ordering::Greater),
+        ))
     }
 }
 
 struct OpAnd {}
 
 impl Operation for OpAnd {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert_eq!(args.len(), 2);
-        let left = args[0].eval(context).unwrap_bool();
-        let right = args[1].eval(context).unwrap_bool();
-        Value::Bool(left && right)
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let left = args[0].eval_bool(context)?;
+        let right = args[1].eval_bool(context)?;
+        Ok(Value::Bool(left && right))
     }
 }
 
 struct OpOr {}
 
 impl Operation for OpOr {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert_eq!(args.len(), 2);
-        let left = args[0].eval(context).unwrap_bool();
-        let right = args[1].eval(context).unwrap_bool();
-        Value::Bool(left || right)
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let left = args[0].eval_bool(context)?;
+        let right = args[1].eval_bool(context)?;
+        Ok(Value::Bool(left || right))
     }
 }
 
 struct OpNot {}
 
 impl Operation for OpNot {
-    fn eval(&self, args: &[Expression], context: &mut Context) -> Value {
-        assert_eq!(args.len(), 1);
-        let val = args[0].eval(context).unwrap_bool();
-        Value::Bool(!val)
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let val = args[0].eval_bool(context)?;
+        Ok(Value::Bool(!val))
+    }
+}
+
+struct OpArg {}
+
+impl Operation for OpArg {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let index = args[0].eval_integer(context)?;
+        let arg = usize::try_from(index)
+            .ok()
+            .and_then(|index| context.args.get(index));
+        Ok(match arg {
+            Some(arg) => Value::String(arg.as_str().into()),
+            None => Value::Nil,
+        })
+    }
+}
+
+struct OpArgCount {}
+
+impl Operation for OpArgCount {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(0))?;
+        let count =
+            i64::try_from(context.args.len()).map_err(|_| EvalErrorKind::IntegerOverflow)?;
+        Ok(Value::Integer(count))
+    }
+}
+
+struct OpExit {}
+
+impl Operation for OpExit {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Range(0, 1))?;
+        let code = match args.first() {
+            Some(arg) => {
+                let code = arg.eval_integer(context)?;
+                if !(0..=255).contains(&code) {
+                    return Err(
+                        EvalError::new(EvalErrorKind::InvalidExitCode(code)).or_span(arg.span)
+                    );
+                }
+                code as i32
+            }
+            None => 0,
+        };
+        Err(EvalErrorKind::Exit(code).into())
+    }
+}
+
+struct OpList {}
+
+impl Operation for OpList {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        let mut items = Vec::with_capacity(args.len());
+        for arg in args {
+            items.push(arg.eval(context)?);
+        }
+        Ok(Value::List(Rc::new(items)))
+    }
+}
+
+struct OpCons {}
+
+impl Operation for OpCons {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let head = args[0].eval(context)?;
+        let mut items = Rc::unwrap_or_clone(args[1].eval_list(context)?);
+        items.insert(0, head);
+        Ok(Value::List(Rc::new(items)))
+    }
+}
+
+struct OpFirst {}
+
+impl Operation for OpFirst {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let first = match &args[0].eval(context)? {
+            Value::List(items) => items.first().cloned(),
+            Value::Vector(items) => items.get(0).cloned(),
+            value => return Err(value.type_mismatch("list").or_span(args[0].span)),
+        };
+        Ok(first.unwrap_or(Value::Nil))
+    }
+}
+
+struct OpRest {}
+
+impl Operation for OpRest {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let rest = match &args[0].eval(context)? {
+            Value::List(items) => items.iter().skip(1).cloned().collect(),
+            Value::Vector(items) => items.iter().skip(1).cloned().collect(),
+            value => return Err(value.type_mismatch("list").or_span(args[0].span)),
+        };
+        Ok(Value::List(Rc::new(rest)))
+    }
+}
+
+/// The number of items in a list, vector or map.
+fn count(value: &Value) -> Result<usize, EvalError> {
+    match value {
+        Value::List(items) => Ok(items.len()),
+        Value::Vector(items) => Ok(items.len()),
+        Value::Map(entries) => Ok(entries.len()),
+        _ => Err(value.type_mismatch("list")),
+    }
+}
+
+fn index_out_of_bounds(index: i64, len: usize, arg: &Expression) -> EvalError {
+    EvalError::new(EvalErrorKind::IndexOutOfBounds { index, len }).or_span(arg.span)
+}
+
+struct OpLen {}
+
+impl Operation for OpLen {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let value = args[0].eval(context)?;
+        let len = count(&value).map_err(|err| err.or_span(args[0].span))?;
+        let len = i64::try_from(len).map_err(|_| EvalErrorKind::IntegerOverflow)?;
+        Ok(Value::Integer(len))
+    }
+}
+
+struct OpNth {}
+
+impl Operation for OpNth {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let value = args[0].eval(context)?;
+        let index = args[1].eval_integer(context)?;
+        let item = match &value {
+            Value::Vector(items) => usize::try_from(index)
+                .ok()
+                .and_then(|index| items.get(index))
+                .cloned(),
+            Value::List(items) => usize::try_from(index)
+                .ok()
+                .and_then(|index| items.get(index))
+                .cloned(),
+            _ => return Err(value.type_mismatch("list").or_span(args[0].span)),
+        };
+        let len = count(&value)?;
+        item.ok_or_else(|| index_out_of_bounds(index, len, &args[1]))
+    }
+}
+
+struct OpAppend {}
+
+impl Operation for OpAppend {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        let mut items = Vec::new();
+        for arg in args {
+            items.extend(arg.eval_list(context)?.iter().cloned());
+        }
+        Ok(Value::List(Rc::new(items)))
+    }
+}
+
+struct OpReverse {}
+
+impl Operation for OpReverse {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let mut items = Rc::unwrap_or_clone(args[0].eval_list(context)?);
+        items.reverse();
+        Ok(Value::List(Rc::new(items)))
+    }
+}
+
+struct OpEmpty {}
+
+impl Operation for OpEmpty {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let value = args[0].eval(context)?;
+        let len = count(&value).map_err(|err| err.or_span(args[0].span))?;
+        Ok(Value::Bool(len == 0))
+    }
+}
+
+struct OpVector {}
+
+impl Operation for OpVector {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        let mut items = Vector::new();
+        for arg in args {
+            items = items.push(arg.eval(context)?);
+        }
+        Ok(Value::Vector(items))
+    }
+}
+
+struct OpConj {}
+
+impl Operation for OpConj {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::AtLeast(1))?;
+        let mut items = args[0].eval_vector(context)?;
+        for arg in &args[1..] {
+            items = items.push(arg.eval(context)?);
+        }
+        Ok(Value::Vector(items))
+    }
+}
+
+struct OpSubvec {}
+
+impl Operation for OpSubvec {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Range(2, 3))?;
+        let items = args[0].eval_vector(context)?;
+        let start = args[1].eval_integer(context)?;
+        let end = match args.get(2) {
+            Some(arg) => arg.eval_integer(context)?,
+            None => i64::try_from(items.len()).map_err(|_| EvalErrorKind::IntegerOverflow)?,
+        };
+        let bound = |index: i64, arg: &Expression| {
+            usize::try_from(index)
+                .ok()
+                .filter(|index| *index <= items.len())
+                .ok_or_else(|| index_out_of_bounds(index, items.len(), arg))
+        };
+        let start_index = bound(start, &args[1])?;
+        let end_index = bound(end, args.get(2).unwrap_or(&args[1]))?;
+        // A start past the end is reported against the whole vector.
+        items
+            .slice(start_index, end_index)
+            .map(Value::Vector)
+            .ok_or_else(|| index_out_of_bounds(start, items.len(), &args[1]))
+    }
+}
+
+/// Evaluates alternating keys and values into `entries`, with later
+/// entries replacing earlier ones for the same key.
+fn insert_pairs(
+    entries: &mut BTreeMap<Value, Value>,
+    args: &[Expression],
+    context: &mut Context,
+) -> Result<(), EvalError> {
+    if !args.len().is_multiple_of(2) {
+        return Err(EvalErrorKind::InvalidSyntax {
+            expected: "a value for every key",
+        }
+        .into());
+    }
+    for pair in args.chunks(2) {
+        let key = pair[0].eval(context)?;
+        let value = pair[1].eval(context)?;
+        entries.insert(key, value);
+    }
+    Ok(())
+}
+
+struct OpHashMap {}
+
+impl Operation for OpHashMap {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        let mut entries = BTreeMap::new();
+        insert_pairs(&mut entries, args, context)?;
+        Ok(Value::Map(Rc::new(entries)))
+    }
+}
+
+struct OpGet {}
+
+impl Operation for OpGet {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Range(2, 3))?;
+        let entries = args[0].eval_map(context)?;
+        let key = args[1].eval(context)?;
+        match entries.get(&key) {
+            Some(value) => Ok(value.clone()),
+            None => match args.get(2) {
+                Some(default) => default.eval(context),
+                None => Ok(Value::Nil),
+            },
+        }
+    }
+}
+
+struct OpAssoc {}
+
+impl Operation for OpAssoc {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::AtLeast(3))?;
+        let target = args[0].eval(context)?;
+        match target {
+            Value::Map(_) => {
+                let mut entries = target.into_map()?;
+                insert_pairs(Rc::make_mut(&mut entries), &args[1..], context)?;
+                Ok(Value::Map(entries))
+            }
+            Value::Vector(ref items) => {
+                let mut items = items.clone();
+                if !args[1..].len().is_multiple_of(2) {
+                    return Err(EvalErrorKind::InvalidSyntax {
+                        expected: "a value for every index",
+                    }
+                    .into());
+                }
+                for pair in args[1..].chunks(2) {
+                    let index = pair[0].eval_integer(context)?;
+                    let value = pair[1].eval(context)?;
+                    // Assigning one past the end appends, as `conj` would.
+                    items = match usize::try_from(index) {
+                        Ok(i) if i == items.len() => Some(items.push(value)),
+                        Ok(i) => items.set(i, value),
+                        Err(_) => None,
+                    }
+                    .ok_or_else(|| index_out_of_bounds(index, items.len(), &pair[0]))?;
+                }
+                Ok(Value::Vector(items))
+            }
+            value => Err(value.type_mismatch("map").or_span(args[0].span)),
+        }
+    }
+}
+
+struct OpDissoc {}
+
+impl Operation for OpDissoc {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::AtLeast(1))?;
+        let mut entries = args[0].eval_map(context)?;
+        for arg in &args[1..] {
+            let key = arg.eval(context)?;
+            if entries.contains_key(&key) {
+                Rc::make_mut(&mut entries).remove(&key);
+            }
+        }
+        Ok(Value::Map(entries))
+    }
+}
+
+struct OpKeys {}
+
+impl Operation for OpKeys {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let entries = args[0].eval_map(context)?;
+        Ok(Value::List(Rc::new(entries.keys().cloned().collect())))
+    }
+}
+
+struct OpVals {}
+
+impl Operation for OpVals {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let entries = args[0].eval_map(context)?;
+        Ok(Value::List(Rc::new(entries.values().cloned().collect())))
+    }
+}
+
+struct OpHasKey {}
+
+impl Operation for OpHasKey {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let entries = args[0].eval_map(context)?;
+        let key = args[1].eval(context)?;
+        Ok(Value::Bool(entries.contains_key(&key)))
+    }
+}
+
+struct OpMerge {}
+
+impl Operation for OpMerge {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::AtLeast(1))?;
+        let mut entries = args[0].eval_map(context)?;
+        for arg in &args[1..] {
+            let other = arg.eval_map(context)?;
+            Rc::make_mut(&mut entries).extend(other.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        Ok(Value::Map(entries))
+    }
+}
+
+struct OpStrLen {}
+
+impl Operation for OpStrLen {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let string = args[0].eval_string(context)?;
+        let len =
+            i64::try_from(string.chars().count()).map_err(|_| EvalErrorKind::IntegerOverflow)?;
+        Ok(Value::Integer(len))
+    }
+}
+
+struct OpConcat {}
+
+impl Operation for OpConcat {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        let mut out = String::new();
+        for arg in args {
+            out.push_str(&arg.eval_string(context)?);
+        }
+        Ok(Value::String(out.into()))
+    }
+}
+
+/// Converts a character index for a string of `len` characters, allowing
+/// the one-past-the-end position.
+fn char_index(arg: &Expression, context: &mut Context, len: usize) -> Result<usize, EvalError> {
+    let index = arg.eval_integer(context)?;
+    usize::try_from(index)
+        .ok()
+        .filter(|index| *index <= len)
+        .ok_or_else(|| {
+            EvalError::new(EvalErrorKind::IndexOutOfBounds { index, len }).or_span(arg.span)
+        })
+}
+
+struct OpSubstr {}
+
+impl Operation for OpSubstr {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Range(2, 3))?;
+        let string = args[0].eval_string(context)?;
+        let len = string.chars().count();
+        let start = char_index(&args[1], context, len)?;
+        let end = match args.get(2) {
+            Some(arg) => char_index(arg, context, len)?,
+            None => len,
+        };
+        // A start past the end is reported against the whole string, as
+        // `subvec` does.
+        if end < start {
+            let start = i64::try_from(start).map_err(|_| EvalErrorKind::IntegerOverflow)?;
+            return Err(index_out_of_bounds(start, len, &args[1]));
+        }
+        Ok(Value::String(
+            string
+                .chars()
+                .skip(start)
+                .take(end - start)
+                .collect::<String>()
+                .into(),
+        ))
+    }
+}
+
+struct OpSplit {}
+
+impl Operation for OpSplit {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let string = args[0].eval_string(context)?;
+        let separator = args[1].eval_string(context)?;
+        let parts: Vec<Value> = if separator.is_empty() {
+            string
+                .chars()
+                .map(|c| Value::String(c.to_string().into()))
+                .collect()
+        } else {
+            string
+                .split(&*separator)
+                .map(|part| Value::String(part.into()))
+                .collect()
+        };
+        Ok(Value::List(Rc::new(parts)))
+    }
+}
+
+struct OpJoin {}
+
+impl Operation for OpJoin {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Range(1, 2))?;
+        let items = args[0].eval_list(context)?;
+        let separator = match args.get(1) {
+            Some(arg) => arg.eval_string(context)?,
+            None => "".into(),
+        };
+        let parts: Vec<String> = items.iter().map(Value::to_text).collect();
+        Ok(Value::String(parts.join(&separator).into()))
+    }
+}
+
+struct OpUpper {}
+
+impl Operation for OpUpper {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        Ok(Value::String(
+            args[0].eval_string(context)?.to_uppercase().into(),
+        ))
+    }
+}
+
+struct OpLower {}
+
+impl Operation for OpLower {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        Ok(Value::String(
+            args[0].eval_string(context)?.to_lowercase().into(),
+        ))
+    }
+}
+
+struct OpTrim {}
+
+impl Operation for OpTrim {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let string = args[0].eval_string(context)?;
+        Ok(Value::String(string.trim().into()))
+    }
+}
+
+struct OpContains {}
+
+impl Operation for OpContains {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let string = args[0].eval_string(context)?;
+        let needle = args[1].eval_string(context)?;
+        Ok(Value::Bool(string.contains(&*needle)))
+    }
+}
+
+struct OpReplace {}
+
+impl Operation for OpReplace {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(3))?;
+        let string = args[0].eval_string(context)?;
+        let from = args[1].eval_string(context)?;
+        let to = args[2].eval_string(context)?;
+        if from.is_empty() {
+            return Ok(Value::String(string));
+        }
+        Ok(Value::String(string.replace(&*from, &to).into()))
+    }
+}
+
+/// Fills each `{}` in the template with the next argument as `print` would
+/// show it. `{{` and `}}` stand for literal braces.
+struct OpFormat {}
+
+impl Operation for OpFormat {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::AtLeast(1))?;
+        let template = args[0].eval_string(context)?;
+        let mut out = String::new();
+        let mut next = 1;
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('{', Some('{')) | ('}', Some('}')) => {
+                    chars.next();
+                    out.push(c);
+                }
+                ('{', Some('}')) => {
+                    chars.next();
+                    if let Some(arg) = args.get(next) {
+                        out.push_str(&arg.eval(context)?.to_text());
+                    }
+                    next += 1;
+                }
+                _ => out.push(c),
+            }
+        }
+        if next != args.len() {
+            return Err(EvalErrorKind::ArityMismatch {
+                expected: Arity::Exact(next),
+                actual: args.len(),
+            }
+            .into());
+        }
+        Ok(Value::String(out.into()))
+    }
+}
+
+struct OpQuote {}
+
+impl Operation for OpQuote {
+    fn eval(&self, args: &[Expression], _context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        Ok(args[0].to_value())
+    }
+}
+
+/// If `expr` is a `(name arg)` form, returns `arg`.
+fn unquoted<'a>(expr: &'a Expression, name: &str) -> Option<&'a Expression> {
+    match &expr.kind {
+        ExprKind::List(items) if items.len() == 2 => match &items[0].kind {
+            ExprKind::Symbol(head) if &**head == name => Some(&items[1]),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Evaluates the template `expr`, nested `level` quasiquotes deep. Only
+/// unquotes at level 1 are evaluated; deeper ones are kept, one level less
+/// deep, for the inner quasiquote to evaluate later.
+fn quasiquote(expr: &Expression, level: usize, context: &mut Context) -> Result<Value, EvalError> {
+    if level == 1 {
+        if let Some(arg) = unquoted(expr, "unquote") {
+            return arg.eval(context);
+        }
+    }
+    let items = match &expr.kind {
+        ExprKind::List(items) => items,
+        ExprKind::Vector(items) => {
+            let items = quasiquote_items(items, level, context)?;
+            return Ok(Value::Vector(items.into_iter().collect()));
+        }
+        ExprKind::Map(entries) => {
+            let mut map = BTreeMap::new();
+            for (key, value) in entries {
+                map.insert(
+                    quasiquote(key, level, context)?,
+                    quasiquote(value, level, context)?,
+                );
+            }
+            return Ok(Value::Map(Rc::new(map)));
+        }
+        _ => return Ok(expr.to_value()),
+    };
+    let level = match items.first().map(|head| &head.kind) {
+        Some(ExprKind::Symbol(name)) if &**name == "quasiquote" => level + 1,
+        Some(ExprKind::Symbol(name))
+            if level > 1 && (&**name == "unquote" || &**name == "unquote-splicing") =>
+        {
+            level - 1
+        }
+        _ => level,
+    };
+    Ok(Value::List(Rc::new(quasiquote_items(
+        items, level, context,
+    )?)))
+}
+
+/// The items of a list or vector template, with those unquote-spliced at
+/// level 1 replaced by their elements.
+fn quasiquote_items(
+    items: &[Expression],
+    level: usize,
+    context: &mut Context,
+) -> Result<Vec<Value>, EvalError> {
+    let mut values = Vec::with_capacity(items.len());
+    for item in items {
+        match unquoted(item, "unquote-splicing") {
+            Some(arg) if level == 1 => values.extend(arg.eval_list(context)?.iter().cloned()),
+            _ => values.push(quasiquote(item, level, context)?),
+        }
+    }
+    Ok(values)
+}
+
+struct OpQuasiquote {}
+
+impl Operation for OpQuasiquote {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        quasiquote(&args[0], 1, context)
+    }
+}
+
+struct OpEval {}
+
+impl Operation for OpEval {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let form = args[0].eval(context)?;
+        expand(&Expression::from_value(&form, args[0].span), context)?.eval(context)
+    }
+}
+
+struct OpDefmacro {}
+
+impl Operation for OpDefmacro {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::AtLeast(3))?;
+        let name = args[0].as_symbol()?.to_string();
+        let function = make_function(Some(name.clone()), &args[1], &args[2..], context)?;
+        context.macros.insert(name, Rc::new(function));
+
+        Ok(Value::Nil)
+    }
+}
+
+struct OpMacroexpand {}
+
+impl Operation for OpMacroexpand {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let form = args[0].eval(context)?;
+        let expanded = expand(&Expression::from_value(&form, args[0].span), context)?;
+        Ok(expanded.to_value())
+    }
+}
+
+struct OpGensym {}
+
+impl Operation for OpGensym {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Range(0, 1))?;
+        let prefix = match args.first() {
+            Some(arg) => {
+                let value = arg.eval(context)?;
+                value
+                    .as_string()
+                    .map_err(|err| err.or_span(arg.span))?
+                    .to_string()
+            }
+            None => "G".to_string(),
+        };
+        context.gensym_counter += 1;
+        Ok(Value::Symbol(
+            format!("{}__{}", prefix, context.gensym_counter).into(),
+        ))
+    }
+}
+
+struct OpAtom {}
+
+impl Operation for OpAtom {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let value = args[0].eval(context)?;
+        Ok(Value::Atom(context.heap.alloc_atom(value)?))
+    }
+}
+
+fn eval_atom(arg: &Expression, context: &mut Context) -> Result<Rc<RefCell<Value>>, EvalError> {
+    let value = arg.eval(context)?;
+    value
+        .as_atom()
+        .cloned()
+        .map_err(|err| err.or_span(arg.span))
+}
+
+struct OpDeref {}
+
+impl Operation for OpDeref {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(1))?;
+        let atom = eval_atom(&args[0], context)?;
+        let value = atom.borrow().clone();
+        Ok(value)
+    }
+}
+
+struct OpReset {}
+
+impl Operation for OpReset {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(2))?;
+        let atom = eval_atom(&args[0], context)?;
+        let value = args[1].eval(context)?;
+        atom.replace(value.clone());
+        Ok(value)
+    }
+}
+
+struct OpSwap {}
+
+impl Operation for OpSwap {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::AtLeast(2))?;
+        let atom = eval_atom(&args[0], context)?;
+        let mut extra = Vec::with_capacity(args.len() - 2);
+        for arg in &args[2..] {
+            extra.push(Expression::new(
+                ExprKind::Value(arg.eval(context)?),
+                arg.span,
+            ));
+        }
+        // The function is applied like any call so builtins work too; it
+        // sees a snapshot of the value and may itself touch the atom.
+        let current = atom.borrow().clone();
+        let mut call = vec![
+            args[1].clone(),
+            Expression::new(ExprKind::Value(current), args[0].span),
+        ];
+        call.extend(extra);
+        let span = args[1].span.to(args[args.len() - 1].span);
+        let value = Expression::new(ExprKind::List(call), span).eval(context)?;
+        atom.replace(value.clone());
+        Ok(value)
+    }
+}
+
+struct OpGc {}
+
+impl Operation for OpGc {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(0))?;
+        Ok(Value::Integer(context.heap.collect() as i64))
+    }
+}
+
+struct OpGcStats {}
+
+impl Operation for OpGcStats {
+    fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+        check_arity(args, Arity::Exact(0))?;
+        let heap = &context.heap;
+        let limit = match heap.limit {
+            Some(limit) => Value::Integer(limit as i64),
+            None => Value::Nil,
+        };
+        let stats = vec![
+            ("live", Value::Integer(heap.live() as i64)),
+            ("collections", Value::Integer(heap.collections as i64)),
+            ("freed", Value::Integer(heap.freed as i64)),
+            ("limit", limit),
+        ];
+        Ok(Value::Map(Rc::new(
+            stats
+                .into_iter()
+                .map(|(key, value)| (Value::Keyword(key.into()), value))
+                .collect(),
+        )))
     }
 }
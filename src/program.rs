@@ -0,0 +1,234 @@
+use crate::context::Context;
+use crate::error::{Error, EvalError, ParseError};
+use crate::expression::Expression;
+use crate::macros::expand;
+use crate::value::Value;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug)]
+pub struct Program {
+    pub source: String,
+    pub forms: Vec<Expression>,
+}
+
+impl Program {
+    pub fn parse(source: &str) -> Result<Program, ParseError> {
+        Ok(Program {
+            source: source.to_string(),
+            forms: Expression::parse(source)?,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Program, Error> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|err| Error::Io(path.to_path_buf(), err))?;
+        match Expression::parse(&source) {
+            Ok(forms) => Ok(Program { source, forms }),
+            Err(err) => Err(Error::Parse(err, source)),
+        }
+    }
+
+    /// Macro-expands and evaluates each top-level form in order, returning
+    /// the value of the last one, or `nil` for an empty program. Macros are
+    /// available to the forms following their definition.
+    pub fn eval(&self, context: &mut Context) -> Result<Value, EvalError> {
+        let mut last_val = Value::Nil;
+
+        for form in &self.forms {
+            let form = expand(form, context)?;
+            last_val = context.eval(&form)?;
+        }
+
+        Ok(last_val)
+    }
+
+    pub fn run(&self, context: &mut Context) -> Result<Value, Error> {
+        self.eval(context)
+            .map_err(|err| Error::Eval(err, self.source.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Engine;
+    use crate::error::{EvalErrorKind, ParseErrorKind};
+    use crate::span::Span;
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    fn run(source: &str) -> Result<Value, EvalError> {
+        Program::parse(source).unwrap().eval(&mut Context::new())
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(run(""), Ok(Value::Nil));
+    }
+
+    #[test]
+    fn test_last_value() {
+        assert_eq!(run("1 2 (+ 1 2)"), Ok(Value::Integer(3)));
+    }
+
+    #[test]
+    fn test_shared_context() {
+        assert_eq!(
+            run("(global i 0)\n(while (< i 10) (global i (+ i 1)))\ni"),
+            Ok(Value::Integer(10))
+        );
+    }
+
+    #[test]
+    fn test_stops_at_error() {
+        let mut context = Context::new();
+        let program = Program::parse("(global a 1) (+ a true) (global a 2)").unwrap();
+        assert_eq!(
+            program.eval(&mut context).map_err(|err| err.kind),
+            Err(EvalErrorKind::TypeMismatch {
+                expected: "number",
+                actual: "bool"
+            })
+        );
+        assert_eq!(context.globals.get("a"), Some(&Value::Integer(1)));
+    }
+
+    #[test]
+    fn test_trailing_garbage() {
+        assert_eq!(
+            Program::parse("(print 1)\n(print 2))").unwrap_err(),
+            ParseError::new(
+                ParseErrorKind::UnmatchedClose(')'),
+                Span::new(19, 20, 2, 10)
+            )
+        );
+    }
+
+    /// Output that can still be read once a context owns it.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_fizzbuzz() {
+        let expected: String = (1..100)
+            .map(|i| match (i % 3, i % 5) {
+                (0, 0) => "FizzBuzz\n".to_string(),
+                (_, 0) => "Buzz\n".to_string(),
+                (0, _) => "Fizz\n".to_string(),
+                _ => format!("{}\n", i),
+            })
+            .collect();
+        let program = Program::load("fizzbuzz.lisplike").unwrap();
+        for engine in [Engine::Tree, Engine::Vm].iter() {
+            let output = SharedOutput::default();
+            let mut context = Context::new();
+            context.engine = *engine;
+            context.set_output(Box::new(output.clone()));
+            assert_eq!(program.eval(&mut context), Ok(Value::Nil));
+            assert!(context.globals.is_empty());
+            assert_eq!(String::from_utf8(output.0.take()).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_globals_share_heap_values() {
+        let mut context = Context::new();
+        Program::parse("(global s (concat \"abc\" \"def\")) (global xs (list 1 2 3))")
+            .unwrap()
+            .eval(&mut context)
+            .unwrap();
+        match (context.lookup("s"), context.globals.get("s")) {
+            (Some(Value::String(ref a)), Some(Value::String(b))) => assert!(Rc::ptr_eq(a, b)),
+            other => panic!("expected strings, got {:?}", other),
+        }
+        match (context.lookup("xs"), context.globals.get("xs")) {
+            (Some(Value::List(ref a)), Some(Value::List(b))) => assert!(Rc::ptr_eq(a, b)),
+            other => panic!("expected lists, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_only_names_are_interned() {
+        let value = run("(list \"literal-text\" 'symbol-text)").unwrap();
+        assert_eq!(crate::intern::lookup("literal-text"), None);
+        assert!(crate::intern::lookup("symbol-text").is_some());
+        drop(value);
+        assert_eq!(crate::intern::lookup("symbol-text"), None);
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_string_globals() {
+        let source = r#"
+            (global s "x")
+            (global i 0)
+            (while (< i 16) (block (global s (concat s s)) (global i (+ i 1))))
+            (global i 0)
+            (while (< i 20000)
+              (block (global t s) (global i (+ i 1))))
+            (str-len t)
+        "#;
+        let program = Program::parse(source).unwrap();
+        let start = Instant::now();
+        let result = program.eval(&mut Context::new()).unwrap();
+        let shared = start.elapsed();
+        assert_eq!(result, Value::Integer(65536));
+
+        // What every global read cost when strings were owned: a deep copy.
+        let s: String = "x".repeat(65536);
+        let start = Instant::now();
+        let mut total = 0;
+        for _ in 0..20000 {
+            total += s.clone().len();
+        }
+        let copied = start.elapsed();
+        assert_eq!(total, 20000 * 65536);
+
+        println!(
+            "shared reads: {:?}, deep copies alone: {:?}",
+            shared, copied
+        );
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_engines() {
+        let source = r#"
+            (let ((i 1) (fizz 0) (buzz 0) (fizzbuzz 0))
+              (while (< i 1000000)
+                (block
+                  (if (= 0 (% i 15)) (set! fizzbuzz (+ fizzbuzz 1))
+                    (if (= 0 (% i 5)) (set! buzz (+ buzz 1))
+                      (if (= 0 (% i 3)) (set! fizz (+ fizz 1)) nil)))
+                  (set! i (+ i 1))))
+              (list fizz buzz fizzbuzz))
+        "#;
+        let program = Program::parse(source).unwrap();
+        let mut times = Vec::new();
+        for engine in [Engine::Tree, Engine::Vm].iter() {
+            let mut context = Context::new();
+            context.engine = *engine;
+            let start = Instant::now();
+            let result = program.eval(&mut context).unwrap();
+            times.push(start.elapsed());
+            assert_eq!(result.to_string(), "(266667 133333 66666)");
+        }
+        println!("tree: {:?}, vm: {:?}", times[0], times[1]);
+    }
+}
@@ -0,0 +1,259 @@
+use crate::bigint::{gcd, BigInt};
+use std::cmp::Ordering;
+use std::fmt;
+
+/// An exact fraction kept in lowest terms with a positive denominator, so
+/// derived equality is structural.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ratio {
+    numer: BigInt,
+    denom: BigInt,
+}
+
+impl Ratio {
+    /// Returns `None` when the denominator is zero.
+    pub fn new(numer: BigInt, denom: BigInt) -> Option<Ratio> {
+        if denom.is_zero() {
+            return None;
+        }
+        let divisor = gcd(&numer, &denom);
+        let (mut numer, _) = numer.div_rem(&divisor)?;
+        let (mut denom, _) = denom.div_rem(&divisor)?;
+        if denom.is_negative() {
+            numer = -&numer;
+            denom = -&denom;
+        }
+        Some(Ratio { numer, denom })
+    }
+
+    /// The exact value of a finite float, or `None` for an infinity or NaN.
+    pub fn from_f64(float: f64) -> Option<Ratio> {
+        if !float.is_finite() {
+            return None;
+        }
+        let bits = float.to_bits();
+        let exponent = ((bits >> 52) & 0x7ff) as i32;
+        let fraction = bits & ((1 << 52) - 1);
+        // The float is `mantissa * 2^exponent`; subnormals have no implicit
+        // leading bit.
+        let (mantissa, exponent) = match exponent {
+            0 => (fraction, -1074),
+            _ => (fraction | 1 << 52, exponent - 1075),
+        };
+        let mut numer = BigInt::from_u64(mantissa);
+        if float < 0.0 {
+            numer = -&numer;
+        }
+        let scale = BigInt::power_of_two(exponent.unsigned_abs());
+        if exponent >= 0 {
+            Ratio::new(&numer * &scale, BigInt::from(1))
+        } else {
+            Ratio::new(numer, scale)
+        }
+    }
+
+    pub fn numer(&self) -> &BigInt {
+        &self.numer
+    }
+
+    pub fn denom(&self) -> &BigInt {
+        &self.denom
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.denom == BigInt::from(1)
+    }
+
+    /// The nearest float. Parts too large for a float are cut down to their
+    /// top 64 bits first and the quotient scaled back, so a ratio of two huge
+    /// parts isn't `inf / inf`.
+    pub fn to_f64(&self) -> f64 {
+        let numer_shift = self.numer.bits().saturating_sub(64);
+        let denom_shift = self.denom.bits().saturating_sub(64);
+        let numer = self.numer.shift_down(numer_shift).to_f64();
+        let denom = self.denom.shift_down(denom_shift).to_f64();
+        scale(numer / denom, numer_shift as i64 - denom_shift as i64)
+    }
+
+    pub fn add(&self, other: &Ratio) -> Ratio {
+        let numer = &(&self.numer * &other.denom) + &(&other.numer * &self.denom);
+        Ratio::new(numer, &self.denom * &other.denom).unwrap()
+    }
+
+    pub fn sub(&self, other: &Ratio) -> Ratio {
+        self.add(&Ratio {
+            numer: -&other.numer,
+            denom: other.denom.clone(),
+        })
+    }
+
+    pub fn mul(&self, other: &Ratio) -> Ratio {
+        Ratio::new(&self.numer * &other.numer, &self.denom * &other.denom).unwrap()
+    }
+
+    /// Returns `None` when dividing by zero.
+    pub fn div(&self, other: &Ratio) -> Option<Ratio> {
+        Ratio::new(&self.numer * &other.denom, &self.denom * &other.numer)
+    }
+
+    /// The integer part, rounding towards zero.
+    pub fn trunc(&self) -> BigInt {
+        self.numer.div_rem(&self.denom).unwrap().0
+    }
+
+    pub fn floor(&self) -> BigInt {
+        let (quotient, remainder) = self.numer.div_rem(&self.denom).unwrap();
+        if remainder.is_negative() {
+            &quotient - &BigInt::from(1)
+        } else {
+            quotient
+        }
+    }
+
+    pub fn ceil(&self) -> BigInt {
+        -&Ratio {
+            numer: -&self.numer,
+            denom: self.denom.clone(),
+        }
+        .floor()
+    }
+
+    /// Rounds to the nearest integer, with halves away from zero.
+    pub fn round(&self) -> BigInt {
+        let half = Ratio::from(BigInt::from(1))
+            .div(&Ratio::from(BigInt::from(2)))
+            .unwrap();
+        if self.numer.is_negative() {
+            self.sub(&half).ceil()
+        } else {
+            self.add(&half).floor()
+        }
+    }
+}
+
+impl From<BigInt> for Ratio {
+    fn from(int: BigInt) -> Self {
+        Ratio {
+            numer: int,
+            denom: BigInt::from(1),
+        }
+    }
+}
+
+impl Ord for Ratio {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.numer * &other.denom).cmp(&(&other.numer * &self.denom))
+    }
+}
+
+impl PartialOrd for Ratio {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// `value * 2^exponent`, in steps small enough that no power of two
+/// overflows a float on its own.
+fn scale(mut value: f64, mut exponent: i64) -> f64 {
+    while exponent != 0 && value != 0.0 && value.is_finite() {
+        let step = exponent.clamp(-1000, 1000);
+        value *= 2f64.powi(step as i32);
+        exponent -= step;
+    }
+    value
+}
+
+impl fmt::Display for Ratio {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.numer, self.denom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ratio(numer: i64, denom: i64) -> Ratio {
+        Ratio::new(BigInt::from(numer), BigInt::from(denom)).unwrap()
+    }
+
+    #[test]
+    fn test_normalization() {
+        assert_eq!(ratio(2, 4), ratio(1, 2));
+        assert_eq!(ratio(3, -6).to_string(), "-1/2");
+        assert_eq!(ratio(0, -5).to_string(), "0/1");
+        assert!(ratio(6, 3).is_integer());
+        assert_eq!(Ratio::new(BigInt::from(1), BigInt::zero()), None);
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(ratio(1, 3).add(&ratio(1, 6)), ratio(1, 2));
+        assert_eq!(ratio(1, 3).sub(&ratio(1, 2)), ratio(-1, 6));
+        assert_eq!(ratio(2, 3).mul(&ratio(3, 4)), ratio(1, 2));
+        assert_eq!(ratio(1, 2).div(&ratio(-1, 4)), Some(ratio(-2, 1)));
+        assert_eq!(ratio(1, 2).div(&ratio(0, 1)), None);
+    }
+
+    #[test]
+    fn test_to_f64() {
+        assert_eq!(ratio(-3, 4).to_f64(), -0.75);
+        let big = BigInt::parse(&format!("1{}", "0".repeat(400))).unwrap();
+        let ratio = Ratio::new(big.clone(), &big + &BigInt::from(1)).unwrap();
+        assert_eq!(ratio.to_f64(), 1.0);
+        let ratio = Ratio::new(BigInt::from(1), &big * &BigInt::from(3)).unwrap();
+        assert_eq!(ratio.to_f64(), 0.0);
+        let ratio = Ratio::new(&big * &BigInt::from(3), BigInt::from(2)).unwrap();
+        assert_eq!(ratio.to_f64(), f64::INFINITY);
+        let ratio = Ratio::new(BigInt::from(1), BigInt::power_of_two(1070)).unwrap();
+        assert_eq!(ratio.to_f64(), f64::from_bits(1 << 4));
+    }
+
+    #[test]
+    fn test_large_parts() {
+        // Reducing 3^20000 / 2^30000 once took minutes.
+        let start = std::time::Instant::now();
+        let mut numer = BigInt::from(1);
+        for _ in 0..20000 {
+            numer = &numer * &BigInt::from(3);
+        }
+        let denom = BigInt::power_of_two(30000);
+        let ratio = Ratio::new(numer.clone(), denom.clone()).unwrap();
+        assert_eq!((ratio.numer(), ratio.denom()), (&numer, &denom));
+        assert!(start.elapsed().as_secs() < 10);
+    }
+
+    #[test]
+    fn test_rounding() {
+        assert_eq!(ratio(-7, 2).trunc(), BigInt::from(-3));
+        assert_eq!(ratio(-7, 2).floor(), BigInt::from(-4));
+        assert_eq!(ratio(-7, 2).ceil(), BigInt::from(-3));
+        assert_eq!(ratio(7, 2).round(), BigInt::from(4));
+        assert_eq!(ratio(-7, 2).round(), BigInt::from(-4));
+        assert_eq!(ratio(-5, 3).round(), BigInt::from(-2));
+    }
+
+    #[test]
+    fn test_from_f64() {
+        assert_eq!(Ratio::from_f64(0.75), Some(ratio(3, 4)));
+        assert_eq!(Ratio::from_f64(-2.0), Some(ratio(-2, 1)));
+        assert_eq!(Ratio::from_f64(-0.0), Some(ratio(0, 1)));
+        assert_eq!(
+            Ratio::from_f64(9007199254740992.0),
+            Some(Ratio::from(BigInt::power_of_two(53)))
+        );
+        assert_eq!(
+            Ratio::from_f64(f64::MIN_POSITIVE / 4.0),
+            Ratio::new(BigInt::from(1), BigInt::power_of_two(1024))
+        );
+        assert_eq!(Ratio::from_f64(f64::INFINITY), None);
+        assert_eq!(Ratio::from_f64(f64::NAN), None);
+    }
+
+    #[test]
+    fn test_ordering() {
+        assert!(ratio(1, 3) < ratio(1, 2));
+        assert!(ratio(-1, 2) < ratio(-1, 3));
+        assert_eq!(ratio(2, 6).to_f64(), 1.0 / 3.0);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::context::Context;
+use crate::convert::IntoNative;
+use crate::error::EvalError;
+use crate::expression::{Expression, Tail};
+use crate::ops::Operation;
+use crate::value::Value;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+pub type NativeFn = dyn Fn(&[Value]) -> Result<Value, EvalError>;
+
+/// A builtin added by the host program.
+#[derive(Clone)]
+pub enum Native {
+    /// A function, given its arguments already evaluated.
+    Function(Rc<NativeFn>),
+    /// A special form, given its arguments unevaluated, as `if` is.
+    Form(Rc<dyn Operation>),
+}
+
+impl Native {
+    pub fn eval_tail(&self, args: &[Expression], context: &mut Context) -> Result<Tail, EvalError> {
+        match self {
+            Native::Function(function) => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(arg.eval(context)?);
+                }
+                function(&values).map(Tail::Value)
+            }
+            Native::Form(op) => op.eval_tail(args, context),
+        }
+    }
+}
+
+/// Builtins registered by the host. They take precedence over the standard
+/// builtins of the same name, and like those are hidden by user functions
+/// bound under it.
+#[derive(Default)]
+pub struct Registry {
+    natives: HashMap<String, Native>,
+    generation: usize,
+}
+
+impl Registry {
+    /// Registers a special form.
+    pub fn register(&mut self, name: &str, op: impl Operation + 'static) {
+        self.insert(name, Native::Form(Rc::new(op)));
+    }
+
+    /// Registers a function of evaluated arguments.
+    pub fn register_fn(
+        &mut self,
+        name: &str,
+        function: impl Fn(&[Value]) -> Result<Value, EvalError> + 'static,
+    ) {
+        self.insert(name, Native::Function(Rc::new(function)));
+    }
+
+    /// Registers a Rust function with typed arguments, such as
+    /// `|a: i64, b: i64| a + b`, converting its arguments with `FromValue`
+    /// and its result with `IntoValue`. A call with the wrong number of
+    /// arguments, or one that does not convert, is an error.
+    pub fn register_typed<Args>(&mut self, name: &str, function: impl IntoNative<Args>) {
+        self.insert(name, Native::Function(function.into_native()));
+    }
+
+    /// Removes a registered builtin, returning whether there was one.
+    pub fn unregister(&mut self, name: &str) -> bool {
+        let removed = self.natives.remove(name).is_some();
+        if removed {
+            self.generation += 1;
+        }
+        removed
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Native> {
+        self.natives.get(name)
+    }
+
+    /// Counts the changes made, so code compiled before one can be told
+    /// apart.
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    fn insert(&mut self, name: &str, native: Native) {
+        self.natives.insert(name.to_string(), native);
+        self.generation += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::context::{Context, Engine};
+    use crate::error::{Arity, EvalError, EvalErrorKind};
+    use crate::expression::Expression;
+    use crate::ops::Operation;
+    use crate::program::Program;
+    use crate::value::Value;
+
+    fn run(source: &str, context: &mut Context) -> Result<Value, EvalError> {
+        Program::parse(source).unwrap().eval(context)
+    }
+
+    fn contexts() -> Vec<Context> {
+        [Engine::Tree, Engine::Vm]
+            .iter()
+            .map(|&engine| {
+                let mut context = Context::new();
+                context.engine = engine;
+                context.builtins.register_fn("sum", |args| {
+                    let mut total = 0;
+                    for arg in args {
+                        total += arg.as_integer()?;
+                    }
+                    Ok(Value::Integer(total))
+                });
+                context.builtins.register("unless", Unless {});
+                context
+            })
+            .collect()
+    }
+
+    struct Unless {}
+
+    impl Operation for Unless {
+        fn eval(&self, args: &[Expression], context: &mut Context) -> Result<Value, EvalError> {
+            if args.len() != 2 {
+                return Err(EvalErrorKind::ArityMismatch {
+                    expected: Arity::Exact(2),
+                    actual: args.len(),
+                }
+                .into());
+            }
+            match args[0].eval_bool(context)? {
+                true => Ok(Value::Nil),
+                false => args[1].eval(context),
+            }
+        }
+    }
+
+    #[test]
+    fn test_native_functions() {
+        for mut context in contexts() {
+            assert_eq!(run("(sum)", &mut context), Ok(Value::Integer(0)));
+            assert_eq!(
+                run("(let ((x 2)) (sum 1 x (sum 3 4)))", &mut context),
+                Ok(Value::Integer(10))
+            );
+            let err = run("(sum 1 true)", &mut context).unwrap_err();
+            assert_eq!(
+                err.kind,
+                EvalErrorKind::TypeMismatch {
+                    expected: "integer",
+                    actual: "bool"
+                }
+            );
+            assert_eq!(err.span.map(|span| span.start), Some(0));
+        }
+    }
+
+    #[test]
+    fn test_native_forms() {
+        for mut context in contexts() {
+            assert_eq!(run("(unless false 1)", &mut context), Ok(Value::Integer(1)));
+            assert_eq!(run("(unless true (exit 1))", &mut context), Ok(Value::Nil));
+            assert_eq!(
+                run("(unless 1 2 3)", &mut context).map_err(|err| err.kind),
+                Err(EvalErrorKind::ArityMismatch {
+                    expected: Arity::Exact(2),
+                    actual: 3
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_typed_natives() {
+        for mut context in contexts() {
+            context
+                .builtins
+                .register_typed("repeat", |text: String, times: usize| text.repeat(times));
+            context
+                .builtins
+                .register_typed("first-or", |items: Vec<Value>, default: Value| {
+                    items.first().cloned().unwrap_or(default)
+                });
+            assert_eq!(
+                run(r#"(repeat "ab" (+ 1 2))"#, &mut context),
+                Ok(Value::String("ababab".into()))
+            );
+            assert_eq!(run("(first-or [] 5)", &mut context), Ok(Value::Integer(5)));
+            assert_eq!(
+                run(r#"(repeat "ab" -1)"#, &mut context).map_err(|err| err.kind),
+                Err(EvalErrorKind::IntegerOverflow)
+            );
+            let err = run(r#"(repeat "ab" "c")"#, &mut context).unwrap_err();
+            assert_eq!(
+                err.kind,
+                EvalErrorKind::TypeMismatch {
+                    expected: "integer",
+                    actual: "string"
+                }
+            );
+            assert_eq!(err.span.map(|span| span.start), Some(0));
+            assert_eq!(
+                run(r#"(repeat "ab")"#, &mut context).map_err(|err| err.kind),
+                Err(EvalErrorKind::ArityMismatch {
+                    expected: Arity::Exact(2),
+                    actual: 1
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_natives_replace_builtins() {
+        for mut context in contexts() {
+            context
+                .builtins
+                .register_fn("+", |_| Ok(Value::String("plus".into())));
+            assert_eq!(
+                run("(+ 1 2)", &mut context),
+                Ok(Value::String("plus".into()))
+            );
+            assert_eq!(
+                run("(defn sum (a) a) (sum 5)", &mut context),
+                Ok(Value::Integer(5))
+            );
+        }
+    }
+
+    #[test]
+    fn test_registering_after_compiling() {
+        for mut context in contexts() {
+            run("(defn f (x) (double x))", &mut context).unwrap();
+            assert_eq!(
+                run("(f 1)", &mut context).map_err(|err| err.kind),
+                Err(EvalErrorKind::UnknownOperator("double".to_string()))
+            );
+            context.builtins.register_fn("double", |args| {
+                Ok(Value::Integer(args[0].as_integer()? * 2))
+            });
+            assert_eq!(run("(f 4)", &mut context), Ok(Value::Integer(8)));
+            assert!(context.builtins.unregister("double"));
+            assert!(!context.builtins.unregister("double"));
+            assert!(run("(f 4)", &mut context).is_err());
+        }
+    }
+}
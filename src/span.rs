@@ -0,0 +1,121 @@
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, column: usize) -> Self {
+        Span {
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+
+    /// An empty span positioned just past the last character of `source`.
+    pub fn end_of(source: &str) -> Span {
+        let line = source.matches('\n').count() + 1;
+        let line_start = source.rfind('\n').map_or(0, |i| i + 1);
+        let column = source[line_start..].chars().count() + 1;
+        Span::new(source.len(), source.len(), line, column)
+    }
+
+    pub fn to(&self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    /// Renders the source line containing the start of this span with the
+    /// spanned text underlined by carets. Spans covering several lines are
+    /// underlined up to the end of their first line. Returns `None` if the
+    /// span does not fall within `source`.
+    pub fn snippet(&self, source: &str) -> Option<String> {
+        if self.end > source.len()
+            || !source.is_char_boundary(self.start)
+            || !source.is_char_boundary(self.end)
+        {
+            return None;
+        }
+        let line_start = source[..self.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[line_start..]
+            .find('\n')
+            .map_or(source.len(), |i| line_start + i);
+        let text = &source[line_start..line_end];
+        let start = self.start;
+
+        let padding: String = source[line_start..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = source[start..self.end.clamp(start, line_end)]
+            .chars()
+            .count()
+            .max(1);
+
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        Some(format!(
+            "{} |\n{} | {}\n{} | {}{}",
+            gutter,
+            number,
+            text,
+            gutter,
+            padding,
+            "^".repeat(width)
+        ))
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snippet() {
+        let source = "(+ 1 true)";
+        assert_eq!(
+            Span::new(5, 9, 1, 6).snippet(source),
+            Some("  |\n1 | (+ 1 true)\n  |      ^^^^".to_string())
+        );
+    }
+
+    #[test]
+    fn test_snippet_later_line() {
+        let source = "(block\n\t(print x))";
+        assert_eq!(
+            Span::new(15, 16, 2, 9).snippet(source),
+            Some("  |\n2 | \t(print x))\n  | \t       ^".to_string())
+        );
+    }
+
+    #[test]
+    fn test_snippet_multiline_span() {
+        let source = "(block\n  1)";
+        assert_eq!(
+            Span::new(0, 11, 1, 1).snippet(source),
+            Some("  |\n1 | (block\n  | ^^^^^^".to_string())
+        );
+    }
+
+    #[test]
+    fn test_snippet_outside_source() {
+        assert_eq!(Span::new(5, 9, 1, 6).snippet("(+ 1)"), None);
+        assert_eq!(Span::new(1, 2, 1, 2).snippet("é"), None);
+    }
+}
@@ -0,0 +1,44 @@
+/// How much stack must be left for a recursive step to go ahead: room for
+/// the work done between two checks, which in debug builds can be tens of
+/// kilobytes, and for unwinding with the error.
+const RED_ZONE: usize = 256 << 10;
+
+/// Whether the current thread's stack is too nearly used up to recurse any
+/// further. Parsing, expansion, compilation and evaluation check this at
+/// each level, so deep input fails with an error however large the thread's
+/// stack is. Where the stack's extent can't be found this is always `false`,
+/// leaving only the depth limits.
+pub(crate) fn exhausted() -> bool {
+    stacker::remaining_stack().is_some_and(|remaining| remaining < RED_ZONE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recurse(levels: usize) -> Option<usize> {
+        if exhausted() {
+            return Some(levels);
+        }
+        let padding = std::hint::black_box([0u8; 1024]);
+        recurse(levels + 1).map(|found| found + padding[0] as usize)
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn test_exhausted() {
+        assert!(!exhausted());
+        let small = std::thread::Builder::new()
+            .stack_size(1 << 20)
+            .spawn(|| recurse(0))
+            .unwrap();
+        let large = std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(|| recurse(0))
+            .unwrap();
+        let small = small.join().unwrap().unwrap();
+        let large = large.join().unwrap().unwrap();
+        assert!(small > 100);
+        assert!(large > 4 * small);
+    }
+}
@@ -1,104 +1,348 @@
+use crate::bigint::BigInt;
+use crate::error::{ParseError, ParseErrorKind};
+use crate::ratio::Ratio;
+use crate::span::Span;
+use std::fmt;
+
 #[derive(Debug, PartialEq, Clone)]
-pub enum Token {
+pub(crate) enum TokenKind {
     LParen,
     RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
+    Deref,
     Nil,
     Symbol(String),
-    Integer(i32),
+    Keyword(String),
+    Integer(i64),
+    BigInt(BigInt),
+    Ratio(Ratio),
+    Float(f64),
     Bool(bool),
     String(String),
 }
 
-impl From<&str> for Token {
-    fn from(string: &str) -> Self {
-        let mut token = match string {
-            "(" => Token::LParen,
-            ")" => Token::RParen,
-            "true" => Token::Bool(true),
-            "false" => Token::Bool(false),
-            "nil" => Token::Nil,
-            text => Token::Symbol(text.to_string()),
-        };
-
-        if let Token::Symbol(text) = &token {
-            assert!(!text.is_empty());
-            if text.starts_with("\"") {
-                assert!(text.len() >= 2);
-                let mut text = text.to_string();
-                text.pop();
-                text.remove(0);
-                token = Token::String(text);
-            } else if let Ok(int) = text.parse::<i32>() {
-                token = Token::Integer(int);
+#[derive(Debug, Clone)]
+pub(crate) struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+// Tokens compare by kind only, so the same text parsed from different
+// places in a source file is considered equal.
+impl PartialEq for Token {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl From<TokenKind> for Token {
+    fn from(kind: TokenKind) -> Self {
+        Token {
+            kind,
+            span: Span::default(),
+        }
+    }
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenKind::LParen => write!(f, "("),
+            TokenKind::RParen => write!(f, ")"),
+            TokenKind::LBrace => write!(f, "{{"),
+            TokenKind::RBrace => write!(f, "}}"),
+            TokenKind::LBracket => write!(f, "["),
+            TokenKind::RBracket => write!(f, "]"),
+            TokenKind::Quote => write!(f, "'"),
+            TokenKind::Quasiquote => write!(f, "`"),
+            TokenKind::Unquote => write!(f, ","),
+            TokenKind::UnquoteSplicing => write!(f, ",@"),
+            TokenKind::Deref => write!(f, "@"),
+            TokenKind::Nil => write!(f, "nil"),
+            TokenKind::Symbol(text) => write!(f, "{}", text),
+            TokenKind::Keyword(name) => write!(f, ":{}", name),
+            TokenKind::Integer(int) => write!(f, "{}", int),
+            TokenKind::BigInt(big) => write!(f, "{}", big),
+            TokenKind::Ratio(ratio) => write!(f, "{}", ratio),
+            TokenKind::Float(float) => write!(f, "{:?}", float),
+            TokenKind::Bool(value) => write!(f, "{}", value),
+            TokenKind::String(string) => write!(f, "\"{}\"", escape(string)),
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+/// Decodes the backslash escapes in the contents of a string literal,
+/// returning the offending sequence for an unknown or malformed escape.
+fn unescape(contents: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(contents.len());
+    let mut chars = contents.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('0') => out.push('\0'),
+            Some('\\') => out.push('\\'),
+            Some('"') => out.push('"'),
+            Some('u') => {
+                let rest = chars.as_str();
+                let code = rest
+                    .strip_prefix('{')
+                    .and_then(|rest| rest.find('}').map(|end| &rest[..end]));
+                let decoded = code
+                    .filter(|hex| !hex.is_empty() && hex.len() <= 6)
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32);
+                match (code, decoded) {
+                    (Some(hex), Some(decoded)) => {
+                        out.push(decoded);
+                        chars = rest[hex.len() + 2..].chars();
+                    }
+                    (Some(hex), None) => return Err(format!("\\u{{{}}}", hex)),
+                    (None, _) => return Err("\\u".to_string()),
+                }
             }
+            Some(other) => return Err(format!("\\{}", other)),
+            None => return Err("\\".to_string()),
         }
+    }
+    Ok(out)
+}
 
-        token
+/// The inverse of `unescape`, used when printing strings readably.
+pub fn escape(string: &str) -> String {
+    let mut out = String::with_capacity(string.len());
+    for c in string.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\0' => out.push_str("\\0"),
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
+        }
     }
+    out
 }
 
-fn split_syntax(string: &str) -> Vec<String> {
-    let mut out = vec![];
+/// Accepts `n/d` with an optionally signed numerator and a non-zero,
+/// unsigned denominator.
+fn parse_ratio(text: &str) -> Option<Ratio> {
+    let slash = text.find('/')?;
+    let (numer, denom) = (&text[..slash], &text[slash + 1..]);
+    if !denom.bytes().next()?.is_ascii_digit() {
+        return None;
+    }
+    Ratio::new(BigInt::parse(numer)?, BigInt::parse(denom)?)
+}
 
-    let mut buffer = String::new();
+/// Whether `text` is shaped like a ratio, `n/d` with optionally signed
+/// integer parts, whether or not `parse_ratio` accepts it.
+fn looks_like_ratio(text: &str) -> bool {
+    let integer = |part: &str| {
+        let digits = part.strip_prefix(&['+', '-'][..]).unwrap_or(part);
+        !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+    };
+    match text.find('/') {
+        Some(slash) => integer(&text[..slash]) && integer(&text[slash + 1..]),
+        None => false,
+    }
+}
+
+/// Accepts decimal and exponent notation plus `inf`, `-inf` and `nan`, but
+/// not the other spellings `str::parse` allows, so `infinity` stays a symbol.
+fn parse_float(text: &str) -> Option<f64> {
+    match text {
+        "inf" | "+inf" => return Some(f64::INFINITY),
+        "-inf" => return Some(f64::NEG_INFINITY),
+        "nan" => return Some(f64::NAN),
+        _ => {}
+    }
+    let numeric = text.bytes().any(|b| b.is_ascii_digit())
+        && text
+            .bytes()
+            .all(|b| b.is_ascii_digit() || b"+-.eE".contains(&b));
+    if numeric {
+        text.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// The kind of token `text`, one piece of `split_syntax`'s output, is.
+fn classify(text: &str) -> Result<TokenKind, ParseErrorKind> {
+    let kind = match text {
+        "" => return Err(ParseErrorKind::UnexpectedEof),
+        "(" => TokenKind::LParen,
+        ")" => TokenKind::RParen,
+        "{" => TokenKind::LBrace,
+        "}" => TokenKind::RBrace,
+        "[" => TokenKind::LBracket,
+        "]" => TokenKind::RBracket,
+        "'" => TokenKind::Quote,
+        "`" => TokenKind::Quasiquote,
+        "," => TokenKind::Unquote,
+        ",@" => TokenKind::UnquoteSplicing,
+        "@" => TokenKind::Deref,
+        "true" => TokenKind::Bool(true),
+        "false" => TokenKind::Bool(false),
+        "nil" => TokenKind::Nil,
+        text if text.starts_with('"') => {
+            if text.len() < 2 || !text.ends_with('"') {
+                return Err(ParseErrorKind::UnterminatedString);
+            }
+            let contents = &text[1..text.len() - 1];
+            TokenKind::String(unescape(contents).map_err(ParseErrorKind::InvalidEscape)?)
+        }
+        text if text.len() > 1 && text.starts_with(':') => {
+            TokenKind::Keyword(text[1..].to_string())
+        }
+        text if looks_like_ratio(text) => match parse_ratio(text) {
+            Some(ratio) => TokenKind::Ratio(ratio),
+            None => return Err(ParseErrorKind::InvalidRatio(text.to_string())),
+        },
+        text => {
+            if let Ok(int) = text.parse::<i64>() {
+                TokenKind::Integer(int)
+            } else if let Some(big) = BigInt::parse(text) {
+                TokenKind::BigInt(big)
+            } else if let Some(float) = parse_float(text) {
+                TokenKind::Float(float)
+            } else {
+                TokenKind::Symbol(text.to_string())
+            }
+        }
+    };
+    Ok(kind)
+}
+
+struct Splitter {
+    out: Vec<(String, Span)>,
+    buffer: String,
+    start: Span,
+}
+
+impl Splitter {
+    fn push(&mut self, c: char, position: Span) {
+        if self.buffer.is_empty() {
+            self.start = position;
+        }
+        self.buffer.push(c);
+        self.start.end = position.end;
+    }
+
+    fn flush(&mut self) {
+        if !self.buffer.is_empty() {
+            self.out.push((self.buffer.clone(), self.start));
+            self.buffer.clear();
+        }
+    }
+}
+
+fn split_syntax(string: &str) -> Result<Vec<(String, Span)>, ParseError> {
+    let mut splitter = Splitter {
+        out: vec![],
+        buffer: String::new(),
+        start: Span::default(),
+    };
 
     let mut in_string = false;
+    let mut line = 1;
+    let mut column = 1;
+
+    let mut chars = string.char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        let position = Span::new(offset, offset + c.len_utf8(), line, column);
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
 
-    for c in string.chars() {
-        let buffer = &mut buffer;
         if in_string {
-            if c == '"' {
-                buffer.push('\"');
-                if !buffer.is_empty() {
-                    out.push(buffer.clone());
-                    buffer.clear();
+            splitter.push(c, position);
+            if c == '\\' {
+                if let Some((offset, escaped)) = chars.next() {
+                    let position = Span::new(offset, offset + escaped.len_utf8(), line, column);
+                    if escaped == '\n' {
+                        line += 1;
+                        column = 1;
+                    } else {
+                        column += 1;
+                    }
+                    splitter.push(escaped, position);
                 }
+            } else if c == '"' {
+                splitter.flush();
                 in_string = false;
-            } else {
-                buffer.push(c);
             }
-        } else {
-            if c == '"' {
-                if !buffer.is_empty() {
-                    out.push(buffer.clone());
-                    buffer.clear();
-                }
-                buffer.push('\"');
-                in_string = true;
-            } else if c.is_whitespace() {
-                if !buffer.is_empty() {
-                    out.push(buffer.clone());
-                    buffer.clear();
-                }
-            } else if c == '(' || c == ')' {
-                if !buffer.is_empty() {
-                    out.push(buffer.clone());
-                    buffer.clear();
-                }
-                buffer.push(c);
-                if !buffer.is_empty() {
-                    out.push(buffer.clone());
-                    buffer.clear();
+        } else if c == '"' {
+            splitter.flush();
+            splitter.push(c, position);
+            in_string = true;
+        } else if c.is_whitespace() {
+            splitter.flush();
+        } else if "(){}[]".contains(c) {
+            splitter.flush();
+            splitter.push(c, position);
+            splitter.flush();
+        } else if c == '\'' || c == '`' || c == ',' {
+            splitter.flush();
+            splitter.push(c, position);
+            if c == ',' {
+                if let Some(&(offset, '@')) = chars.peek() {
+                    chars.next();
+                    splitter.push('@', Span::new(offset, offset + 1, line, column));
+                    column += 1;
                 }
-            } else {
-                buffer.push(c);
             }
+            splitter.flush();
+        } else if c == '@' && splitter.buffer.is_empty() {
+            splitter.push(c, position);
+            splitter.flush();
+        } else {
+            splitter.push(c, position);
         }
     }
-    if !buffer.is_empty() {
-        out.push(buffer.clone());
-        buffer.clear();
+    if in_string {
+        return Err(ParseError::new(
+            ParseErrorKind::UnterminatedString,
+            splitter.start,
+        ));
     }
+    splitter.flush();
 
-    out
+    Ok(splitter.out)
 }
 
-pub fn tokenize(string: &str) -> Vec<Token> {
-    let parts = split_syntax(string);
-    parts
-        .iter()
-        .map(|part| Token::from(part.as_ref()))
-        .collect::<Vec<Token>>()
+pub fn tokenize(string: &str) -> Result<Vec<Token>, ParseError> {
+    split_syntax(string)?
+        .into_iter()
+        .map(|(part, span)| match classify(&part) {
+            Ok(kind) => Ok(Token { kind, span }),
+            Err(kind) => Err(ParseError::new(kind, span)),
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -108,68 +352,76 @@ mod tests {
     mod split {
         use super::*;
 
+        fn split(string: &str) -> Vec<String> {
+            split_syntax(string)
+                .unwrap()
+                .into_iter()
+                .map(|(part, _)| part)
+                .collect()
+        }
+
         #[test]
         fn test_empty() {
-            assert_eq!(split_syntax(""), Vec::<String>::new());
+            assert_eq!(split(""), Vec::<String>::new());
         }
 
         #[test]
         fn test_just_whitespace() {
-            assert_eq!(split_syntax("  \t"), Vec::<String>::new());
+            assert_eq!(split("  \t"), Vec::<String>::new());
         }
 
         #[test]
         fn test_just_newline() {
-            assert_eq!(split_syntax("\n"), Vec::<String>::new());
+            assert_eq!(split("\n"), Vec::<String>::new());
         }
 
         #[test]
         fn test_int() {
-            assert_eq!(split_syntax("5"), vec!["5"]);
+            assert_eq!(split("5"), vec!["5"]);
         }
 
         #[test]
         fn test_int_with_whitespace() {
-            assert_eq!(split_syntax("\t\t5  "), vec!["5"]);
+            assert_eq!(split("\t\t5  "), vec!["5"]);
         }
 
         #[test]
         fn test_neg_int_with_whitespace() {
-            assert_eq!(split_syntax("-8  "), vec!["-8"]);
+            assert_eq!(split("-8  "), vec!["-8"]);
         }
 
         #[test]
         fn test_math_operators() {
-            assert_eq!(split_syntax("\t\t +\t- * /     "), vec!["+", "-", "*", "/"]);
+            assert_eq!(split("\t\t +\t- * /     "), vec!["+", "-", "*", "/"]);
         }
 
         #[test]
         fn test_basic_math_symbols() {
             assert_eq!(
-                split_syntax("3 * 2 / / + 881     \t-3  -\t"),
+                split("3 * 2 / / + 881     \t-3  -\t"),
                 vec!["3", "*", "2", "/", "/", "+", "881", "-3", "-"]
             );
         }
 
         #[test]
         fn test_l_paren() {
-            assert_eq!(split_syntax("  \t("), vec!["("]);
+            assert_eq!(split("  \t("), vec!["("]);
         }
 
         #[test]
         fn test_r_paren() {
-            assert_eq!(split_syntax("  \t)\t\t"), vec![")"]);
+            assert_eq!(split("  \t)\t\t"), vec![")"]);
         }
 
         #[test]
         fn test_multiple_parens() {
-            assert_eq!(split_syntax("(( \n)( "), vec!["(", "(", ")", "("]);
+            assert_eq!(split("(( \n)( "), vec!["(", "(", ")", "("]);
         }
 
         #[test]
         fn test_mixed_with_parens() {
             assert_eq!(
-                split_syntax("7(3)(+))"),
+                split("7(3)(+))"),
                 vec!["7", "(", "3", ")", "(", "+", ")", ")"]
             );
         }
@@ -177,106 +429,316 @@ mod tests {
         #[test]
         fn test_math_expr() {
             assert_eq!(
-                split_syntax("(+ 4 (* 3 5))"),
+                split("(+ 4 (* 3 5))"),
                 vec!["(", "+", "4", "(", "*", "3", "5", ")", ")"]
             );
         }
 
         #[test]
         fn test_empty_string() {
-            assert_eq!(split_syntax("\"\""), vec!["\"\""]);
-            assert_eq!(split_syntax("asdf\"\"  \t"), vec!["asdf", "\"\""]);
+            assert_eq!(split("\"\""), vec!["\"\""]);
+            assert_eq!(split("asdf\"\"  \t"), vec!["asdf", "\"\""]);
         }
 
         #[test]
         fn test_string() {
+            assert_eq!(split("\t\"Hello, world!\"  "), vec!["\"Hello, world!\""]);
             assert_eq!(
-                split_syntax("\t\"Hello, world!\"  "),
-                vec!["\"Hello, world!\""]
-            );
-            assert_eq!(
-                split_syntax("asdf\"Hello, world!\"  \t"),
+                split("asdf\"Hello, world!\"  \t"),
                 vec!["asdf", "\"Hello, world!\""]
             );
             assert_eq!(
-                split_syntax("(\"Hello, world!\" ) "),
+                split("(\"Hello, world!\" ) "),
                 vec!["(", "\"Hello, world!\"", ")"]
             );
         }
+
+        #[test]
+        fn test_braces() {
+            assert_eq!(split("{:a(b)}"), vec!["{", ":a", "(", "b", ")", "}"]);
+            assert_eq!(split("[1[]2]"), vec!["[", "1", "[", "]", "2", "]"]);
+        }
+
+        #[test]
+        fn test_escaped_quote() {
+            assert_eq!(split(r#"("a\"b" c)"#), vec!["(", r#""a\"b""#, "c", ")"]);
+        }
+
+        #[test]
+        fn test_quotes() {
+            assert_eq!(
+                split("'a `(b ,c ,@d) 'e'f \"'\""),
+                vec![
+                    "'", "a", "`", "(", "b", ",", "c", ",@", "d", ")", "'", "e", "'", "f", "\"'\""
+                ]
+            );
+        }
+
+        #[test]
+        fn test_unterminated_string() {
+            assert_eq!(
+                split_syntax("(print \"abc)"),
+                Err(ParseError::new(
+                    ParseErrorKind::UnterminatedString,
+                    Span::new(7, 12, 1, 8)
+                ))
+            );
+        }
     }
 
     mod str_to_token {
         use super::*;
 
+        fn token(text: &str) -> TokenKind {
+            classify(text).unwrap()
+        }
+
         #[test]
         fn test_lparen() {
-            assert_eq!(Token::from("("), Token::LParen);
+            assert_eq!(token("("), TokenKind::LParen);
         }
 
         #[test]
         fn test_rparen() {
-            assert_eq!(Token::from("("), Token::LParen);
+            assert_eq!(token("("), TokenKind::LParen);
         }
 
         #[test]
         fn test_nil() {
-            assert_eq!(Token::from("nil"), Token::Nil);
+            assert_eq!(token("nil"), TokenKind::Nil);
         }
 
         #[test]
         fn test_bool() {
-            assert_eq!(Token::from("true"), Token::Bool(true));
-            assert_eq!(Token::from("false"), Token::Bool(false));
+            assert_eq!(token("true"), TokenKind::Bool(true));
+            assert_eq!(token("false"), TokenKind::Bool(false));
         }
 
         #[test]
         fn test_integer() {
-            assert_eq!(Token::from("123"), Token::Integer(123));
+            assert_eq!(token("123"), TokenKind::Integer(123));
+        }
+
+        #[test]
+        fn test_float() {
+            assert_eq!(token("2.75"), TokenKind::Float(2.75));
+            assert_eq!(token("-.5"), TokenKind::Float(-0.5));
+            assert_eq!(token("1e-9"), TokenKind::Float(1e-9));
+            assert_eq!(token("-inf"), TokenKind::Float(f64::NEG_INFINITY));
+            assert!(matches!(token("nan"), TokenKind::Float(f) if f.is_nan()));
+            assert_eq!(token("e5"), TokenKind::Symbol("e5".to_string()));
+            assert_eq!(token("infinity"), TokenKind::Symbol("infinity".to_string()));
+            assert_eq!(token("1.2.3"), TokenKind::Symbol("1.2.3".to_string()));
+        }
+
+        #[test]
+        fn test_keyword() {
+            assert_eq!(token(":name"), TokenKind::Keyword("name".to_string()));
+            assert_eq!(token(":"), TokenKind::Symbol(":".to_string()));
+        }
+
+        #[test]
+        fn test_braces() {
+            assert_eq!(token("{"), TokenKind::LBrace);
+            assert_eq!(token("}"), TokenKind::RBrace);
+            assert_eq!(token("["), TokenKind::LBracket);
+            assert_eq!(token("]"), TokenKind::RBracket);
+        }
+
+        #[test]
+        fn test_ratio() {
+            let ratio = |n, d| Ratio::new(BigInt::from(n), BigInt::from(d)).unwrap();
+            assert_eq!(token("1/3"), TokenKind::Ratio(ratio(1, 3)));
+            assert_eq!(token("-2/4"), TokenKind::Ratio(ratio(-1, 2)));
+            assert_eq!(token("a/b"), TokenKind::Symbol("a/b".to_string()));
+            assert_eq!(token("1/x"), TokenKind::Symbol("1/x".to_string()));
+        }
+
+        #[test]
+        fn test_big_integer() {
+            assert_eq!(token("9223372036854775807"), TokenKind::Integer(i64::MAX));
+            assert_eq!(
+                token("-9223372036854775809"),
+                TokenKind::BigInt(BigInt::parse("-9223372036854775809").unwrap())
+            );
         }
 
         #[test]
         fn test_neg_integer() {
-            assert_eq!(Token::from("-88"), Token::Integer(-88));
+            assert_eq!(token("-88"), TokenKind::Integer(-88));
+        }
+
+        #[test]
+        fn test_malformed_text() {
+            assert_eq!(classify(""), Err(ParseErrorKind::UnexpectedEof));
+            assert_eq!(classify("\""), Err(ParseErrorKind::UnterminatedString));
+            assert_eq!(
+                classify(r#""\q""#),
+                Err(ParseErrorKind::InvalidEscape("\\q".to_string()))
+            );
+            assert_eq!(
+                classify("1/0"),
+                Err(ParseErrorKind::InvalidRatio("1/0".to_string()))
+            );
         }
 
         #[test]
         fn test_arbitrary_symbol() {
-            assert_eq!(Token::from("432af"), Token::Symbol("432af".to_string()));
+            assert_eq!(token("432af"), TokenKind::Symbol("432af".to_string()));
         }
 
         #[test]
         fn test_math_operators() {
-            assert_eq!(Token::from("+"), Token::Symbol("+".to_string()));
-            assert_eq!(Token::from("-"), Token::Symbol("-".to_string()));
-            assert_eq!(Token::from("*"), Token::Symbol("*".to_string()));
-            assert_eq!(Token::from("/"), Token::Symbol("/".to_string()));
+            assert_eq!(token("+"), TokenKind::Symbol("+".to_string()));
+            assert_eq!(token("-"), TokenKind::Symbol("-".to_string()));
+            assert_eq!(token("*"), TokenKind::Symbol("*".to_string()));
+            assert_eq!(token("/"), TokenKind::Symbol("/".to_string()));
         }
     }
 
     mod tokenize {
         use super::*;
 
+        fn kinds(string: &str) -> Vec<TokenKind> {
+            tokenize(string)
+                .unwrap()
+                .into_iter()
+                .map(|token| token.kind)
+                .collect()
+        }
+
         #[test]
         fn test_empty() {
-            assert_eq!(tokenize(""), vec![]);
+            assert_eq!(kinds(""), vec![]);
         }
 
         #[test]
         fn test_math_expr() {
             assert_eq!(
-                tokenize("(+ 4 (* 3 5))"),
+                kinds("(+ 4 (* 3 5))"),
                 vec![
-                    Token::LParen,
-                    Token::Symbol("+".to_string()),
-                    Token::Integer(4),
-                    Token::LParen,
-                    Token::Symbol("*".to_string()),
-                    Token::Integer(3),
-                    Token::Integer(5),
-                    Token::RParen,
-                    Token::RParen
+                    TokenKind::LParen,
+                    TokenKind::Symbol("+".to_string()),
+                    TokenKind::Integer(4),
+                    TokenKind::LParen,
+                    TokenKind::Symbol("*".to_string()),
+                    TokenKind::Integer(3),
+                    TokenKind::Integer(5),
+                    TokenKind::RParen,
+                    TokenKind::RParen
                 ]
             );
         }
+
+        #[test]
+        fn test_string_escapes() {
+            assert_eq!(
+                kinds(r#""a\"b\\c\n\t\u{e9}\u{1F600}""#),
+                vec![TokenKind::String("a\"b\\c\n\té😀".to_string())]
+            );
+            assert_eq!(
+                kinds(r#"("\"" x)"#),
+                vec![
+                    TokenKind::LParen,
+                    TokenKind::String("\"".to_string()),
+                    TokenKind::Symbol("x".to_string()),
+                    TokenKind::RParen
+                ]
+            );
+        }
+
+        #[test]
+        fn test_invalid_escapes() {
+            let kind = |string: &str| tokenize(string).unwrap_err().kind;
+            for (source, sequence) in &[
+                (r#""\q""#, r"\q"),
+                (r#""\u{110000}""#, r"\u{110000}"),
+                (r#""\u{}""#, r"\u{}"),
+                (r#""\u41""#, r"\u"),
+            ] {
+                assert_eq!(
+                    kind(source),
+                    ParseErrorKind::InvalidEscape(sequence.to_string())
+                );
+            }
+            assert_eq!(kind(r#""abc\""#), ParseErrorKind::UnterminatedString);
+        }
+
+        #[test]
+        fn test_invalid_ratios() {
+            for source in &["1/0", "1/-2", "-3/+4", "0/00"] {
+                assert_eq!(
+                    tokenize(&format!("(+ {} 1)", source)),
+                    Err(ParseError::new(
+                        ParseErrorKind::InvalidRatio(source.to_string()),
+                        Span::new(3, 3 + source.len(), 1, 4)
+                    ))
+                );
+            }
+        }
+
+        #[test]
+        fn test_escape_round_trip() {
+            let original = "tab\there \"quoted\" back\\slash\nnew\u{7}";
+            assert_eq!(
+                kinds(&format!("\"{}\"", escape(original))),
+                vec![TokenKind::String(original.to_string())]
+            );
+        }
+
+        #[test]
+        fn test_quotes() {
+            assert_eq!(
+                kinds("'(,a ,@b)`c"),
+                vec![
+                    TokenKind::Quote,
+                    TokenKind::LParen,
+                    TokenKind::Unquote,
+                    TokenKind::Symbol("a".to_string()),
+                    TokenKind::UnquoteSplicing,
+                    TokenKind::Symbol("b".to_string()),
+                    TokenKind::RParen,
+                    TokenKind::Quasiquote,
+                    TokenKind::Symbol("c".to_string()),
+                ]
+            );
+            assert_eq!(
+                kinds("@a b@c"),
+                vec![
+                    TokenKind::Deref,
+                    TokenKind::Symbol("a".to_string()),
+                    TokenKind::Symbol("b@c".to_string()),
+                ]
+            );
+            let tokens = tokenize("(x ,@y)").unwrap();
+            assert_eq!(tokens[2].span, Span::new(3, 5, 1, 4));
+            assert_eq!(tokens[3].span, Span::new(5, 6, 1, 6));
+        }
+
+        #[test]
+        fn test_spans() {
+            let spans: Vec<Span> = tokenize("(+ 4\n  \"a b\")")
+                .unwrap()
+                .into_iter()
+                .map(|token| token.span)
+                .collect();
+            assert_eq!(
+                spans,
+                vec![
+                    Span::new(0, 1, 1, 1),
+                    Span::new(1, 2, 1, 2),
+                    Span::new(3, 4, 1, 4),
+                    Span::new(7, 12, 2, 3),
+                    Span::new(12, 13, 2, 8),
+                ]
+            );
+        }
+
+        #[test]
+        fn test_multibyte_spans() {
+            let tokens = tokenize("(print \"héllo\") x").unwrap();
+            assert_eq!(tokens[2].span, Span::new(7, 15, 1, 8));
+            assert_eq!(tokens[4].span, Span::new(17, 18, 1, 17));
+        }
     }
 }
@@ -1,71 +1,494 @@
+use crate::bigint::BigInt;
+use crate::error::{EvalError, EvalErrorKind};
+use crate::function::Function;
+use crate::numeric;
+use crate::ratio::Ratio;
+use crate::tokenizer::escape;
+use crate::vector::Vector;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{btree_map, BTreeMap, HashSet};
 use std::fmt;
+use std::mem;
+use std::rc::Rc;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub enum Value {
-    Integer(i32),
+    Integer(i64),
+    BigInt(BigInt),
+    Ratio(Ratio),
+    Float(f64),
     Bool(bool),
-    String(String),
+    String(Rc<str>),
+    Symbol(Rc<str>),
+    Keyword(Rc<str>),
+    List(Rc<Vec<Value>>),
+    Vector(Vector),
+    Map(Rc<BTreeMap<Value, Value>>),
+    Function(Rc<Function>),
+    Atom(Rc<RefCell<Value>>),
     Nil,
 }
 
+/// Equality is agreement under the total order below: numbers are equal
+/// when they are numerically equal, so `1` and `1.0` are the same map key at
+/// any depth, and NaN is equal to itself.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+/// A total order over all values so they can be used as map keys. Numbers
+/// sort numerically, whatever their type, everything else first by type and
+/// then by contents.
+/// This is unrelated to the `<` builtin, which only accepts numbers.
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_nested(self, other, Value::cmp_leaf)
+    }
+}
+
+/// The items of a collection being compared, a map's as each key followed
+/// by its value.
+enum Items<'a> {
+    List(std::slice::Iter<'a, Value>),
+    Vector(&'a Vector, usize),
+    Map(btree_map::Iter<'a, Value, Value>, Option<&'a Value>),
+}
+
+impl<'a> Iterator for Items<'a> {
+    type Item = &'a Value;
+
+    fn next(&mut self) -> Option<&'a Value> {
+        match self {
+            Items::List(items) => items.next(),
+            Items::Vector(vector, index) => {
+                *index += 1;
+                vector.get(*index - 1)
+            }
+            Items::Map(entries, value) => value.take().or_else(|| {
+                let (key, next) = entries.next()?;
+                *value = Some(next);
+                Some(key)
+            }),
+        }
+    }
+}
+
+/// Compares two values, collections of the same kind item by item and
+/// anything else with `leaf`. Nested collections are compared from a
+/// worklist rather than recursively so that deep ones can't overflow the
+/// stack.
+fn compare_nested(a: &Value, b: &Value, leaf: impl Fn(&Value, &Value) -> Ordering) -> Ordering {
+    let mut pending: Vec<(Items, Items)> = Vec::new();
+    let (mut a, mut b) = (a, b);
+    loop {
+        match (a, b) {
+            (Value::List(x), Value::List(y)) if !Rc::ptr_eq(x, y) => {
+                pending.push((Items::List(x.iter()), Items::List(y.iter())))
+            }
+            (Value::Vector(x), Value::Vector(y)) => {
+                pending.push((Items::Vector(x, 0), Items::Vector(y, 0)))
+            }
+            (Value::Map(x), Value::Map(y)) if !Rc::ptr_eq(x, y) => {
+                pending.push((Items::Map(x.iter(), None), Items::Map(y.iter(), None)))
+            }
+            (Value::List(_), Value::List(_)) | (Value::Map(_), Value::Map(_)) => {}
+            _ => match leaf(a, b) {
+                Ordering::Equal => {}
+                order => return order,
+            },
+        }
+        loop {
+            let (xs, ys) = match pending.last_mut() {
+                Some(items) => items,
+                None => return Ordering::Equal,
+            };
+            match (xs.next(), ys.next()) {
+                (Some(x), Some(y)) => {
+                    a = x;
+                    b = y;
+                    break;
+                }
+                (None, None) => {
+                    pending.pop();
+                }
+                (None, Some(_)) => return Ordering::Less,
+                (Some(_), None) => return Ordering::Greater,
+            }
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl From<BigInt> for Value {
+    fn from(big: BigInt) -> Self {
+        match big.to_i64() {
+            Some(int) => Value::Integer(int),
+            None => Value::BigInt(big),
+        }
+    }
+}
+
+impl From<Ratio> for Value {
+    fn from(ratio: Ratio) -> Self {
+        if ratio.is_integer() {
+            Value::from(ratio.numer().clone())
+        } else {
+            Value::Ratio(ratio)
+        }
+    }
+}
+
+/// Writes floats so that they read back as floats: always with a decimal
+/// point or exponent, and with the `inf`/`nan` literal spellings.
+fn write_float(f: &mut fmt::Formatter, float: f64) -> fmt::Result {
+    if float.is_nan() {
+        write!(f, "nan")
+    } else if float.is_infinite() {
+        write!(f, "{}inf", if float < 0.0 { "-" } else { "" })
+    } else {
+        write!(f, "{:?}", float)
+    }
+}
+
+/// What is left to write of a value being displayed.
+enum Print {
+    Value(Value),
+    Text(&'static str),
+    /// The end of an atom's contents.
+    EndAtom(*const RefCell<Value>),
+}
+
+/// Queues `items` to be written in order, separated by spaces.
+fn push_items(pending: &mut Vec<Print>, items: Vec<Value>) {
+    for (i, item) in items.into_iter().enumerate().rev() {
+        pending.push(Print::Value(item));
+        if i > 0 {
+            pending.push(Print::Text(" "));
+        }
+    }
+}
+
+/// Values are written from a worklist rather than recursively, so that deep
+/// ones can't overflow the stack. An atom nested inside itself is elided so
+/// that cycles still print finitely.
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Value::Integer(int) => write!(f, "{}", int),
-            Value::Bool(value) => write!(f, "{}", if *value { "true" } else { "false" }),
-            Value::String(string) => write!(f, "\"{}\"", string),
-            Value::Nil => write!(f, "nil"),
+        let mut atoms = HashSet::new();
+        let mut pending = vec![Print::Value(self.clone())];
+        while let Some(next) = pending.pop() {
+            let value = match next {
+                Print::Value(value) => value,
+                Print::Text(text) => {
+                    f.write_str(text)?;
+                    continue;
+                }
+                Print::EndAtom(atom) => {
+                    atoms.remove(&atom);
+                    f.write_str(">")?;
+                    continue;
+                }
+            };
+            match &value {
+                Value::Integer(int) => write!(f, "{}", int)?,
+                Value::BigInt(big) => write!(f, "{}", big)?,
+                Value::Ratio(ratio) => write!(f, "{}", ratio)?,
+                Value::Float(float) => write_float(f, *float)?,
+                Value::Bool(value) => write!(f, "{}", if *value { "true" } else { "false" })?,
+                Value::String(string) => write!(f, "\"{}\"", escape(string))?,
+                Value::Symbol(name) => write!(f, "{}", name)?,
+                Value::Keyword(name) => write!(f, ":{}", name)?,
+                Value::List(items) => {
+                    f.write_str("(")?;
+                    pending.push(Print::Text(")"));
+                    push_items(&mut pending, items.to_vec());
+                }
+                Value::Vector(items) => {
+                    f.write_str("[")?;
+                    pending.push(Print::Text("]"));
+                    push_items(&mut pending, items.iter().cloned().collect());
+                }
+                Value::Map(entries) => {
+                    f.write_str("{")?;
+                    pending.push(Print::Text("}"));
+                    let items = entries
+                        .iter()
+                        .flat_map(|(key, value)| vec![key.clone(), value.clone()])
+                        .collect();
+                    push_items(&mut pending, items);
+                }
+                Value::Function(function) => match &function.name {
+                    Some(name) => write!(f, "<function {}>", name)?,
+                    None => write!(f, "<lambda>")?,
+                },
+                Value::Atom(cell) => {
+                    let ptr = Rc::as_ptr(cell);
+                    if !atoms.insert(ptr) {
+                        write!(f, "<atom ...>")?;
+                    } else {
+                        f.write_str("<atom ")?;
+                        pending.push(Print::EndAtom(ptr));
+                        pending.push(Print::Value(cell.borrow().clone()));
+                    }
+                }
+                Value::Nil => write!(f, "nil")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Collections and atoms no other value shares are emptied onto a worklist
+/// before being freed, so that dropping deeply nested data can't overflow
+/// the stack.
+impl Drop for Value {
+    fn drop(&mut self) {
+        if !self.owns_nested() {
+            return;
+        }
+        let mut pending = Vec::new();
+        self.take_contents(&mut pending);
+        while let Some(mut value) = pending.pop() {
+            value.take_contents(&mut pending);
         }
     }
 }
 
 impl Value {
-    pub fn is_integer(&self) -> bool {
+    /// The order of two values other than a pair of collections of the same
+    /// kind.
+    fn cmp_leaf(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (a, b) if a.is_number() && b.is_number() => numeric::total_cmp(a, b),
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::String(a), Value::String(b)) => a.cmp(b),
+            (Value::Symbol(a), Value::Symbol(b)) => a.cmp(b),
+            (Value::Keyword(a), Value::Keyword(b)) => a.cmp(b),
+            (Value::Function(a), Value::Function(b)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+            (Value::Atom(a), Value::Atom(b)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
         match self {
-            Value::Integer(_) => true,
-            _ => false,
+            Value::Integer(_) | Value::BigInt(_) => "integer",
+            Value::Ratio(_) => "ratio",
+            Value::Float(_) => "float",
+            Value::Bool(_) => "bool",
+            Value::String(_) => "string",
+            Value::Symbol(_) => "symbol",
+            Value::Keyword(_) => "keyword",
+            Value::List(_) => "list",
+            Value::Vector(_) => "vector",
+            Value::Map(_) => "map",
+            Value::Function(_) => "function",
+            Value::Atom(_) => "atom",
+            Value::Nil => "nil",
         }
     }
 
-    pub fn is_bool(&self) -> bool {
+    fn rank(&self) -> u8 {
         match self {
-            Value::Bool(_) => true,
-            _ => false,
+            Value::Nil => 0,
+            Value::Bool(_) => 1,
+            Value::Integer(_) => 2,
+            Value::BigInt(_) => 3,
+            Value::Ratio(_) => 4,
+            Value::Float(_) => 5,
+            Value::String(_) => 6,
+            Value::Keyword(_) => 7,
+            Value::Symbol(_) => 8,
+            Value::List(_) => 9,
+            Value::Vector(_) => 10,
+            Value::Map(_) => 11,
+            Value::Function(_) => 12,
+            Value::Atom(_) => 13,
         }
     }
 
-    pub fn is_string(&self) -> bool {
+    /// Whether this is the only reference to a collection or atom that
+    /// holds another, which dropping would otherwise free recursively.
+    fn owns_nested(&self) -> bool {
+        fn nests(value: &Value) -> bool {
+            matches!(
+                value,
+                Value::List(_) | Value::Vector(_) | Value::Map(_) | Value::Atom(_)
+            )
+        }
         match self {
-            Value::String(_) => true,
+            Value::List(items) => Rc::strong_count(items) == 1 && items.iter().any(nests),
+            Value::Vector(items) => items.is_unique() && items.iter().any(nests),
+            Value::Map(entries) => {
+                Rc::strong_count(entries) == 1
+                    && entries
+                        .iter()
+                        .any(|(key, value)| nests(key) || nests(value))
+            }
+            Value::Atom(cell) => Rc::strong_count(cell) == 1 && nests(&cell.borrow()),
             _ => false,
         }
     }
 
-    pub fn is_nil(&self) -> bool {
+    /// Moves the contents of a collection or atom nothing else refers to
+    /// onto `pending`, leaving it empty.
+    fn take_contents(&mut self, pending: &mut Vec<Value>) {
         match self {
-            Value::Nil => true,
-            _ => false,
+            Value::List(items) if Rc::strong_count(items) == 1 => {
+                if let Ok(items) = Rc::try_unwrap(mem::take(items)) {
+                    pending.extend(items);
+                }
+            }
+            Value::Vector(items) => items.take_items(pending),
+            Value::Map(entries) if Rc::strong_count(entries) == 1 => {
+                if let Ok(entries) = Rc::try_unwrap(mem::take(entries)) {
+                    for (key, value) in entries {
+                        pending.push(key);
+                        pending.push(value);
+                    }
+                }
+            }
+            Value::Atom(cell) if Rc::strong_count(cell) == 1 => {
+                pending.push(cell.replace(Value::Nil));
+            }
+            _ => {}
         }
     }
 
-    pub fn unwrap_integer(&self) -> i32 {
+    pub fn is_number(&self) -> bool {
+        matches!(
+            self,
+            Value::Integer(_) | Value::BigInt(_) | Value::Ratio(_) | Value::Float(_)
+        )
+    }
+
+    /// The value as a machine integer, failing for integers too large to fit.
+    pub fn as_integer(&self) -> Result<i64, EvalError> {
+        match self {
+            Value::Integer(int) => Ok(*int),
+            Value::BigInt(_) => Err(EvalErrorKind::IntegerOverflow.into()),
+            _ => Err(self.type_mismatch("integer")),
+        }
+    }
+
+    pub fn as_bool(&self) -> Result<bool, EvalError> {
         match self {
-            Value::Integer(int) => *int,
-            _ => panic!(),
+            Value::Bool(val) => Ok(*val),
+            _ => Err(self.type_mismatch("bool")),
         }
     }
 
-    pub fn unwrap_bool(&self) -> bool {
+    /// The value as `print` shows it: strings without quotes or escapes, and
+    /// everything else in its readable form.
+    pub fn to_text(&self) -> String {
         match self {
-            Value::Bool(val) => *val,
-            _ => panic!(),
+            Value::String(string) => string.to_string(),
+            value => value.to_string(),
         }
     }
 
-    pub fn unwrap_string(&self) -> &str {
+    pub fn as_string(&self) -> Result<&str, EvalError> {
         match self {
-            Value::String(string) => string,
-            _ => panic!(),
+            Value::String(string) => Ok(string),
+            _ => Err(self.type_mismatch("string")),
         }
     }
+
+    pub fn into_list(self) -> Result<Rc<Vec<Value>>, EvalError> {
+        match &self {
+            Value::List(items) => Ok(items.clone()),
+            _ => Err(self.type_mismatch("list")),
+        }
+    }
+
+    pub fn into_map(self) -> Result<Rc<BTreeMap<Value, Value>>, EvalError> {
+        match &self {
+            Value::Map(entries) => Ok(entries.clone()),
+            _ => Err(self.type_mismatch("map")),
+        }
+    }
+
+    pub fn as_function(&self) -> Result<&Rc<Function>, EvalError> {
+        match self {
+            Value::Function(function) => Ok(function),
+            _ => Err(self.type_mismatch("function")),
+        }
+    }
+
+    pub fn as_atom(&self) -> Result<&Rc<RefCell<Value>>, EvalError> {
+        match self {
+            Value::Atom(atom) => Ok(atom),
+            _ => Err(self.type_mismatch("atom")),
+        }
+    }
+
+    pub fn type_mismatch(&self, expected: &'static str) -> EvalError {
+        EvalError::new(EvalErrorKind::TypeMismatch {
+            expected,
+            actual: self.type_name(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use crate::program::Program;
+
+    fn nested(wrap: fn(Value) -> Value, depth: usize) -> Value {
+        (0..depth).fold(Value::Nil, |value, _| wrap(value))
+    }
+
+    #[test]
+    fn test_deep_values_on_default_stack() {
+        let list: fn(Value) -> Value = |x| Value::List(Rc::new(vec![x]));
+        let vector: fn(Value) -> Value = |x| Value::Vector(Vector::new().push(x));
+        let map: fn(Value) -> Value = |x| {
+            Value::Map(Rc::new(
+                vec![(Value::Keyword("x".into()), x)].into_iter().collect(),
+            ))
+        };
+        let atom: fn(Value) -> Value = |x| Value::Atom(Rc::new(RefCell::new(x)));
+        let shapes = [
+            (list, "(", ")"),
+            (vector, "[", "]"),
+            (map, "{:x ", "}"),
+            (atom, "<atom ", ">"),
+        ];
+        std::thread::spawn(move || {
+            let depth = 100_000;
+            for (wrap, open, close) in shapes.iter() {
+                let a = nested(*wrap, depth);
+                let text = open.repeat(depth) + "nil" + &close.repeat(depth);
+                assert_eq!(a.to_string(), text);
+                if let Value::Atom(_) = a {
+                    continue;
+                }
+                let b = nested(*wrap, depth - 1);
+                assert_ne!(a, b);
+                assert_eq!(a.cmp(&b), Ordering::Greater);
+            }
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_display() {
+        let value = Program::parse(r#"(let ((a (atom nil))) (reset! a [a {:k "v"} '(1 2.0)]) a)"#)
+            .unwrap()
+            .eval(&mut Context::new())
+            .unwrap();
+        assert_eq!(value.to_string(), r#"<atom [<atom ...> {:k "v"} (1 2.0)]>"#);
+    }
 }
@@ -0,0 +1,304 @@
+use crate::gc::Tracer;
+use crate::value::Value;
+use std::any::Any;
+use std::fmt;
+use std::mem;
+use std::rc::{Rc, Weak};
+
+const BITS: u32 = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+#[derive(Debug)]
+enum Node {
+    Branch(Vec<Rc<Node>>),
+    Leaf(Vec<Value>),
+}
+
+/// A persistent vector: a 32-way trie whose updates copy only the path to
+/// the changed leaf and share everything else with the original. Cloning
+/// is a reference count bump.
+///
+/// A vector may be a window `offset..offset + len` onto a larger trie, which
+/// is how `subvec` avoids copying.
+#[derive(Clone)]
+pub struct Vector {
+    root: Rc<Node>,
+    shift: u32,
+    size: usize,
+    offset: usize,
+    len: usize,
+}
+
+fn new_path(shift: u32, value: Value) -> Rc<Node> {
+    if shift == 0 {
+        Rc::new(Node::Leaf(vec![value]))
+    } else {
+        Rc::new(Node::Branch(vec![new_path(shift - BITS, value)]))
+    }
+}
+
+fn push_into(node: &Node, shift: u32, index: usize, value: Value) -> Rc<Node> {
+    match node {
+        Node::Leaf(items) => {
+            let mut items = items.clone();
+            items.push(value);
+            Rc::new(Node::Leaf(items))
+        }
+        Node::Branch(children) => {
+            let mut children = children.clone();
+            let child = (index >> shift) & MASK;
+            if child < children.len() {
+                children[child] = push_into(&children[child], shift - BITS, index, value);
+            } else {
+                children.push(new_path(shift - BITS, value));
+            }
+            Rc::new(Node::Branch(children))
+        }
+    }
+}
+
+fn set_in(node: &Node, shift: u32, index: usize, value: Value) -> Rc<Node> {
+    match node {
+        Node::Leaf(items) => {
+            let mut items = items.clone();
+            items[index & MASK] = value;
+            Rc::new(Node::Leaf(items))
+        }
+        Node::Branch(children) => {
+            let mut children = children.clone();
+            let child = (index >> shift) & MASK;
+            children[child] = set_in(&children[child], shift - BITS, index, value);
+            Rc::new(Node::Branch(children))
+        }
+    }
+}
+
+fn trace_node(node: &Rc<Node>, tracer: &mut Tracer) {
+    tracer.edge(node, |node, tracer| match node {
+        Node::Branch(children) => children.iter().for_each(|child| trace_node(child, tracer)),
+        Node::Leaf(items) => items.iter().for_each(|item| tracer.value(item)),
+    });
+}
+
+impl Vector {
+    pub fn new() -> Self {
+        Vector {
+            root: Rc::new(Node::Leaf(vec![])),
+            shift: 0,
+            size: 0,
+            offset: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Value> {
+        if index >= self.len {
+            return None;
+        }
+        let index = self.offset + index;
+        let mut node = &self.root;
+        let mut shift = self.shift;
+        loop {
+            match &**node {
+                Node::Branch(children) => {
+                    node = &children[(index >> shift) & MASK];
+                    shift -= BITS;
+                }
+                Node::Leaf(items) => return Some(&items[index & MASK]),
+            }
+        }
+    }
+
+    /// Returns a copy with `value` appended.
+    pub fn push(&self, value: Value) -> Vector {
+        let end = self.offset + self.len;
+        if end < self.size {
+            // A window ending before the trie does: overwrite the hidden slot.
+            let mut vector = self.set_raw(end, value);
+            vector.len += 1;
+            return vector;
+        }
+        let (root, shift) = if self.size == 1 << (self.shift + BITS) {
+            let root = Node::Branch(vec![self.root.clone(), new_path(self.shift, value)]);
+            (Rc::new(root), self.shift + BITS)
+        } else {
+            (
+                push_into(&self.root, self.shift, self.size, value),
+                self.shift,
+            )
+        };
+        Vector {
+            root,
+            shift,
+            size: self.size + 1,
+            offset: self.offset,
+            len: self.len + 1,
+        }
+    }
+
+    /// Returns a copy with the item at `index` replaced, or `None` if the
+    /// index is out of bounds.
+    pub fn set(&self, index: usize, value: Value) -> Option<Vector> {
+        if index >= self.len {
+            return None;
+        }
+        Some(self.set_raw(self.offset + index, value))
+    }
+
+    fn set_raw(&self, index: usize, value: Value) -> Vector {
+        Vector {
+            root: set_in(&self.root, self.shift, index, value),
+            ..self.clone()
+        }
+    }
+
+    /// The items `start..end` as a vector sharing this one's storage, or
+    /// `None` if the range is out of bounds.
+    pub fn slice(&self, start: usize, end: usize) -> Option<Vector> {
+        if start > end || end > self.len {
+            return None;
+        }
+        Some(Vector {
+            offset: self.offset + start,
+            len: end - start,
+            ..self.clone()
+        })
+    }
+
+    /// The trie this vector is a window onto, which lives as long as any
+    /// vector sharing it.
+    pub(crate) fn root(&self) -> Weak<dyn Any> {
+        Rc::downgrade(&self.root) as Weak<dyn Any>
+    }
+
+    /// Whether no other vector shares this one's trie.
+    pub(crate) fn is_unique(&self) -> bool {
+        Rc::strong_count(&self.root) == 1
+    }
+
+    /// Moves every item onto `items` if no other vector shares the trie,
+    /// leaving this one empty, so that they can be dropped one at a time.
+    pub(crate) fn take_items(&mut self, items: &mut Vec<Value>) {
+        if !self.is_unique() {
+            return;
+        }
+        let mut nodes = vec![mem::take(self).root];
+        while let Some(node) = nodes.pop() {
+            match Rc::try_unwrap(node) {
+                Ok(Node::Branch(children)) => nodes.extend(children),
+                Ok(Node::Leaf(leaf)) => items.extend(leaf),
+                Err(_) => {}
+            }
+        }
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        trace_node(&self.root, tracer);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Value> {
+        (0..self.len).filter_map(move |index| self.get(index))
+    }
+}
+
+impl Default for Vector {
+    fn default() -> Self {
+        Vector::new()
+    }
+}
+
+impl PartialEq for Vector {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl fmt::Debug for Vector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl std::iter::FromIterator<Value> for Vector {
+    fn from_iter<I: IntoIterator<Item = Value>>(iter: I) -> Self {
+        iter.into_iter()
+            .fold(Vector::new(), |vector, value| vector.push(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ints(range: std::ops::Range<i64>) -> Vector {
+        range.map(Value::Integer).collect()
+    }
+
+    fn to_ints(vector: &Vector) -> Vec<i64> {
+        vector
+            .iter()
+            .map(|value| value.as_integer().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_push_and_get() {
+        for n in &[0, 1, 32, 33, 1024, 1025, 40_000] {
+            let vector = ints(0..*n);
+            assert_eq!(vector.len(), *n as usize);
+            assert_eq!(to_ints(&vector), (0..*n).collect::<Vec<_>>());
+            assert_eq!(vector.get(*n as usize), None);
+        }
+    }
+
+    #[test]
+    fn test_updates_are_persistent() {
+        let original = ints(0..100);
+        let updated = original.set(50, Value::Nil).unwrap();
+        let pushed = original.push(Value::Integer(100));
+        assert_eq!(original.get(50), Some(&Value::Integer(50)));
+        assert_eq!(updated.get(50), Some(&Value::Nil));
+        assert_eq!(original.len(), 100);
+        assert_eq!(pushed.len(), 101);
+        assert_eq!(original.set(100, Value::Nil), None);
+    }
+
+    #[test]
+    fn test_structural_sharing() {
+        let original = ints(0..1024);
+        let updated = original.set(0, Value::Nil).unwrap();
+        match (&*original.root, &*updated.root) {
+            (Node::Branch(a), Node::Branch(b)) => {
+                assert!(!Rc::ptr_eq(&a[0], &b[0]));
+                assert!(a[1..].iter().zip(&b[1..]).all(|(x, y)| Rc::ptr_eq(x, y)));
+            }
+            _ => panic!("expected a branch root"),
+        }
+    }
+
+    #[test]
+    fn test_slice() {
+        let vector = ints(0..100);
+        let slice = vector.slice(10, 20).unwrap();
+        assert_eq!(to_ints(&slice), (10..20).collect::<Vec<_>>());
+        assert!(Rc::ptr_eq(&slice.root, &vector.root));
+        assert_eq!(to_ints(&slice.slice(2, 4).unwrap()), vec![12, 13]);
+        assert_eq!(vector.slice(5, 101), None);
+        assert_eq!(vector.slice(6, 5), None);
+
+        // Pushing onto a window must not disturb the vector it came from.
+        let pushed = slice.push(Value::Nil);
+        assert_eq!(pushed.get(10), Some(&Value::Nil));
+        assert_eq!(vector.get(20), Some(&Value::Integer(20)));
+        assert_eq!(slice, ints(10..20));
+    }
+}
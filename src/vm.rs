@@ -0,0 +1,386 @@
+use crate::compiler::{compile, Address, Builtin, Chunk, Instruction};
+use crate::context::{Context, Env};
+use crate::error::{EvalError, EvalErrorKind};
+use crate::expression::Expression;
+use crate::function::Function;
+use crate::numeric;
+use crate::span::Span;
+use crate::value::Value;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+/// A function (or top-level expression) being run.
+struct CallFrame {
+    chunk: Rc<Chunk>,
+    ip: usize,
+    /// The scope to restore on returning, or `None` for the outermost frame,
+    /// which runs in the caller's scope.
+    caller_scope: Option<Option<Env>>,
+    /// Where `scopes` stood on entry; `let` forms push above it.
+    scopes_base: usize,
+    /// Where the function was called from. Errors raised while replacing
+    /// the frame with a tail call are reported there.
+    call_span: Option<Span>,
+}
+
+/// What the VM does after running an instruction.
+enum Flow {
+    Next,
+    /// A call or return changed the running frame.
+    Switch,
+    Done(Value),
+}
+
+struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    /// The scopes enclosing each active `let`.
+    scopes: Vec<Option<Env>>,
+}
+
+/// Compiles `expr` and runs it on the VM.
+pub fn eval(expr: &Expression, context: &mut Context) -> Result<Value, EvalError> {
+    let frame = CallFrame {
+        chunk: Rc::new(compile(expr, context.scope.as_ref(), &context.builtins)),
+        ip: 0,
+        caller_scope: None,
+        scopes_base: 0,
+        call_span: None,
+    };
+    run(frame, context)
+}
+
+/// Calls a user function on the VM.
+pub fn call(
+    function: &Rc<Function>,
+    args: Vec<Value>,
+    context: &mut Context,
+) -> Result<Value, EvalError> {
+    let scope = context.scope.clone();
+    let depth = context.depth;
+    let mut vm = Vm {
+        stack: Vec::new(),
+        frames: Vec::new(),
+        scopes: Vec::new(),
+    };
+    let result = vm
+        .enter(function, args, None, context)
+        .and_then(|()| vm.run(context));
+    if result.is_err() {
+        context.scope = scope;
+        context.depth = depth;
+    }
+    result
+}
+
+fn run(frame: CallFrame, context: &mut Context) -> Result<Value, EvalError> {
+    let scope = context.scope.clone();
+    let depth = context.depth;
+    let mut vm = Vm {
+        stack: Vec::new(),
+        frames: vec![frame],
+        scopes: Vec::new(),
+    };
+    let result = vm.run(context);
+    if result.is_err() {
+        context.scope = scope;
+        context.depth = depth;
+    }
+    result
+}
+
+impl Vm {
+    /// Pushes a frame running `function`, as `Function::call` would.
+    fn enter(
+        &mut self,
+        function: &Rc<Function>,
+        args: Vec<Value>,
+        call_span: Option<Span>,
+        context: &mut Context,
+    ) -> Result<(), EvalError> {
+        if context.depth >= context.max_depth {
+            return Err(EvalErrorKind::StackOverflow(context.max_depth).into());
+        }
+        context.depth += 1;
+        let frame = function.bind(args)?;
+        let env = context.heap.alloc(frame)?;
+        let caller_scope = context.scope.replace(env);
+        self.frames.push(CallFrame {
+            chunk: function.chunk(&context.builtins),
+            ip: 0,
+            caller_scope: Some(caller_scope),
+            scopes_base: self.scopes.len(),
+            call_span,
+        });
+        Ok(())
+    }
+
+    /// Replaces the current function's frame with one running `function`.
+    fn tail_call(
+        &mut self,
+        function: &Rc<Function>,
+        args: Vec<Value>,
+        context: &mut Context,
+    ) -> Result<(), EvalError> {
+        let frame = self.frames.last_mut().unwrap();
+        self.scopes.truncate(frame.scopes_base);
+        context.scope = frame.caller_scope.clone().unwrap();
+        let env = context.heap.alloc(function.bind(args)?)?;
+        context.scope = Some(env);
+        frame.chunk = function.chunk(&context.builtins);
+        frame.ip = 0;
+        Ok(())
+    }
+
+    /// Whether the callee below the top `count` values is a user function.
+    fn calls_function(&self, count: usize) -> bool {
+        let callee = &self.stack[self.stack.len() - 1 - count];
+        matches!(callee, Value::Function(_))
+    }
+
+    fn pop_call(&mut self, count: usize) -> (Rc<Function>, Vec<Value>) {
+        let args = self.stack.split_off(self.stack.len() - count);
+        match &self.stack.pop() {
+            Some(Value::Function(function)) => (function.clone(), args),
+            _ => unreachable!("calls are compiled after a function"),
+        }
+    }
+
+    fn run(&mut self, context: &mut Context) -> Result<Value, EvalError> {
+        let mut chunk = self.frames.last().unwrap().chunk.clone();
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let ip = frame.ip;
+            frame.ip += 1;
+            let result = match chunk.code[ip] {
+                // Failing to make a tail call fails the call being replaced,
+                // so the error belongs to its call site. A frame entered from
+                // Rust has none; the caller adds its own.
+                Instruction::TailCall(count) => {
+                    let call_span = frame.call_span;
+                    let (function, args) = self.pop_call(count);
+                    match self.tail_call(&function, args, context) {
+                        Ok(()) => Ok(Flow::Switch),
+                        Err(err) => {
+                            return Err(match call_span {
+                                Some(span) => err.or_span(span),
+                                None => err,
+                            })
+                        }
+                    }
+                }
+                instruction => self.step(&chunk, instruction, context),
+            };
+            match result {
+                Ok(Flow::Next) => {}
+                Ok(Flow::Switch) => chunk = self.frames.last().unwrap().chunk.clone(),
+                Ok(Flow::Done(value)) => return Ok(value),
+                Err(err) => return Err(err.or_span(chunk.spans[ip])),
+            }
+        }
+    }
+
+    /// Runs one instruction.
+    fn step(
+        &mut self,
+        chunk: &Chunk,
+        instruction: Instruction,
+        context: &mut Context,
+    ) -> Result<Flow, EvalError> {
+        match instruction {
+            Instruction::Const(index) => self.stack.push(chunk.constants[index].clone()),
+            Instruction::Nil => self.stack.push(Value::Nil),
+            Instruction::Pop => {
+                self.stack.pop();
+            }
+            Instruction::Load(address) => {
+                let value = load(chunk, address, context)
+                    .ok_or_else(|| unbound(chunk, address, EvalErrorKind::UnboundSymbol))?;
+                self.stack.push(value);
+            }
+            Instruction::Jump(target) => self.frames.last_mut().unwrap().ip = target,
+            Instruction::JumpUnless(target) => {
+                if !self.stack.pop().unwrap().as_bool()? {
+                    self.frames.last_mut().unwrap().ip = target;
+                }
+            }
+            Instruction::JumpIfFunction(count, target) => {
+                if self.calls_function(count) {
+                    self.frames.last_mut().unwrap().ip = target;
+                }
+            }
+            Instruction::CheckNumber(count) => {
+                let value = self.stack.last().unwrap();
+                if !value.is_number() && !self.calls_function(count) {
+                    return Err(value.type_mismatch("number"));
+                }
+            }
+            Instruction::CheckBool(count) => {
+                if !self.calls_function(count) {
+                    self.stack.last().unwrap().as_bool()?;
+                }
+            }
+            Instruction::Callee(address) => match load(chunk, address, context) {
+                Some(function @ Value::Function(_)) => self.stack.push(function),
+                _ => self.stack.push(Value::Nil),
+            },
+            Instruction::Builtin(builtin, count) => {
+                let start = self.stack.len() - count;
+                let value = apply(builtin, &self.stack[start..], context)?;
+                context.heap.track(&value)?;
+                self.stack.truncate(start - 1);
+                self.stack.push(value);
+            }
+            Instruction::Shadowed(address, target) => {
+                if let Some(Value::Function(_)) = load(chunk, address, context) {
+                    self.frames.last_mut().unwrap().ip = target;
+                }
+            }
+            Instruction::Function(address) => match load(chunk, address, context) {
+                Some(value @ Value::Function(_)) => self.stack.push(value),
+                Some(value) => return Err(value.type_mismatch("function")),
+                None => return Err(unbound(chunk, address, EvalErrorKind::UnknownOperator)),
+            },
+            Instruction::CheckFunction => {
+                self.stack.last().unwrap().as_function()?;
+            }
+            Instruction::Call(count) => {
+                let (function, args) = self.pop_call(count);
+                let frame = self.frames.last().unwrap();
+                let span = frame.chunk.spans[frame.ip - 1];
+                self.enter(&function, args, Some(span), context)?;
+                return Ok(Flow::Switch);
+            }
+            Instruction::TailCall(_) => unreachable!("tail calls are run by `run`"),
+            Instruction::Let(index) => {
+                let layout = &chunk.layouts[index];
+                let start = self.stack.len() - layout.slots.len();
+                let frame = layout.frame(self.stack.drain(start..), context.scope.clone());
+                let env = context.heap.alloc(frame)?;
+                self.scopes.push(context.scope.replace(env));
+            }
+            Instruction::EndLet => context.scope = self.scopes.pop().unwrap(),
+            Instruction::Set(address) => {
+                let value = self.stack.pop().unwrap();
+                match address {
+                    Address::Local(depth, slot) => {
+                        context.frame(depth).borrow_mut().vars[slot].1 = value;
+                    }
+                    Address::Global(index) => match context
+                        .globals
+                        .get_symbol_mut(&chunk.names[index])
+                    {
+                        Some(slot) => *slot = value,
+                        None => return Err(unbound(chunk, address, EvalErrorKind::UnboundSymbol)),
+                    },
+                }
+                self.stack.push(Value::Nil);
+            }
+            Instruction::Global(index) => {
+                let value = self.stack.pop().unwrap();
+                context.globals.insert_symbol(&chunk.names[index], value);
+                self.stack.push(Value::Nil);
+            }
+            Instruction::Native(index, count) => {
+                let start = self.stack.len() - count;
+                let value = (chunk.natives[index])(&self.stack[start..])?;
+                context.heap.track(&value)?;
+                self.stack.truncate(start - 1);
+                self.stack.push(value);
+            }
+            Instruction::Operation(index) => {
+                let (op, args) = &chunk.operations[index];
+                let value = op.eval(args, context)?;
+                context.heap.track(&value)?;
+                self.stack.push(value);
+            }
+            Instruction::Vector(count) => {
+                let start = self.stack.len() - count;
+                let value = Value::Vector(self.stack.drain(start..).collect());
+                context.heap.track(&value)?;
+                self.stack.push(value);
+            }
+            Instruction::Map(count) => {
+                let start = self.stack.len() - 2 * count;
+                let mut entries = BTreeMap::new();
+                let mut items = self.stack.drain(start..);
+                while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                    entries.insert(key, value);
+                }
+                drop(items);
+                let value = Value::Map(Rc::new(entries));
+                context.heap.track(&value)?;
+                self.stack.push(value);
+            }
+            Instruction::Eval(index) => {
+                let value = chunk.exprs[index].eval(context)?;
+                self.stack.push(value);
+            }
+            Instruction::Return => {
+                let frame = self.frames.pop().unwrap();
+                if let Some(scope) = frame.caller_scope {
+                    context.scope = scope;
+                    context.depth -= 1;
+                }
+                if self.frames.is_empty() {
+                    return Ok(Flow::Done(self.stack.pop().unwrap()));
+                }
+                return Ok(Flow::Switch);
+            }
+        }
+        Ok(Flow::Next)
+    }
+}
+
+fn load(chunk: &Chunk, address: Address, context: &Context) -> Option<Value> {
+    match address {
+        Address::Local(depth, slot) => Some(context.frame(depth).borrow().vars[slot].1.clone()),
+        Address::Global(index) => context.globals.get_symbol(&chunk.names[index]).cloned(),
+    }
+}
+
+/// The error for a variable found unbound, which only globals can be.
+fn unbound(chunk: &Chunk, address: Address, kind: fn(String) -> EvalErrorKind) -> EvalError {
+    match address {
+        Address::Global(index) => kind(chunk.names[index].name().to_string()).into(),
+        Address::Local(..) => unreachable!("locals are always bound"),
+    }
+}
+
+fn apply(builtin: Builtin, args: &[Value], context: &mut Context) -> Result<Value, EvalError> {
+    let bool_arg = |index: usize| args[index].as_bool();
+    let value = match builtin {
+        Builtin::Add => {
+            let mut sum = Value::Integer(0);
+            for arg in args {
+                sum = numeric::add(&sum, arg)?;
+            }
+            sum
+        }
+        Builtin::Mul => {
+            let mut product = Value::Integer(1);
+            for arg in args {
+                product = numeric::mul(&product, arg)?;
+            }
+            product
+        }
+        Builtin::Sub => numeric::sub(&args[0], &args[1])?,
+        Builtin::Div => numeric::div(&args[0], &args[1])?,
+        Builtin::Rem => numeric::rem(&args[0], &args[1])?,
+        Builtin::Eq => Value::Bool(args[0] == args[1]),
+        Builtin::Lt => {
+            Value::Bool(numeric::compare(&args[0], &args[1])? == Some(std::cmp::Ordering::Less))
+        }
+        Builtin::Gt => {
+            Value::Bool(numeric::compare(&args[0], &args[1])? == Some(std::cmp::Ordering::Greater))
+        }
+        Builtin::And => Value::Bool(bool_arg(0)? && bool_arg(1)?),
+        Builtin::Or => Value::Bool(bool_arg(0)? || bool_arg(1)?),
+        Builtin::Not => Value::Bool(!bool_arg(0)?),
+        Builtin::Print => {
+            context.print(&args[0].to_text())?;
+            Value::Nil
+        }
+    };
+    Ok(value)
+}